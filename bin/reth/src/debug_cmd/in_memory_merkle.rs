@@ -16,7 +16,7 @@ use reth_primitives::{fs, stage::StageId, BlockHashOrNumber, ChainSpec};
 use reth_provider::{
     AccountExtReader, BlockWriter, ExecutorFactory, HashingWriter, HeaderProvider,
     LatestStateProviderRef, OriginalValuesKnown, ProviderFactory, StageCheckpointReader,
-    StorageReader,
+    StorageReader, WriteTables,
 };
 use reth_tasks::TaskExecutor;
 use reth_trie::{hashed_cursor::HashedPostStateCursorFactory, updates::TrieKey, StateRoot};
@@ -197,7 +197,11 @@ impl Command {
 
         // Insert block, state and hashes
         provider_rw.insert_block(block.clone(), None, None)?;
-        block_state.write_to_db(provider_rw.tx_ref(), OriginalValuesKnown::No)?;
+        block_state.write_to_db(
+            provider_rw.tx_ref(),
+            OriginalValuesKnown::No,
+            WriteTables::default(),
+        )?;
         let storage_lists = provider_rw.changed_storages_with_range(block.number..=block.number)?;
         let storages = provider_rw.plainstate_storages(storage_lists)?;
         provider_rw.insert_storage_for_hashing(storages)?;
@@ -10,9 +10,9 @@ use reth_primitives::{
     stage::StageId, Account, Bytecode, ChainSpec, Receipts, StorageEntry, H256, U256,
 };
 use reth_provider::{
-    bundle_state::{BundleStateInit, RevertsInit},
+    bundle_state::{BundleStateInit, BundleWriteError, RevertsInit},
     BundleStateWithReceipts, DatabaseProviderRW, HashingWriter, HistoryWriter, OriginalValuesKnown,
-    ProviderFactory,
+    ProviderFactory, WriteTables,
 };
 use std::{
     collections::{BTreeMap, HashMap},
@@ -42,6 +42,15 @@ pub enum InitDatabaseError {
     InternalError(#[from] RethError),
 }
 
+impl From<BundleWriteError> for InitDatabaseError {
+    fn from(err: BundleWriteError) -> Self {
+        match RethError::from(err) {
+            RethError::Database(err) => InitDatabaseError::DBError(err),
+            err => InitDatabaseError::InternalError(err),
+        }
+    }
+}
+
 /// Write the genesis block if it has not already been written
 #[allow(clippy::field_reassign_with_default)]
 pub fn init_genesis<DB: Database>(
@@ -151,7 +160,7 @@ pub fn insert_genesis_state<DB: Database>(
         0,
     );
 
-    bundle.write_to_db(tx, OriginalValuesKnown::Yes)?;
+    bundle.write_to_db(tx, OriginalValuesKnown::Yes, WriteTables::default())?;
 
     Ok(())
 }
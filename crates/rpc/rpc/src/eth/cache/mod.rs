@@ -486,7 +486,11 @@ where
             for block in &blocks {
                 let block_receipts = BlockReceipts {
                     block_hash: block.hash,
-                    receipts: state.receipts_by_block(block.number).to_vec(),
+                    receipts: state
+                        .receipts_view()
+                        .by_block(block.number)
+                        .unwrap_or_default()
+                        .to_vec(),
                 };
                 receipts.push(block_receipts);
             }
@@ -4,6 +4,7 @@ use reth_interfaces::{
     provider::ProviderError, RethError,
 };
 use reth_primitives::SealedHeader;
+use reth_provider::bundle_state::BundleWriteError;
 use thiserror::Error;
 use tokio::sync::mpsc::error::SendError;
 
@@ -96,6 +97,15 @@ impl StageError {
     }
 }
 
+impl From<BundleWriteError> for StageError {
+    fn from(err: BundleWriteError) -> Self {
+        match RethError::from(err) {
+            RethError::Database(err) => StageError::Database(err),
+            err => StageError::Internal(err),
+        }
+    }
+}
+
 /// A pipeline execution error.
 #[derive(Error, Debug)]
 pub enum PipelineError {
@@ -19,7 +19,7 @@ use reth_primitives::{
 };
 use reth_provider::{
     BlockReader, DatabaseProviderRW, ExecutorFactory, HeaderProvider, LatestStateProviderRef,
-    OriginalValuesKnown, ProviderError,
+    OriginalValuesKnown, ProviderError, WriteTables,
 };
 use std::{
     ops::RangeInclusive,
@@ -190,7 +190,7 @@ impl<EF: ExecutorFactory> ExecutionStage<EF> {
 
         let time = Instant::now();
         // write output
-        state.write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)?;
+        state.write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())?;
         let db_write_duration = time.elapsed();
         debug!(
             target: "sync::stages::execution",
@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use reth_db::{database::Database, test_utils::create_test_rw_db};
+use reth_primitives::{Account, Address, H256, U256};
+use reth_provider::{bundle_state::BundleStateBuilder, BundleStateWithReceipts};
+
+/// Benchmarks the first and the repeated state root calculation of the same bundle.
+pub fn state_root_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Bundle state root");
+    let db = create_test_rw_db();
+    let tx = db.tx().unwrap();
+
+    for size in [100, 1_000, 10_000] {
+        let group_name = |description: &str| format!("state root | size: {size} | {description}");
+        let bundle = generate_test_data(size);
+
+        group.bench_function(group_name("first call"), |b| {
+            b.iter_batched(
+                || bundle.clone(),
+                |bundle| black_box(bundle.state_root_slow(&tx).unwrap()),
+                BatchSize::LargeInput,
+            );
+        });
+
+        bundle.state_root_slow(&tx).unwrap();
+        group.bench_function(group_name("cached"), |b| {
+            b.iter(|| black_box(bundle.state_root_slow(&tx).unwrap()));
+        });
+    }
+}
+
+/// Bundle creating `size` accounts with one storage slot each.
+fn generate_test_data(size: u64) -> BundleStateWithReceipts {
+    (0..size)
+        .fold(BundleStateBuilder::new(1), |builder, index| {
+            let address = Address::from_low_u64_be(index);
+            let account = Account { nonce: index, ..Default::default() };
+            builder.add_account_change(1, address, None, Some(account)).add_storage_change(
+                1,
+                address,
+                H256::from_low_u64_be(index),
+                U256::ZERO,
+                U256::from(index + 1),
+            )
+        })
+        .build()
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default();
+    targets = state_root_benchmark
+}
+criterion_main!(benches);
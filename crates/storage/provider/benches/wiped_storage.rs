@@ -0,0 +1,60 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use reth_db::{
+    cursor::DbDupCursorRW, database::Database, tables, test_utils::create_test_rw_db,
+    transaction::DbTxMut,
+};
+use reth_primitives::{Address, StorageEntry, H256, U256};
+use reth_provider::StateReverts;
+use reth_revm_primitives::{
+    db::states::{PlainStateReverts, PlainStorageRevert},
+    primitives::AccountInfo,
+};
+
+/// Benchmarks writing the reverts of an account wipe with different wiped storage batch sizes.
+pub fn wiped_storage_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Wiped storage reverts");
+    group.sample_size(10);
+    let db = create_test_rw_db();
+    let address = Address::repeat_byte(0x11);
+
+    for slots in [1_000, 1_000_000] {
+        let tx = db.tx_mut().unwrap();
+        tx.clear::<tables::PlainStorageState>().unwrap();
+        let mut cursor = tx.cursor_dup_write::<tables::PlainStorageState>().unwrap();
+        for key in 0..slots {
+            let entry = StorageEntry { key: H256::from_low_u64_be(key), value: U256::from(1) };
+            cursor.append_dup(address, entry).unwrap();
+        }
+        drop(cursor);
+        tx.commit().unwrap();
+
+        for batch_size in [1, 1024, 65536] {
+            let group_name = format!("wipe | slots: {slots} | batch size: {batch_size}");
+            group.bench_function(group_name, |b| {
+                b.iter_batched(
+                    || (db.tx_mut().unwrap(), wipe_reverts(address)),
+                    |(tx, reverts)| {
+                        // Transaction is dropped without committing.
+                        black_box(reverts.write_to_db_with_batch_size(&tx, 1, batch_size).unwrap())
+                    },
+                    BatchSize::PerIteration,
+                );
+            });
+        }
+    }
+}
+
+/// Reverts of a single block destroying an account that existed before the block.
+fn wipe_reverts(address: Address) -> StateReverts {
+    StateReverts(PlainStateReverts {
+        accounts: vec![vec![(address, Some(AccountInfo::default()))]],
+        storage: vec![vec![PlainStorageRevert { address, wiped: true, storage_revert: vec![] }]],
+    })
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default();
+    targets = wiped_storage_benchmark
+}
+criterion_main!(benches);
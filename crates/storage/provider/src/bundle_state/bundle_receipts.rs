@@ -0,0 +1,491 @@
+#[cfg(feature = "compressed-receipts")]
+use crate::bundle_state::CompressedReceipts;
+#[cfg(feature = "compressed-receipts")]
+use reth_interfaces::db::DatabaseError;
+use reth_primitives::{proofs::calculate_receipt_root_ref, BlockNumber, Receipt, Receipts, H256};
+#[cfg(not(feature = "compressed-receipts"))]
+use std::ops::Range;
+use std::sync::OnceLock;
+
+/// Receipts of consecutive blocks stored in a single vector.
+///
+/// Receipts of block at index `i` start at `offsets[i]` and end at the start of the next block,
+/// or at the end of the vector for the last block.
+///
+/// With the `compressed-receipts` feature the receipts of every block are stored compressed in
+/// `CompressedReceipts` instead. Accessors that return borrowed receipts read them from the nested
+/// layout, so the first of them decompresses the receipts of all blocks, and a receipt that fails
+/// to decode is treated as pruned there.
+///
+/// If receipt is None it means it is pruned.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct FlatReceipts {
+    /// Receipts of all blocks, ordered by block and transaction number.
+    #[cfg(not(feature = "compressed-receipts"))]
+    receipts: Vec<Option<Receipt>>,
+    /// Index of the first receipt of each block.
+    #[cfg(not(feature = "compressed-receipts"))]
+    offsets: Vec<usize>,
+    /// Compressed receipts of each block.
+    #[cfg(feature = "compressed-receipts")]
+    compressed: CompressedReceipts,
+    /// Blob gas used by each block, None if it was not recorded.
+    blob_gas_used: Vec<Option<u64>>,
+    /// Receipts in the nested layout, built on first use by [Self::nested] and cleared when the
+    /// receipts change.
+    nested: OnceLock<Receipts>,
+}
+
+impl PartialEq for FlatReceipts {
+    #[cfg(not(feature = "compressed-receipts"))]
+    fn eq(&self, other: &Self) -> bool {
+        // Nested receipts are derived from the flat receipts.
+        self.receipts == other.receipts &&
+            self.offsets == other.offsets &&
+            self.blob_gas_used == other.blob_gas_used
+    }
+
+    #[cfg(feature = "compressed-receipts")]
+    fn eq(&self, other: &Self) -> bool {
+        self.compressed == other.compressed && self.blob_gas_used == other.blob_gas_used
+    }
+}
+
+impl Eq for FlatReceipts {}
+
+impl FlatReceipts {
+    /// Create empty receipts with capacity for the given number of blocks and receipts.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn with_capacity(num_blocks: usize, num_receipts: usize) -> Self {
+        Self {
+            receipts: Vec::with_capacity(num_receipts),
+            offsets: Vec::with_capacity(num_blocks),
+            blob_gas_used: Vec::with_capacity(num_blocks),
+            nested: OnceLock::new(),
+        }
+    }
+
+    /// Create empty receipts with capacity for the given number of blocks, receipts are
+    /// compressed per block.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn with_capacity(num_blocks: usize, _num_receipts: usize) -> Self {
+        Self {
+            compressed: CompressedReceipts::with_capacity(num_blocks),
+            blob_gas_used: Vec::with_capacity(num_blocks),
+            nested: OnceLock::new(),
+        }
+    }
+
+    /// Return receipts of all blocks in the nested layout, building them on first use.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn nested(&self) -> &Receipts {
+        self.nested
+            .get_or_init(|| Receipts::from_vec(self.iter_blocks().map(<[_]>::to_vec).collect()))
+    }
+
+    /// Return receipts of all blocks in the nested layout, decompressing them on first use.
+    ///
+    /// A receipt that fails to decode is pruned in the nested layout, [Self::decompress_block]
+    /// returns the error.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn nested(&self) -> &Receipts {
+        self.nested.get_or_init(|| Receipts::from_vec(self.compressed.iter_or_pruned().collect()))
+    }
+
+    /// Number of blocks.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Number of blocks.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn len(&self) -> usize {
+        self.compressed.len()
+    }
+
+    /// Number of receipts in all blocks, pruned receipts included.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn total_count(&self) -> usize {
+        self.receipts.len()
+    }
+
+    /// Number of receipts in all blocks, pruned receipts included.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn total_count(&self) -> usize {
+        self.compressed.total_count()
+    }
+
+    /// Number of bytes of the compressed receipts of all blocks.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn compressed_size(&self) -> usize {
+        self.compressed.compressed_size()
+    }
+
+    /// Range of receipts of the block at the given index.
+    #[cfg(not(feature = "compressed-receipts"))]
+    fn block_range(&self, index: usize) -> Range<usize> {
+        let end = self.offsets.get(index + 1).copied().unwrap_or(self.receipts.len());
+        self.offsets[index]..end
+    }
+
+    /// Return receipts of the block at the given index.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn block(&self, index: usize) -> Option<&[Option<Receipt>]> {
+        if index >= self.len() {
+            return None
+        }
+        Some(&self.receipts[self.block_range(index)])
+    }
+
+    /// Return receipts of the block at the given index from the nested layout, see
+    /// [Self::nested].
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn block(&self, index: usize) -> Option<&[Option<Receipt>]> {
+        self.nested().receipt_vec.get(index).map(Vec::as_slice)
+    }
+
+    /// Decompress receipts of the block at the given index, without building the nested layout.
+    ///
+    /// Returns None if the block is not present, or the error of the first receipt that fails to
+    /// decode.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn decompress_block(
+        &self,
+        index: usize,
+    ) -> Option<Result<Vec<Option<Receipt>>, DatabaseError>> {
+        self.compressed.block(index)
+    }
+
+    /// Return blob gas used by the block at the given index, or None if the block is not present
+    /// or its blob gas used was not recorded.
+    pub(crate) fn blob_gas_used(&self, index: usize) -> Option<u64> {
+        self.blob_gas_used.get(index).copied().flatten()
+    }
+
+    /// Record blob gas used by the block at the given index.
+    ///
+    /// # Panics
+    ///
+    /// If the block is not present.
+    pub(crate) fn set_blob_gas_used(&mut self, index: usize, blob_gas_used: u64) {
+        self.blob_gas_used[index] = Some(blob_gas_used);
+    }
+
+    /// Iterate over receipts of all blocks.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn iter_blocks(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = &[Option<Receipt>]> + ExactSizeIterator {
+        (0..self.len()).map(|index| &self.receipts[self.block_range(index)])
+    }
+
+    /// Iterate over receipts of all blocks from the nested layout, see [Self::nested].
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn iter_blocks(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = &[Option<Receipt>]> + ExactSizeIterator {
+        self.nested().receipt_vec.iter().map(Vec::as_slice)
+    }
+
+    /// Mutate every receipt that is not pruned.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn map_receipts(&mut self, f: impl FnMut(&mut Receipt)) {
+        self.nested.take();
+        self.receipts.iter_mut().flatten().for_each(f);
+    }
+
+    /// Mutate every receipt that is not pruned, recompressing every block. Blocks that fail to
+    /// decode are kept as they are.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn map_receipts(&mut self, mut f: impl FnMut(&mut Receipt)) {
+        self.nested.take();
+        for index in 0..self.len() {
+            let Some(Ok(mut receipts)) = self.compressed.block(index) else { continue };
+            receipts.iter_mut().flatten().for_each(&mut f);
+            self.compressed.set_block(index, &receipts);
+        }
+    }
+
+    /// Retrieves the receipt root of the block at the given index.
+    ///
+    /// Returns None if the block is not present or any of its receipts is pruned.
+    pub(crate) fn root_slow(&self, index: usize) -> Option<H256> {
+        Some(calculate_receipt_root_ref(
+            &self.block(index)?.iter().map(Option::as_ref).collect::<Option<Vec<_>>>()?,
+        ))
+    }
+
+    /// Replace receipts of the block at the given index.
+    ///
+    /// # Panics
+    ///
+    /// If the block is not present.
+    pub(crate) fn set_block(&mut self, index: usize, receipts: Vec<Option<Receipt>>) {
+        self.nested.take();
+        #[cfg(not(feature = "compressed-receipts"))]
+        {
+            let range = self.block_range(index);
+            let (old_len, new_len) = (range.len(), receipts.len());
+            self.receipts.splice(range, receipts);
+            for offset in &mut self.offsets[index + 1..] {
+                *offset = *offset + new_len - old_len;
+            }
+        }
+        #[cfg(feature = "compressed-receipts")]
+        self.compressed.set_block(index, &receipts);
+    }
+
+    /// Remove blocks starting from the given index and return their receipts.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn split_off(&mut self, index: usize) -> Vec<Vec<Option<Receipt>>> {
+        if index >= self.len() {
+            return Vec::new()
+        }
+        self.nested.take();
+        self.blob_gas_used.truncate(index);
+        let offsets = self.offsets.split_off(index);
+        let first = offsets[0];
+        let mut receipts = self.receipts.split_off(first);
+        let mut blocks = Vec::with_capacity(offsets.len());
+        // Split from the back so every block is moved out only once.
+        for offset in offsets.into_iter().rev() {
+            blocks.push(receipts.split_off(offset - first));
+        }
+        blocks.reverse();
+        blocks
+    }
+
+    /// Remove blocks starting from the given index and return their decompressed receipts, see
+    /// [Self::nested] for receipts that fail to decode.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn split_off(&mut self, index: usize) -> Vec<Vec<Option<Receipt>>> {
+        if index >= self.len() {
+            return Vec::new()
+        }
+        self.nested.take();
+        self.blob_gas_used.truncate(index);
+        self.compressed.split_off(index).iter_or_pruned().collect()
+    }
+
+    /// Remove first `num_blocks` blocks.
+    pub(crate) fn remove_first(&mut self, num_blocks: usize) {
+        if num_blocks >= self.len() {
+            *self = Self::default();
+            return
+        }
+        self.nested.take();
+        self.blob_gas_used.drain(..num_blocks);
+        #[cfg(not(feature = "compressed-receipts"))]
+        {
+            let first = self.offsets[num_blocks];
+            self.receipts.drain(..first);
+            self.offsets.drain(..num_blocks);
+            self.offsets.iter_mut().for_each(|offset| *offset -= first);
+        }
+        #[cfg(feature = "compressed-receipts")]
+        self.compressed.remove_first(num_blocks);
+    }
+
+    /// Append receipts of all blocks of `other`.
+    pub(crate) fn extend(&mut self, other: Self) {
+        self.nested.take();
+        #[cfg(not(feature = "compressed-receipts"))]
+        {
+            let shift = self.receipts.len();
+            self.offsets.extend(other.offsets.into_iter().map(|offset| offset + shift));
+            self.receipts.extend(other.receipts);
+        }
+        #[cfg(feature = "compressed-receipts")]
+        self.compressed.extend(other.compressed);
+        self.blob_gas_used.extend(other.blob_gas_used);
+    }
+
+    /// Remove all blocks, keeping the allocated capacity.
+    pub(crate) fn clear(&mut self) {
+        self.nested.take();
+        #[cfg(not(feature = "compressed-receipts"))]
+        {
+            self.receipts.clear();
+            self.offsets.clear();
+        }
+        #[cfg(feature = "compressed-receipts")]
+        self.compressed.clear();
+        self.blob_gas_used.clear();
+    }
+
+    /// Release excess capacity.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        #[cfg(not(feature = "compressed-receipts"))]
+        {
+            self.receipts.shrink_to_fit();
+            self.offsets.shrink_to_fit();
+        }
+        #[cfg(feature = "compressed-receipts")]
+        self.compressed.shrink_to_fit();
+        self.blob_gas_used.shrink_to_fit();
+    }
+
+    /// Consume receipts and return them with the number of receipts of each block.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub(crate) fn into_flattened(self) -> (Vec<Option<Receipt>>, Vec<usize>) {
+        let block_lengths = (0..self.len()).map(|index| self.block_range(index).len()).collect();
+        (self.receipts, block_lengths)
+    }
+
+    /// Decompress receipts and return them with the number of receipts of each block.
+    ///
+    /// Returns the error of the first receipt that fails to decode.
+    #[cfg(feature = "compressed-receipts")]
+    pub(crate) fn into_flattened(
+        self,
+    ) -> Result<(Vec<Option<Receipt>>, Vec<usize>), DatabaseError> {
+        let mut receipts = Vec::with_capacity(self.total_count());
+        let mut block_lengths = Vec::with_capacity(self.len());
+        for block in self.compressed.iter() {
+            let block = block?;
+            block_lengths.push(block.len());
+            receipts.extend(block);
+        }
+        Ok((receipts, block_lengths))
+    }
+}
+
+impl From<Receipts> for FlatReceipts {
+    fn from(receipts: Receipts) -> Self {
+        let mut flat =
+            Self::with_capacity(receipts.len(), receipts.receipt_vec.iter().map(Vec::len).sum());
+        for block_receipts in receipts.receipt_vec {
+            flat.blob_gas_used.push(None);
+            #[cfg(not(feature = "compressed-receipts"))]
+            {
+                flat.offsets.push(flat.receipts.len());
+                flat.receipts.extend(block_receipts);
+            }
+            #[cfg(feature = "compressed-receipts")]
+            flat.compressed.push(&block_receipts);
+        }
+        flat
+    }
+}
+
+/// Read-only view of the receipts of a [BundleStateWithReceipts](crate::BundleStateWithReceipts).
+///
+/// Receipts are accessed by block number, the layout in which the bundle stores them is not
+/// exposed.
+#[derive(Debug, Clone, Copy)]
+pub struct BundleReceipts<'a> {
+    /// Receipts of the bundle.
+    receipts: &'a FlatReceipts,
+    /// Block number of the first receipts.
+    first_block: BlockNumber,
+}
+
+impl<'a> BundleReceipts<'a> {
+    /// Create new view over receipts starting at `first_block`.
+    pub(crate) fn new(receipts: &'a FlatReceipts, first_block: BlockNumber) -> Self {
+        Self { receipts, first_block }
+    }
+
+    /// Return receipts of the block ordered by transaction number, or None if the block is not in
+    /// the bundle.
+    ///
+    /// If receipt is None it means it is pruned.
+    pub fn by_block(&self, block_number: BlockNumber) -> Option<&'a [Option<Receipt>]> {
+        let index = block_number.checked_sub(self.first_block)?;
+        self.receipts.block(usize::try_from(index).ok()?)
+    }
+
+    /// Iterate over all blocks and their receipts, ordered by block number.
+    pub fn iter_blocks(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = (BlockNumber, &'a [Option<Receipt>])> + ExactSizeIterator + 'a
+    {
+        let first_block = self.first_block;
+        self.receipts
+            .iter_blocks()
+            .enumerate()
+            .map(move |(index, receipts)| (first_block + index as BlockNumber, receipts))
+    }
+
+    /// Return number of receipts in all blocks, pruned receipts included.
+    pub fn total_count(&self) -> usize {
+        self.receipts.total_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FlatReceipts;
+    use crate::bundle_state::BundleStateBuilder;
+    use reth_primitives::{Receipt, Receipts};
+
+    fn receipt(cumulative_gas_used: u64) -> Option<Receipt> {
+        Some(Receipt { cumulative_gas_used, ..Default::default() })
+    }
+
+    fn blocks(receipts: &FlatReceipts) -> Vec<Vec<Option<Receipt>>> {
+        receipts.iter_blocks().map(<[_]>::to_vec).collect()
+    }
+
+    #[test]
+    fn flat_receipts() {
+        let nested = vec![vec![receipt(1), None], vec![], vec![receipt(2)], vec![receipt(3)]];
+        let mut receipts = FlatReceipts::from(Receipts::from_vec(nested.clone()));
+        assert_eq!(receipts.len(), 4);
+        assert_eq!(receipts.total_count(), 4);
+        assert_eq!(blocks(&receipts), nested);
+        assert_eq!(receipts.block(1), Some(&[][..]));
+        assert_eq!(receipts.block(4), None);
+
+        assert_eq!(receipts.nested(), &Receipts::from_vec(nested.clone()));
+
+        let mut other = receipts.clone();
+        assert_eq!(other.split_off(2), nested[2..].to_vec());
+        assert_eq!(blocks(&other), nested[..2].to_vec());
+        assert_eq!(other.nested(), &Receipts::from_vec(nested[..2].to_vec()));
+        assert_eq!(other.split_off(2), Vec::<Vec<_>>::new());
+
+        other.extend(FlatReceipts::from(Receipts::from_vec(nested[2..].to_vec())));
+        assert_eq!(other, receipts);
+
+        receipts.set_blob_gas_used(2, 131_072);
+        assert_eq!(receipts.blob_gas_used(2), Some(131_072));
+        assert_eq!(receipts.blob_gas_used(1), None);
+        assert_eq!(receipts.blob_gas_used(4), None);
+
+        receipts.remove_first(1);
+        assert_eq!(receipts.blob_gas_used(1), Some(131_072));
+        assert_eq!(blocks(&receipts), nested[1..].to_vec());
+        let flattened = (vec![receipt(2), receipt(3)], vec![0, 1, 1]);
+        #[cfg(not(feature = "compressed-receipts"))]
+        assert_eq!(receipts.into_flattened(), flattened);
+        #[cfg(feature = "compressed-receipts")]
+        assert_eq!(receipts.into_flattened(), Ok(flattened));
+    }
+
+    #[test]
+    fn receipts_view() {
+        let receipt = Receipt { cumulative_gas_used: 21_000, ..Default::default() };
+        let bundle = BundleStateBuilder::new(10)
+            .add_receipt(10, receipt.clone())
+            .add_receipt(10, Receipt::default())
+            .add_receipt(12, receipt.clone())
+            .build();
+        let view = bundle.receipts_view();
+
+        assert_eq!(view.total_count(), 3);
+        assert_eq!(view.by_block(9), None);
+        assert_eq!(view.by_block(10), Some(&[Some(receipt.clone()), Some(Receipt::default())][..]));
+        assert_eq!(view.by_block(11), Some(&[][..]));
+        assert_eq!(view.by_block(13), None);
+        assert_eq!(
+            view.iter_blocks().map(|(block, receipts)| (block, receipts.len())).collect::<Vec<_>>(),
+            vec![(10, 2), (11, 0), (12, 1)]
+        );
+        assert_eq!(
+            view.iter_blocks().rev().map(|(block, _)| block).collect::<Vec<_>>(),
+            vec![12, 11, 10]
+        );
+    }
+}
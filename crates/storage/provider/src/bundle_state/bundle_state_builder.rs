@@ -0,0 +1,207 @@
+use crate::bundle_state::{BundleStateInit, BundleStateWithReceipts, RevertsInit};
+use reth_primitives::{
+    Account, Address, BlockNumber, Bytecode, Receipt, Receipts, StorageEntry, H256, U256,
+};
+use std::collections::{hash_map, BTreeMap, HashMap};
+
+/// Builder for [BundleStateWithReceipts].
+///
+/// Changes can be added in any order, [BundleStateBuilder::build] orders them by block number.
+/// The built bundle contains every block from `first_block` up to the highest block number that
+/// was added, blocks without changes get empty reverts and receipts.
+#[derive(Debug, Default)]
+pub struct BundleStateBuilder {
+    /// First block of the bundle.
+    first_block: BlockNumber,
+    /// Account changes as `(block, address, old, new)`.
+    accounts: Vec<(BlockNumber, Address, Option<Account>, Option<Account>)>,
+    /// Storage changes as `(block, address, slot, old, new)`.
+    storage: Vec<(BlockNumber, Address, H256, U256, U256)>,
+    /// Created contracts.
+    contracts: Vec<(H256, Bytecode)>,
+    /// Receipts by block number.
+    receipts: BTreeMap<BlockNumber, Vec<Option<Receipt>>>,
+}
+
+impl BundleStateBuilder {
+    /// Create new builder for a bundle starting at `first_block`.
+    pub fn new(first_block: BlockNumber) -> Self {
+        Self { first_block, ..Default::default() }
+    }
+
+    /// Add account change from `old` to `new` in the given block.
+    ///
+    /// # Panics
+    ///
+    /// If block number is lower than the first block of the bundle.
+    pub fn add_account_change(
+        mut self,
+        block: BlockNumber,
+        address: Address,
+        old: Option<Account>,
+        new: Option<Account>,
+    ) -> Self {
+        self.assert_block(block);
+        self.accounts.push((block, address, old, new));
+        self
+    }
+
+    /// Add storage slot change from `old` to `new` in the given block.
+    ///
+    /// If the account has no account changes, it is treated as non existing in the bundle.
+    ///
+    /// # Panics
+    ///
+    /// If block number is lower than the first block of the bundle.
+    pub fn add_storage_change(
+        mut self,
+        block: BlockNumber,
+        address: Address,
+        slot: H256,
+        old: U256,
+        new: U256,
+    ) -> Self {
+        self.assert_block(block);
+        self.storage.push((block, address, slot, old, new));
+        self
+    }
+
+    /// Add contract bytecode.
+    pub fn add_contract(mut self, hash: H256, bytecode: Bytecode) -> Self {
+        self.contracts.push((hash, bytecode));
+        self
+    }
+
+    /// Add receipt of the next transaction in the given block.
+    ///
+    /// # Panics
+    ///
+    /// If block number is lower than the first block of the bundle.
+    pub fn add_receipt(mut self, block: BlockNumber, receipt: Receipt) -> Self {
+        self.assert_block(block);
+        self.receipts.entry(block).or_default().push(Some(receipt));
+        self
+    }
+
+    /// Build the [BundleStateWithReceipts].
+    pub fn build(mut self) -> BundleStateWithReceipts {
+        let last_block = self
+            .accounts
+            .iter()
+            .map(|(block, ..)| *block)
+            .chain(self.storage.iter().map(|(block, ..)| *block))
+            .chain(self.receipts.keys().copied())
+            .max();
+        let Some(last_block) = last_block else {
+            return BundleStateWithReceipts::new_init(
+                HashMap::new(),
+                HashMap::new(),
+                self.contracts,
+                Receipts::new(),
+                self.first_block,
+            )
+        };
+        let blocks = self.first_block..=last_block;
+
+        let mut state: BundleStateInit = HashMap::new();
+        let mut reverts: RevertsInit =
+            blocks.clone().map(|block| (block, HashMap::new())).collect();
+
+        // Stable sort keeps the insertion order of changes within the same block.
+        self.accounts.sort_by_key(|(block, ..)| *block);
+        for (block, address, old, new) in self.accounts {
+            match state.entry(address) {
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert((old, new, HashMap::new()));
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().1 = new;
+                }
+            }
+            // First change in the block holds the value before the block.
+            let account_revert = reverts.entry(block).or_default().entry(address).or_default();
+            if account_revert.0.is_none() {
+                account_revert.0 = Some(old);
+            }
+        }
+
+        self.storage.sort_by_key(|(block, ..)| *block);
+        for (block, address, slot, old, new) in self.storage {
+            let account_state =
+                state.entry(address).or_insert_with(|| (None, None, HashMap::new()));
+            match account_state.2.entry(slot) {
+                hash_map::Entry::Vacant(entry) => {
+                    entry.insert((old, new));
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().1 = new;
+                }
+            }
+
+            let storage_reverts =
+                &mut reverts.entry(block).or_default().entry(address).or_default().1;
+            if !storage_reverts.iter().any(|entry| entry.key == slot) {
+                storage_reverts.push(StorageEntry { key: slot, value: old });
+            }
+        }
+
+        let receipts = blocks
+            .map(|block| self.receipts.remove(&block).unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        BundleStateWithReceipts::new_init(
+            state,
+            reverts,
+            self.contracts,
+            Receipts::from_vec(receipts),
+            self.first_block,
+        )
+    }
+
+    fn assert_block(&self, block: BlockNumber) {
+        assert!(
+            block >= self.first_block,
+            "block {block} is lower than the first block {}",
+            self.first_block
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BundleStateBuilder;
+    use reth_primitives::{Account, Address, Receipt, H256, U256};
+
+    #[test]
+    fn build() {
+        let address = Address::repeat_byte(0x11);
+        let account_1 = Account { nonce: 1, ..Default::default() };
+        let account_2 = Account { nonce: 2, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+
+        let bundle = BundleStateBuilder::new(10)
+            .add_account_change(12, address, Some(account_1), Some(account_2))
+            .add_account_change(10, address, None, Some(account_1))
+            .add_storage_change(10, address, slot, U256::ZERO, U256::from(1))
+            .add_storage_change(12, address, slot, U256::from(1), U256::from(2))
+            .add_receipt(11, Receipt::default())
+            .build();
+
+        assert_eq!(bundle.first_block(), 10);
+        assert_eq!(bundle.len(), 3);
+        assert!(bundle.receipts_view().by_block(10).unwrap_or_default().is_empty());
+        assert_eq!(
+            bundle.receipts_view().by_block(11).unwrap_or_default(),
+            &[Some(Receipt::default())]
+        );
+        assert_eq!(bundle.account(&address), Some(Some(account_2)));
+        assert_eq!(bundle.storage(&address, U256::from(1)), Some(U256::from(2)));
+        assert_eq!(bundle.state().account(&address).unwrap().original_info, None);
+    }
+
+    #[test]
+    #[should_panic]
+    fn block_before_first_block() {
+        BundleStateBuilder::new(10).add_receipt(9, Receipt::default());
+    }
+}
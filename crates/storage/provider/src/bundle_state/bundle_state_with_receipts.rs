@@ -1,39 +1,128 @@
+use parking_lot::Mutex;
 use reth_db::{
-    cursor::{DbCursorRO, DbCursorRW},
+    cursor::{DbCursorRO, DbCursorRW, DbDupCursorRO},
+    database::Database,
+    models::{AccountBeforeTx, BlockNumberAddress},
+    table::Compress,
     tables,
     transaction::{DbTx, DbTxMut},
 };
-use reth_interfaces::db::DatabaseError;
+use reth_interfaces::{db::DatabaseError, provider::ProviderError, RethError, RethResult};
 use reth_primitives::{
-    bloom::logs_bloom, keccak256, Account, Address, BlockNumber, Bloom, Bytecode, Log, Receipt,
-    Receipts, StorageEntry, H256, U256,
+    bloom::logs_bloom, keccak256, trie::Nibbles, Account, Address, BlockNumber, Bloom, Bytecode,
+    Bytes, Log, Receipt, Receipts, StorageEntry, H256, U256,
 };
 use reth_revm_primitives::{
-    db::states::BundleState, into_reth_acc, into_revm_acc, primitives::AccountInfo,
+    db::states::{reverts::AccountInfoRevert, BundleAccount, BundleState, RevertToSlot},
+    into_reth_acc, into_revm_acc,
+    primitives::AccountInfo,
 };
+use reth_rlp::{Decodable, DecodeError, Encodable};
 use reth_trie::{
-    hashed_cursor::{HashedPostState, HashedPostStateCursorFactory, HashedStorage},
-    StateRoot, StateRootError,
+    hashed_cursor::{
+        HashedCursorFactory, HashedPostState, HashedPostStateCursorFactory, HashedStorage,
+    },
+    prefix_set::{PrefixSet, PrefixSetMut},
+    StateRoot, StateRootError, StorageRoot, StorageRootError,
+};
+use std::{
+    collections::{hash_map, BTreeMap, HashMap, HashSet},
+    ops::{Range, RangeInclusive},
+    sync::OnceLock,
 };
-use std::collections::HashMap;
 
 pub use reth_revm_primitives::db::states::OriginalValuesKnown;
 
-use crate::{StateChanges, StateReverts};
+use crate::{
+    bundle_state::{
+        bundle_receipts::FlatReceipts, BundleChangesets, BundleReceipts, InMemoryState,
+    },
+    ProviderFactory, StateChanges, StateReverts,
+};
+
+bitflags::bitflags! {
+    /// Tables written by [BundleStateWithReceipts::write_to_db].
+    ///
+    /// [Default] selects all tables.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WriteTables: u8 {
+        /// Receipts of all blocks.
+        const RECEIPTS = 0b0001;
+        /// Account and storage changesets created from the reverts.
+        const CHANGESETS = 0b0010;
+        /// Plain account and storage state.
+        const PLAIN_STATE = 0b0100;
+        /// Bytecodes of created contracts.
+        const BYTECODES = 0b1000;
+    }
+}
+
+impl Default for WriteTables {
+    fn default() -> Self {
+        Self::all()
+    }
+}
 
 /// Bundle state of post execution changes and reverts
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[derive(Default, Debug, Clone)]
 pub struct BundleStateWithReceipts {
     /// Bundle state with reverts.
     bundle: BundleState,
     /// The collection of receipts.
-    /// Outer vector stores receipts for each block sequentially.
-    /// The inner vector stores receipts ordered by transaction number.
+    /// Receipts of all blocks are stored sequentially in a single vector, ordered by block and
+    /// transaction number.
     ///
     /// If receipt is None it means it is pruned.
-    receipts: Receipts,
+    receipts: FlatReceipts,
     /// First block of bundle state.
     first_block: BlockNumber,
+    /// Whether a lower part of the bundle was detached, see [Self::split_at].
+    ///
+    /// Original values of such a bundle are the values before the detached part, so they can't
+    /// be used to skip unchanged values when writing to the database, see
+    /// [Self::rebuild_plain_state_from_reverts].
+    detached: bool,
+    /// Hashed state and prefix sets used by [Self::state_root_slow], computed on first use and
+    /// cleared when the state of the bundle changes, together with the storage roots computed by
+    /// the last [Self::state_root_slow].
+    hashed_state: OnceLock<HashedStateCache>,
+}
+
+impl PartialEq for BundleStateWithReceipts {
+    fn eq(&self, other: &Self) -> bool {
+        // Hashed state cache is derived from the bundle state.
+        self.bundle == other.bundle &&
+            self.receipts == other.receipts &&
+            self.first_block == other.first_block &&
+            self.detached == other.detached
+    }
+}
+
+impl Eq for BundleStateWithReceipts {}
+
+/// Sorted hashed post state of the bundle with its prefix sets.
+#[derive(Debug)]
+struct HashedStateCache {
+    /// Sorted hashed post state.
+    hashed_state: HashedPostState,
+    /// Prefix set of changed accounts.
+    account_prefix_set: PrefixSet,
+    /// Prefix sets of changed storage slots by hashed address.
+    storage_prefix_sets: HashMap<H256, PrefixSet>,
+    /// Storage roots of the accounts with changed storage by hashed address, computed by the last
+    /// [BundleStateWithReceipts::state_root_slow].
+    storage_roots: Mutex<HashMap<H256, H256>>,
+}
+
+impl Clone for HashedStateCache {
+    fn clone(&self) -> Self {
+        Self {
+            hashed_state: self.hashed_state.clone(),
+            account_prefix_set: self.account_prefix_set.clone(),
+            storage_prefix_sets: self.storage_prefix_sets.clone(),
+            storage_roots: Mutex::new(self.storage_roots.lock().clone()),
+        }
+    }
 }
 
 /// Type used to initialize revms bundle state.
@@ -46,10 +135,329 @@ pub type AccountRevertInit = (Option<Option<Account>>, Vec<StorageEntry>);
 /// Type used to initialize revms reverts.
 pub type RevertsInit = HashMap<BlockNumber, HashMap<Address, AccountRevertInit>>;
 
+/// Present accounts with their present non-zero storage, see
+/// [BundleStateWithReceipts::to_plain_state].
+pub type PlainStateSnapshot = HashMap<Address, (Account, HashMap<H256, U256>)>;
+
+/// Error returned by [BundleStateWithReceipts::try_revert_to].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RevertError {
+    /// Target block is not part of the bundle.
+    #[error("block {block_number} is not in the bundle of {len} blocks starting at {first_block}")]
+    BlockNotInBundle {
+        /// Requested block number.
+        block_number: BlockNumber,
+        /// First block of the bundle.
+        first_block: BlockNumber,
+        /// Number of blocks in the bundle.
+        len: usize,
+    },
+}
+
+/// Error returned by [BundleStateWithReceipts::try_block_logs_bloom] when a block has more logs
+/// than allowed.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("block {block_number} has {logs} logs, more than the maximum of {max_logs}")]
+pub struct TooManyLogs {
+    /// Block number.
+    pub block_number: BlockNumber,
+    /// Number of logs in the block.
+    pub logs: usize,
+    /// Maximum number of logs.
+    pub max_logs: usize,
+}
+
+/// Error returned by [BundleStateWithReceipts::check_block_alignment] when the number of blocks
+/// with receipts and with reverts differ.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("bundle has receipts of {receipts} blocks and reverts of {reverts} blocks")]
+pub struct AlignmentError {
+    /// Number of blocks with receipts.
+    pub receipts: usize,
+    /// Number of blocks with reverts.
+    pub reverts: usize,
+}
+
+/// Receipt whose bloom does not match its logs, see
+/// [BundleStateWithReceipts::verify_receipt_blooms].
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("bloom of receipt {tx_index} in block {block_number} does not match its logs")]
+pub struct BloomMismatch {
+    /// Block number of the receipt.
+    pub block_number: BlockNumber,
+    /// Index of the receipt in the block.
+    pub tx_index: usize,
+}
+
+/// Error returned when a block is not in the bundle.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("block {block_number} is not in the bundle")]
+pub struct OutOfRange {
+    /// Requested block number.
+    pub block_number: BlockNumber,
+}
+
+/// Error returned by [BundleStateWithReceipts::concat] and
+/// [BundleStateWithReceipts::from_per_block] for misgrouped bundles.
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcatError {
+    /// Bundle does not contain exactly one block.
+    #[error(
+        "bundle at index {index} starting at block {first_block} has {len} blocks, expected 1"
+    )]
+    NotSingleBlock {
+        /// Index of the bundle in the input.
+        index: usize,
+        /// First block of the bundle.
+        first_block: BlockNumber,
+        /// Number of blocks in the bundle.
+        len: usize,
+    },
+    /// Bundle does not follow the previous bundle.
+    #[error("bundle at index {index} is for block {got}, expected block {expected}")]
+    NonContiguous {
+        /// Index of the bundle in the input.
+        index: usize,
+        /// Block following the previous bundle.
+        expected: BlockNumber,
+        /// First block of the bundle.
+        got: BlockNumber,
+    },
+}
+
+/// Error returned by [BundleStateWithReceipts::merge_shared_base] if the shared blocks of the
+/// bundles differ.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MergeError {
+    /// Bundles start at different blocks, or the shared block is not the last block of this
+    /// bundle or is not in the other bundle.
+    #[error(
+        "block {shared_up_to} is not shared by bundles from {first_block} and {other_first_block}"
+    )]
+    NotShared {
+        /// Last shared block.
+        shared_up_to: BlockNumber,
+        /// First block of this bundle.
+        first_block: BlockNumber,
+        /// First block of the other bundle.
+        other_first_block: BlockNumber,
+    },
+    /// Receipts of a shared block differ.
+    #[error(transparent)]
+    Receipts(#[from] ReceiptMismatch),
+    /// Reverts of a shared block differ.
+    #[error("reverts of shared block {0} differ")]
+    Reverts(BlockNumber),
+    /// Value of the account before the first merged block differs from its value in this bundle.
+    #[error("account {0:?} before the first merged block differs from this bundle")]
+    Base(Address),
+}
+
+/// First receipt that differs between two bundles, see [BundleStateWithReceipts::receipts_equal].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("receipts differ at block {block_number} transaction {tx_index}, fields: {fields:?}")]
+pub struct ReceiptMismatch {
+    /// Block number of the receipt.
+    pub block_number: BlockNumber,
+    /// Index of the transaction in the block.
+    pub tx_index: usize,
+    /// Receipt of the first bundle, None if it is missing or pruned.
+    pub left: Option<Receipt>,
+    /// Receipt of the second bundle, None if it is missing or pruned.
+    pub right: Option<Receipt>,
+    /// Names of the receipt fields that differ, empty if either receipt is None.
+    pub fields: Vec<&'static str>,
+}
+
+/// Error returned by [BundleStateWithReceipts::write_to_db] with the phase of the write that
+/// failed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BundleWriteError {
+    /// Writing [tables::Receipts] failed.
+    #[error("failed to write receipts: {0}")]
+    Receipts(#[source] DatabaseError),
+    /// Writing [tables::StorageChangeSet] failed.
+    #[error("failed to write storage reverts: {0}")]
+    StorageReverts(#[source] DatabaseError),
+    /// Writing [tables::AccountChangeSet] failed.
+    #[error("failed to write account reverts: {0}")]
+    AccountReverts(#[source] DatabaseError),
+    /// Writing [tables::PlainStorageState] failed.
+    #[error("failed to write plain storage state: {0}")]
+    PlainStorage(#[source] DatabaseError),
+    /// Writing [tables::PlainAccountState] failed.
+    #[error("failed to write plain account state: {0}")]
+    PlainAccounts(#[source] DatabaseError),
+    /// Writing [tables::Bytecodes] failed.
+    #[error("failed to write bytecodes: {0}")]
+    Bytecodes(#[source] DatabaseError),
+    /// Receipts and reverts of the bundle are for a different number of blocks, nothing was
+    /// written. See [BundleStateWithReceipts::check_block_alignment].
+    #[error(
+        "bundle has receipts of {receipt_blocks} blocks and reverts of {revert_blocks} blocks"
+    )]
+    Misaligned {
+        /// Number of blocks with receipts.
+        receipt_blocks: usize,
+        /// Number of blocks with reverts.
+        revert_blocks: usize,
+    },
+    /// Original values were passed as known for a bundle whose lower part was detached, nothing
+    /// was written. See [BundleStateWithReceipts::has_complete_plain_state].
+    #[error("original values of a bundle with a detached lower part are not known")]
+    UnknownOriginalValues,
+}
+
+impl BundleWriteError {
+    /// Return the underlying database error, None if the bundle was rejected before writing.
+    pub fn into_inner(self) -> Option<DatabaseError> {
+        match self {
+            Self::Receipts(err) |
+            Self::StorageReverts(err) |
+            Self::AccountReverts(err) |
+            Self::PlainStorage(err) |
+            Self::PlainAccounts(err) |
+            Self::Bytecodes(err) => Some(err),
+            Self::Misaligned { .. } | Self::UnknownOriginalValues => None,
+        }
+    }
+}
+
+impl From<AlignmentError> for BundleWriteError {
+    fn from(err: AlignmentError) -> Self {
+        Self::Misaligned { receipt_blocks: err.receipts, revert_blocks: err.reverts }
+    }
+}
+
+impl From<BundleWriteError> for RethError {
+    fn from(err: BundleWriteError) -> Self {
+        match err {
+            BundleWriteError::Receipts(err) |
+            BundleWriteError::StorageReverts(err) |
+            BundleWriteError::AccountReverts(err) |
+            BundleWriteError::PlainStorage(err) |
+            BundleWriteError::PlainAccounts(err) |
+            BundleWriteError::Bytecodes(err) => RethError::Database(err),
+            err @ BundleWriteError::Misaligned { .. } => RethError::Custom(err.to_string()),
+            err @ BundleWriteError::UnknownOriginalValues => RethError::Custom(err.to_string()),
+        }
+    }
+}
+
+/// Error returned by [BundleStateWithReceipts::write_to_db_verified].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifiedWriteError {
+    /// Writing the bundle failed.
+    #[error(transparent)]
+    Write(#[from] BundleWriteError),
+    /// Computing the state root failed.
+    #[error("failed to compute state root: {0}")]
+    StateRoot(#[from] StateRootError),
+    /// State root of the bundle does not match the expected root, nothing was written.
+    #[error("state root mismatch, expected {expected:?}, got {got:?}")]
+    StateRootMismatch {
+        /// Expected state root.
+        expected: H256,
+        /// State root of the bundle.
+        got: H256,
+    },
+}
+
+impl From<VerifiedWriteError> for RethError {
+    fn from(err: VerifiedWriteError) -> Self {
+        match err {
+            VerifiedWriteError::Write(err) => err.into(),
+            VerifiedWriteError::StateRoot(err) => RethError::Database(err.into()),
+            err @ VerifiedWriteError::StateRootMismatch { .. } => {
+                RethError::Custom(err.to_string())
+            }
+        }
+    }
+}
+
+/// Error returned by [BundleStateWithReceipts::verify_bytecode_references].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MissingBytecode {
+    /// Bytecode of the account is neither in the bundle nor in [tables::Bytecodes].
+    #[error("bytecode {code_hash:?} of account {address:?} is missing")]
+    NotFound {
+        /// Account address.
+        address: Address,
+        /// Code hash of the account.
+        code_hash: H256,
+    },
+    /// Reading [tables::Bytecodes] failed.
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+impl From<MissingBytecode> for RethError {
+    fn from(err: MissingBytecode) -> Self {
+        match err {
+            MissingBytecode::Database(err) => RethError::Database(err),
+            err @ MissingBytecode::NotFound { .. } => RethError::Custom(err.to_string()),
+        }
+    }
+}
+
+/// Number of bundle state changes that differ from the values persisted in the database.
+///
+/// See [BundleStateWithReceipts::effective_diff].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EffectiveDiff {
+    /// Number of accounts whose present value differs from the database.
+    pub changed_accounts: usize,
+    /// Number of accounts whose present value is the same as in the database.
+    pub unchanged_accounts: usize,
+    /// Number of storage slots whose present value differs from the database.
+    pub changed_storage: usize,
+    /// Number of storage slots whose present value is the same as in the database.
+    pub unchanged_storage: usize,
+}
+
+/// Code hashes of the bytecodes of a bundle at some point, used to find the bytecodes added
+/// since.
+///
+/// See [BundleStateWithReceipts::contract_checkpoint] and
+/// [BundleStateWithReceipts::write_to_db_since_checkpoint].
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ContractCheckpoint {
+    /// Code hashes of the bytecodes at the checkpoint.
+    code_hashes: HashSet<H256>,
+}
+
+impl ContractCheckpoint {
+    /// Return true if the bytecode with the code hash is in the checkpoint.
+    pub fn contains(&self, code_hash: &H256) -> bool {
+        self.code_hashes.contains(code_hash)
+    }
+}
+
+/// Size of the reverts of a single block of the bundle.
+///
+/// See [BundleStateWithReceipts::reverts_report].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockRevertStats {
+    /// Block number.
+    pub block_number: BlockNumber,
+    /// Number of account reverts written to the account changeset.
+    pub account_reverts: usize,
+    /// Number of storage slot reverts, not including slots of wiped storage.
+    pub storage_reverts: usize,
+    /// Number of accounts with wiped storage, which is read from the plain state on write.
+    pub wiped_accounts: usize,
+}
+
 impl BundleStateWithReceipts {
     /// Create Bundle State.
     pub fn new(bundle: BundleState, receipts: Receipts, first_block: BlockNumber) -> Self {
-        Self { bundle, receipts, first_block }
+        Self {
+            bundle,
+            receipts: receipts.into(),
+            first_block,
+            detached: false,
+            hashed_state: OnceLock::new(),
+        }
     }
 
     /// Create new bundle state with receipts.
@@ -87,7 +495,135 @@ impl BundleStateWithReceipts {
             contracts_init.into_iter().map(|(code_hash, bytecode)| (code_hash, bytecode.0)),
         );
 
-        Self { bundle, receipts, first_block }
+        Self {
+            bundle,
+            receipts: receipts.into(),
+            first_block,
+            detached: false,
+            hashed_state: OnceLock::new(),
+        }
+    }
+
+    /// Reconstruct the bundle state of already persisted blocks in the given range.
+    ///
+    /// Account and storage changesets are read so that the reverts of the returned bundle
+    /// recreate the state before the first block of the range. Present values are taken from the
+    /// plain state, so the range is expected to end at the database tip. Bytecodes of the changed
+    /// accounts are read from [tables::Bytecodes], bytecodes missing from the table are skipped,
+    /// see [Self::verify_bytecode_references].
+    ///
+    /// Returns [ProviderError::BlockBodyIndicesNotFound] if a block of the range has no body
+    /// indices.
+    ///
+    /// This is the read-only inverse of [Self::write_to_db].
+    pub fn from_db_range<'tx, TX: DbTx<'tx>>(
+        tx: &TX,
+        range: RangeInclusive<BlockNumber>,
+    ) -> Result<Self, ProviderError> {
+        if range.is_empty() {
+            return Ok(Self::default())
+        }
+        let first_block = *range.start();
+
+        let mut block_bodies_cursor = tx.cursor_read::<tables::BlockBodyIndices>()?;
+        let block_bodies = range
+            .clone()
+            .map(|block_number| {
+                block_bodies_cursor
+                    .seek_exact(block_number)?
+                    .ok_or(ProviderError::BlockBodyIndicesNotFound(block_number))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let account_changeset = tx
+            .cursor_dup_read::<tables::AccountChangeSet>()?
+            .walk_range(range.clone())?
+            .collect::<Result<Vec<_>, _>>()?;
+        let storage_changeset = tx
+            .cursor_dup_read::<tables::StorageChangeSet>()?
+            .walk_range(BlockNumberAddress::range(range))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut plain_accounts_cursor = tx.cursor_read::<tables::PlainAccountState>()?;
+        let mut plain_storage_cursor = tx.cursor_dup_read::<tables::PlainStorageState>()?;
+
+        let mut state: BundleStateInit = HashMap::new();
+        // Every block needs a revert, even if nothing was changed in it.
+        let mut reverts: RevertsInit =
+            block_bodies.iter().map(|(block_number, _)| (*block_number, HashMap::new())).collect();
+
+        // Changesets are iterated in reverse so the oldest value ends up as the original one.
+        for (block_number, account_before) in account_changeset.into_iter().rev() {
+            let AccountBeforeTx { info: old_info, address } = account_before;
+            match state.entry(address) {
+                hash_map::Entry::Vacant(entry) => {
+                    let new_info = plain_accounts_cursor.seek_exact(address)?.map(|kv| kv.1);
+                    entry.insert((old_info, new_info, HashMap::new()));
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().0 = old_info;
+                }
+            }
+            reverts.entry(block_number).or_default().entry(address).or_default().0 = Some(old_info);
+        }
+
+        for (block_and_address, old_storage) in storage_changeset.into_iter().rev() {
+            let BlockNumberAddress((block_number, address)) = block_and_address;
+            let account_state = match state.entry(address) {
+                hash_map::Entry::Vacant(entry) => {
+                    let present_info = plain_accounts_cursor.seek_exact(address)?.map(|kv| kv.1);
+                    entry.insert((present_info, present_info, HashMap::new()))
+                }
+                hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            };
+
+            match account_state.2.entry(old_storage.key) {
+                hash_map::Entry::Vacant(entry) => {
+                    let new_storage = plain_storage_cursor
+                        .seek_by_key_subkey(address, old_storage.key)?
+                        .filter(|storage| storage.key == old_storage.key)
+                        .unwrap_or_default();
+                    entry.insert((old_storage.value, new_storage.value));
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().0 = old_storage.value;
+                }
+            };
+
+            reverts
+                .entry(block_number)
+                .or_default()
+                .entry(address)
+                .or_default()
+                .1
+                .push(old_storage);
+        }
+
+        let code_hashes = state
+            .values()
+            .flat_map(|(original, present, _)| [original, present])
+            .filter_map(|info| info.as_ref()?.bytecode_hash)
+            .collect::<HashSet<_>>();
+        let mut bytecodes_cursor = tx.cursor_read::<tables::Bytecodes>()?;
+        let mut contracts = Vec::with_capacity(code_hashes.len());
+        for code_hash in code_hashes {
+            if let Some((_, bytecode)) = bytecodes_cursor.seek_exact(code_hash)? {
+                contracts.push((code_hash, bytecode));
+            }
+        }
+
+        // Missing receipts are pruned.
+        let mut receipts_cursor = tx.cursor_read::<tables::Receipts>()?;
+        let mut receipts = Vec::with_capacity(block_bodies.len());
+        for (_, block_body) in block_bodies {
+            let mut block_receipts = Vec::with_capacity(block_body.tx_count as usize);
+            for tx_num in block_body.tx_num_range() {
+                block_receipts
+                    .push(receipts_cursor.seek_exact(tx_num)?.map(|(_, receipt)| receipt));
+            }
+            receipts.push(block_receipts);
+        }
+
+        Ok(Self::new_init(state, reverts, contracts, Receipts::from_vec(receipts), first_block))
     }
 
     /// Return revm bundle state.
@@ -105,51 +641,371 @@ impl BundleStateWithReceipts {
         self.bundle.state().iter().map(|(a, acc)| (*a, acc.info.as_ref()))
     }
 
+    /// Return iterator over account changes as `(address, original, present)`, without storage.
+    ///
+    /// Destroyed accounts have no present info.
+    pub fn account_changes_only(
+        &self,
+    ) -> impl Iterator<Item = (Address, Option<Account>, Option<Account>)> + '_ {
+        self.bundle.state().iter().map(|(address, account)| {
+            (
+                *address,
+                account.original_info.clone().map(into_reth_acc),
+                account.info.clone().map(into_reth_acc),
+            )
+        })
+    }
+
+    /// Return iterator over changed storage slots of all accounts as
+    /// `(address, slot, original, present)`, in no particular order.
+    ///
+    /// Slots with the same original and present value are skipped.
+    pub fn all_storage_changes(&self) -> impl Iterator<Item = (Address, U256, U256, U256)> + '_ {
+        self.bundle.state().iter().flat_map(|(address, account)| {
+            account
+                .storage
+                .iter()
+                .filter(|(_, slot)| slot.previous_or_original_value != slot.present_value)
+                .map(|(key, slot)| {
+                    (*address, *key, slot.previous_or_original_value, slot.present_value)
+                })
+        })
+    }
+
+    /// Return iterator over accounts whose storage was wiped in the bundle.
+    ///
+    /// Reverts of these accounts read the whole plain storage of the account, see
+    /// [StateReverts::write_to_db].
+    pub fn wiped_storage_accounts(&self) -> impl Iterator<Item = Address> + '_ {
+        self.bundle
+            .state()
+            .iter()
+            .filter(|(_, account)| account.status.was_destroyed())
+            .map(|(address, _)| *address)
+    }
+
+    /// Return iterator over accounts that did not exist before the bundle and do not exist after
+    /// it, because they were created and destroyed within the bundle.
+    ///
+    /// These accounts have no plain state, [Self::write_to_db] does not write them to
+    /// [tables::PlainAccountState].
+    pub fn transient_accounts(&self) -> impl Iterator<Item = Address> + '_ {
+        self.bundle
+            .state()
+            .iter()
+            .filter(|(_, account)| account.original_info.is_none() && account.info.is_none())
+            .map(|(address, _)| *address)
+    }
+
+    /// Return all touched accounts with their present info, sorted by address.
+    ///
+    /// Meant for debugging and test assertions, not for hot paths.
+    pub fn sorted_accounts(&self) -> Vec<(Address, Option<Account>)> {
+        let mut accounts = self
+            .accounts_iter()
+            .map(|(address, info)| (address, info.cloned().map(into_reth_acc)))
+            .collect::<Vec<_>>();
+        accounts.sort_unstable_by_key(|(address, _)| *address);
+        accounts
+    }
+
+    /// Return present state of all existing accounts in the bundle, destroyed accounts are
+    /// skipped.
+    ///
+    /// Storage contains only the non-zero slots known to the bundle, for accounts that were not
+    /// destroyed slots that were not changed have to be taken from the database.
+    pub fn to_plain_state(&self) -> PlainStateSnapshot {
+        self.bundle
+            .state()
+            .iter()
+            .filter_map(|(address, account)| {
+                let info = into_reth_acc(account.info.clone()?);
+                let storage = account
+                    .storage
+                    .iter()
+                    .filter(|(_, slot)| slot.present_value != U256::ZERO)
+                    .map(|(key, slot)| (H256(key.to_be_bytes()), slot.present_value))
+                    .collect();
+                Some((*address, (info, storage)))
+            })
+            .collect()
+    }
+
+    /// Apply the plain state changes of the bundle to an in-memory plain state, the same way
+    /// [Self::write_to_db] writes them to the plain state tables.
+    ///
+    /// Original values are treated as not known. Reverts and receipts are not applied.
+    pub fn apply_to_memory(&self, state: &mut InMemoryState) {
+        let (plain_state, _) =
+            self.bundle.clone().into_plain_state_and_reverts(OriginalValuesKnown::No);
+        StateChanges(plain_state).apply_to_memory(state);
+    }
+
+    /// Return present account and storage changes of the bundle in canonical order.
+    pub fn changesets(&self) -> BundleChangesets {
+        BundleChangesets::from(&self.bundle)
+    }
+
+    /// Encode present account and storage changes of the bundle with RLP.
+    ///
+    /// Unlike [Self::write_to_db], the encoding does not depend on the database, so it can be
+    /// hashed or shared as a portable representation of the state transition. Decode it with
+    /// [Self::decode_changesets_rlp].
+    pub fn encode_changesets_rlp(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.changesets().encode(&mut out);
+        out
+    }
+
+    /// Return a digest of the bundle content, for example to deduplicate bundles.
+    ///
+    /// Hashes the first block, the canonical present account and storage changes of
+    /// [Self::changesets], the sorted hashes of the contracts and the receipts root of every
+    /// block. Blocks with pruned receipts have no receipts root and are hashed as zero. Reverts
+    /// are not included, so unlike [PartialEq] bundles that only differ in reverts have the same
+    /// hash.
+    pub fn content_hash(&self) -> H256 {
+        let mut contracts = self.bundle.contracts.keys().copied().collect::<Vec<_>>();
+        contracts.sort_unstable();
+        let receipts_roots = (0..self.receipts.len())
+            .map(|index| self.receipts.root_slow(index).unwrap_or_default())
+            .collect::<Vec<_>>();
+
+        let mut out = Vec::new();
+        self.first_block.encode(&mut out);
+        self.changesets().encode(&mut out);
+        contracts.encode(&mut out);
+        receipts_roots.encode(&mut out);
+        keccak256(out)
+    }
+
+    /// Decode changesets encoded by [Self::encode_changesets_rlp].
+    ///
+    /// The whole buffer has to be consumed.
+    pub fn decode_changesets_rlp(mut buf: &[u8]) -> Result<BundleChangesets, DecodeError> {
+        let changesets = BundleChangesets::decode(&mut buf)?;
+        if !buf.is_empty() {
+            return Err(DecodeError::UnexpectedLength)
+        }
+        Ok(changesets)
+    }
+
+    /// Number of account, storage and revert entries in the bundle, see [BundleState::size_hint].
+    pub fn size_hint(&self) -> usize {
+        self.bundle.size_hint()
+    }
+
+    /// Release excess capacity of the receipts, accounts, storage and bytecodes of the bundle.
+    ///
+    /// Capacity is retained by [Self::extend] and [Self::revert_to], long-lived bundles can call
+    /// this after them. Reverts are not shrunk. Content of the bundle and [Self::size_hint] do
+    /// not change.
+    pub fn shrink_to_fit(&mut self) {
+        self.receipts.shrink_to_fit();
+        self.bundle.state.shrink_to_fit();
+        for account in self.bundle.state.values_mut() {
+            account.storage.shrink_to_fit();
+        }
+        self.bundle.contracts.shrink_to_fit();
+    }
+
+    /// Keep only the accounts for which `predicate` returns true, removing the state and reverts
+    /// of all other accounts.
+    ///
+    /// Receipts, bytecodes and the blocks of the bundle are kept, so block numbers stay valid.
+    /// The bundle no longer contains all changes of its blocks: its state root, changesets and
+    /// plain state are not canonical and it should not be written to a database that is used
+    /// for anything but debugging. This is meant for minimized reproductions that only touch
+    /// some accounts.
+    pub fn retain_accounts(&mut self, predicate: impl Fn(&Address) -> bool) {
+        let BundleState { state, reverts, state_size, reverts_size, .. } = &mut self.bundle;
+        // Keep the size accounting in the units of [Self::size_hint].
+        state.retain(|address, account| {
+            let retain = predicate(address);
+            if !retain {
+                *state_size = state_size.saturating_sub(1 + account.storage.len());
+            }
+            retain
+        });
+        for block_reverts in reverts.iter_mut() {
+            block_reverts.retain(|(address, revert)| {
+                let retain = predicate(address);
+                if !retain {
+                    *reverts_size = reverts_size.saturating_sub(1 + revert.storage.len());
+                }
+                retain
+            });
+        }
+        self.hashed_state.take();
+    }
+
+    /// Remove all blocks, state and bytecodes of the bundle, keeping its first block.
+    ///
+    /// Allocated capacity of the receipts, accounts, bytecodes and reverts is kept, so the bundle
+    /// can be refilled without reallocating. See [Self::shrink_to_fit] to release it.
+    pub fn clear(&mut self) {
+        let mut state = std::mem::take(&mut self.bundle.state);
+        let mut contracts = std::mem::take(&mut self.bundle.contracts);
+        let mut reverts = std::mem::take(&mut self.bundle.reverts);
+        state.clear();
+        contracts.clear();
+        reverts.clear();
+        // Start from a default bundle so its size accounting is reset as well.
+        self.bundle = BundleState::default();
+        self.bundle.state = state;
+        self.bundle.contracts = contracts;
+        self.bundle.reverts = reverts;
+        self.receipts.clear();
+        self.detached = false;
+        self.hashed_state.take();
+    }
+
+    /// Same as [Self::clear], with the bundle starting at `first_block`.
+    pub fn reset_to(&mut self, first_block: BlockNumber) {
+        self.clear();
+        self.first_block = first_block;
+    }
+
+    /// Number of accounts touched by the bundle.
+    pub fn account_count(&self) -> usize {
+        self.bundle.state().len()
+    }
+
+    /// Number of storage slots touched by the bundle, summed over all accounts.
+    pub fn storage_slot_count(&self) -> usize {
+        self.bundle.state().values().map(|account| account.storage.len()).sum()
+    }
+
+    /// Number of storage slots touched by the bundle for the account, None if the account is not
+    /// in the bundle.
+    pub fn account_storage_slot_count(&self, address: &Address) -> Option<usize> {
+        self.bundle.account(address).map(|account| account.storage.len())
+    }
+
     /// Get account if account is known.
     pub fn account(&self, address: &Address) -> Option<Option<Account>> {
         self.bundle.account(address).map(|a| a.info.clone().map(into_reth_acc))
     }
 
+    /// Get accounts from the bundle, falling back to [tables::PlainAccountState] for accounts
+    /// that are not known.
+    ///
+    /// Accounts are returned in the order of `addresses`. Unknown accounts are read with a
+    /// single cursor in ascending address order.
+    pub fn accounts_or_db<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        addresses: &[Address],
+    ) -> Result<Vec<Option<Account>>, DatabaseError> {
+        let mut accounts =
+            addresses.iter().map(|address| self.account(address)).collect::<Vec<_>>();
+        let mut missing =
+            (0..addresses.len()).filter(|index| accounts[*index].is_none()).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            missing.sort_unstable_by_key(|index| addresses[*index]);
+            let mut cursor = tx.cursor_read::<tables::PlainAccountState>()?;
+            for index in missing {
+                accounts[index] =
+                    Some(cursor.seek_exact(addresses[index])?.map(|(_, account)| account));
+            }
+        }
+        Ok(accounts.into_iter().map(Option::flatten).collect())
+    }
+
+    /// Get account value at the start of the bundle if account is known.
+    ///
+    /// Inner None means the account did not exist before the bundle.
+    pub fn account_original(&self, address: &Address) -> Option<Option<Account>> {
+        self.bundle.account(address).map(|a| a.original_info.clone().map(into_reth_acc))
+    }
+
     /// Get storage if value is known.
     ///
-    /// This means that depending on status we can potentially return U256::ZERO.
+    /// This means that depending on status we can potentially return U256::ZERO:
+    /// - `Some(value)` if the slot was changed in the bundle, value can be zero.
+    /// - `Some(U256::ZERO)` if the slot was not changed but the storage of the account was wiped.
+    /// - `None` if the value is not known and has to be read from the database.
+    ///
+    /// See [Self::storage_known] to only check whether the value is known.
     pub fn storage(&self, address: &Address, storage_key: U256) -> Option<U256> {
         self.bundle.account(address).and_then(|a| a.storage_slot(storage_key))
     }
 
+    /// Return true if the storage value is known by the bundle, even if it is zero.
+    ///
+    /// If false, the value has to be read from the database, see [Self::storage].
+    pub fn storage_known(&self, address: &Address, storage_key: U256) -> bool {
+        self.storage(address, storage_key).is_some()
+    }
+
+    /// Get storage value at the start of the bundle if the slot is known.
+    pub fn storage_original(&self, address: &Address, slot: U256) -> Option<U256> {
+        self.bundle.account(address)?.storage.get(&slot).map(|slot| slot.previous_or_original_value)
+    }
+
+    /// Return true if only the storage of the account was changed, with its balance, nonce and
+    /// code the same as at the start of the bundle.
+    ///
+    /// Returns None if the account is not in the bundle.
+    pub fn is_storage_only_change(&self, address: &Address) -> Option<bool> {
+        let account = self.bundle.account(address)?;
+        let storage_changed = account.status.was_destroyed() ||
+            account
+                .storage
+                .values()
+                .any(|slot| slot.present_value != slot.previous_or_original_value);
+        Some(account.info == account.original_info && storage_changed)
+    }
+
     /// Return bytecode if known.
     pub fn bytecode(&self, code_hash: &H256) -> Option<Bytecode> {
         self.bundle.bytecode(code_hash).map(Bytecode)
     }
 
+    /// Return an iterator over all known bytecodes with their code hashes, in no particular order.
+    pub fn bytecodes_iter(&self) -> impl Iterator<Item = (H256, Bytecode)> + '_ {
+        self.bundle
+            .contracts
+            .iter()
+            .map(|(code_hash, bytecode)| (*code_hash, Bytecode(bytecode.clone())))
+    }
+
+    /// Return checkpoint of the current bytecodes of the bundle, see [Self::new_contracts_since].
+    pub fn contract_checkpoint(&self) -> ContractCheckpoint {
+        ContractCheckpoint { code_hashes: self.bundle.contracts.keys().copied().collect() }
+    }
+
+    /// Return an iterator over the bytecodes that were added to the bundle after the checkpoint,
+    /// in no particular order.
+    pub fn new_contracts_since(
+        &self,
+        checkpoint: ContractCheckpoint,
+    ) -> impl Iterator<Item = (H256, Bytecode)> + '_ {
+        self.bytecodes_iter().filter(move |(code_hash, _)| !checkpoint.contains(code_hash))
+    }
+
     /// Hash all changed accounts and storage entries that are currently stored in the post state.
     ///
     /// # Returns
     ///
     /// The hashed post state.
     pub fn hash_state_slow(&self) -> HashedPostState {
-        //let mut storages = BTreeMap::default();
-        let mut hashed_state = HashedPostState::default();
+        Self::hashed_post_state(
+            self.bundle.state().iter().map(|(address, account)| hash_account(address, account)),
+        )
+    }
 
-        for (address, account) in self.bundle.state() {
-            let hashed_address = keccak256(address);
-            if let Some(account) = &account.info {
-                hashed_state.insert_account(hashed_address, into_reth_acc(account.clone()))
+    /// Collect hashed accounts into a sorted [HashedPostState].
+    fn hashed_post_state(
+        hashed_accounts: impl IntoIterator<Item = (H256, Option<Account>, HashedStorage)>,
+    ) -> HashedPostState {
+        let mut hashed_state = HashedPostState::default();
+        for (hashed_address, account, hashed_storage) in hashed_accounts {
+            if let Some(account) = account {
+                hashed_state.insert_account(hashed_address, account)
             } else {
                 hashed_state.insert_cleared_account(hashed_address);
             }
-
-            // insert storage.
-            let mut hashed_storage = HashedStorage::new(account.status.was_destroyed());
-
-            for (key, value) in account.storage.iter() {
-                let hashed_key = keccak256(H256(key.to_be_bytes()));
-                if value.present_value == U256::ZERO {
-                    hashed_storage.insert_zero_valued_slot(hashed_key);
-                } else {
-                    hashed_storage.insert_non_zero_valued_storage(hashed_key, value.present_value);
-                }
-            }
             hashed_state.insert_hashed_storage(hashed_address, hashed_storage)
         }
         hashed_state.sorted()
@@ -160,10 +1016,13 @@ impl BundleStateWithReceipts {
     /// Afterwards, it retrieves the prefixsets from the [HashedPostState] and uses them to
     /// calculate the incremental state root.
     ///
+    /// The hashed state and prefix sets are cached, repeated calls on an unchanged bundle only
+    /// walk the trie.
+    ///
     /// # Example
     ///
     /// ```
-    /// use reth_primitives::{Account, U256, Receipts};
+    /// use reth_primitives::{proofs::EMPTY_ROOT, Account, U256, Receipts};
     /// use reth_provider::BundleStateWithReceipts;
     /// use reth_db::{test_utils::create_test_rw_db, database::Database};
     /// use std::collections::HashMap;
@@ -189,65 +1048,949 @@ impl BundleStateWithReceipts {
     ///
     /// // Calculate the state root
     /// let tx = db.tx().expect("failed to create transaction");
-    /// let state_root = bundle.state_root_slow(&tx);
+    /// let state_root = bundle.state_root_slow(&tx).expect("failed to calculate state root");
+    /// assert_ne!(state_root, EMPTY_ROOT);
     /// ```
     ///
     /// # Returns
     ///
     /// The state root for this [BundleState].
+    ///
+    /// Storage roots of the accounts with changed storage are computed from `tx` on every call and
+    /// cached for [Self::storage_root_slow].
     pub fn state_root_slow<'a, 'tx, TX: DbTx<'tx>>(
         &self,
         tx: &'a TX,
     ) -> Result<H256, StateRootError> {
-        let hashed_post_state = self.hash_state_slow();
-        let (account_prefix_set, storage_prefix_set) = hashed_post_state.construct_prefix_sets();
-        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, &hashed_post_state);
-        StateRoot::new(tx)
+        let cache = self.hashed_state_cache();
+        let storage_roots =
+            Self::changed_storage_roots(tx, &cache.hashed_state, &cache.storage_prefix_sets, 1)?;
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, &cache.hashed_state);
+        let root = StateRoot::new(tx)
             .with_hashed_cursor_factory(&hashed_cursor_factory)
-            .with_changed_account_prefixes(account_prefix_set)
-            .with_changed_storage_prefixes(storage_prefix_set)
-            .root()
+            .with_changed_account_prefixes(cache.account_prefix_set.clone())
+            .with_changed_storage_prefixes(cache.storage_prefix_sets.clone())
+            .with_storage_root_overrides(storage_roots.clone())
+            .root()?;
+        *cache.storage_roots.lock() = storage_roots;
+        Ok(root)
     }
 
-    /// Transform block number to the index of block.
-    fn block_number_to_index(&self, block_number: BlockNumber) -> Option<usize> {
-        if self.first_block > block_number {
-            return None
-        }
-        let index = block_number - self.first_block;
-        if index >= self.receipts.len() as u64 {
-            return None
+    /// Calculate the storage root of the account after this bundle.
+    ///
+    /// If the storage of the account was changed by the bundle, the root cached by the last
+    /// [Self::state_root_slow] is returned without reading `tx`. The cache is tied to the database
+    /// view of the `tx` passed to that call, it is replaced by every call of
+    /// [Self::state_root_slow] and cleared by every method that changes the state of the bundle,
+    /// like [Self::extend] or [Self::revert_to]. Callers that pass a different database view have
+    /// to call [Self::state_root_slow] with it first. Otherwise the root is computed from `tx`.
+    pub fn storage_root_slow<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        address: Address,
+    ) -> Result<H256, StorageRootError> {
+        let hashed_address = keccak256(address);
+        let cache = self.hashed_state_cache();
+        if let Some(storage_root) = cache.storage_roots.lock().get(&hashed_address) {
+            return Ok(*storage_root)
         }
-        Some(index as usize)
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, &cache.hashed_state);
+        StorageRoot::new_hashed_with_factory(tx, &hashed_cursor_factory, hashed_address)
+            .with_changed_prefixes(
+                cache.storage_prefix_sets.get(&hashed_address).cloned().unwrap_or_default(),
+            )
+            .root()
     }
 
-    /// Returns an iterator over all block logs.
-    pub fn logs(&self, block_number: BlockNumber) -> Option<impl Iterator<Item = &Log>> {
-        let index = self.block_number_to_index(block_number)?;
-        Some(self.receipts[index].iter().filter_map(|r| Some(r.as_ref()?.logs.iter())).flatten())
+    /// Calculate the state root for this bundle with hashed accounts and storage read from the
+    /// given factory.
+    ///
+    /// Same as [Self::state_root_slow], but the hashed state is not taken from
+    /// [Self::hash_state_slow]. The factory should return the hashed state after the bundle, as
+    /// only the accounts and storage slots in [Self::touched_prefix_sets] are re-hashed, other
+    /// nodes are read from the trie tables of `tx`.
+    pub fn state_root_slow_with_factory<'a, 'b, 'tx, TX, F>(
+        &self,
+        tx: &'a TX,
+        factory: &'b F,
+    ) -> Result<H256, StateRootError>
+    where
+        TX: DbTx<'tx>,
+        F: HashedCursorFactory<'b>,
+    {
+        let (account_prefix_set, storage_prefix_sets) = self.touched_prefix_sets();
+        StateRoot::new(tx)
+            .with_hashed_cursor_factory(factory)
+            .with_changed_account_prefixes(account_prefix_set)
+            .with_changed_storage_prefixes(storage_prefix_sets)
+            .root()
     }
 
-    /// Return blocks logs bloom
-    pub fn block_logs_bloom(&self, block_number: BlockNumber) -> Option<Bloom> {
-        Some(logs_bloom(self.logs(block_number)?))
+    /// Return prefix sets of the changed accounts and of the changed storage of every account,
+    /// keyed by hashed address.
+    ///
+    /// These are the prefix sets [Self::state_root_slow] passes to [StateRoot], they are computed
+    /// once from [Self::hash_state_slow] and cached until the bundle changes.
+    pub fn touched_prefix_sets(&self) -> (PrefixSet, HashMap<H256, PrefixSet>) {
+        let cache = self.hashed_state_cache();
+        (cache.account_prefix_set.clone(), cache.storage_prefix_sets.clone())
     }
 
-    /// Returns the receipt root for all recorded receipts.
-    /// Note: this function calculated Bloom filters for every receipt and created merkle trees
-    /// of receipt. This is a expensive operation.
-    pub fn receipts_root_slow(&self, block_number: BlockNumber) -> Option<H256> {
-        self.receipts.root_slow(self.block_number_to_index(block_number)?)
-    }
+    /// Return prefix sets of the accounts and storage slots changed in the given block, None if
+    /// the block is not in the bundle.
+    ///
+    /// Touched keys are taken from the reverts of the block, so only the changes of that block are
+    /// included, unlike [Self::touched_prefix_sets]. Slots removed by a storage wipe are not known
+    /// to the bundle and are not included.
+    pub fn block_prefix_sets(
+        &self,
+        block_number: BlockNumber,
+    ) -> Option<(PrefixSet, HashMap<H256, PrefixSet>)> {
+        let block_reverts = self.bundle.reverts.get(self.block_number_to_index(block_number)?)?;
 
-    /// Return reference to receipts.
-    pub fn receipts(&self) -> &Receipts {
-        &self.receipts
+        let mut account_prefix_set = PrefixSetMut::default();
+        let mut storage_prefix_sets: HashMap<H256, PrefixSetMut> = HashMap::new();
+        for (address, revert) in block_reverts.iter() {
+            let hashed_address = keccak256(address);
+            account_prefix_set.insert(Nibbles::unpack(hashed_address));
+            if revert.storage.is_empty() {
+                continue
+            }
+            let storage_prefix_set = storage_prefix_sets.entry(hashed_address).or_default();
+            for slot in revert.storage.keys() {
+                storage_prefix_set.insert(Nibbles::unpack(keccak256(H256(slot.to_be_bytes()))));
+            }
+        }
+        Some((
+            account_prefix_set.freeze(),
+            storage_prefix_sets.into_iter().map(|(address, set)| (address, set.freeze())).collect(),
+        ))
     }
 
-    /// Return all block receipts
-    pub fn receipts_by_block(&self, block_number: BlockNumber) -> &[Option<Receipt>] {
-        let Some(index) = self.block_number_to_index(block_number) else { return &[] };
-        &self.receipts[index]
+    /// Return the hashed state with its prefix sets, computing them on first use.
+    fn hashed_state_cache(&self) -> &HashedStateCache {
+        self.hashed_state.get_or_init(|| {
+            let hashed_state = self.hash_state_slow();
+            let (account_prefix_set, storage_prefix_sets) = hashed_state.construct_prefix_sets();
+            HashedStateCache {
+                hashed_state,
+                account_prefix_set,
+                storage_prefix_sets,
+                storage_roots: Mutex::default(),
+            }
+        })
+    }
+
+    /// Calculate the storage roots of the accounts with changed storage, keyed by hashed address,
+    /// on up to `num_threads` threads.
+    fn changed_storage_roots<'a, 'tx, TX: DbTx<'tx>>(
+        tx: &'a TX,
+        hashed_post_state: &HashedPostState,
+        storage_prefix_sets: &HashMap<H256, PrefixSet>,
+        num_threads: usize,
+    ) -> Result<HashMap<H256, H256>, StorageRootError> {
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, hashed_post_state);
+        let changed_storages = storage_prefix_sets.iter().collect::<Vec<_>>();
+        map_on_threads(&changed_storages, num_threads, |(hashed_address, prefix_set)| {
+            let storage_root =
+                StorageRoot::new_hashed_with_factory(tx, &hashed_cursor_factory, **hashed_address)
+                    .with_changed_prefixes((*prefix_set).clone())
+                    .root()?;
+            Ok((**hashed_address, storage_root))
+        })
+        .into_iter()
+        .collect()
+    }
+
+    /// Calculate the state root for this [BundleState] on up to `num_threads` threads.
+    ///
+    /// Accounts and storage are hashed and the storage tries of the accounts with changed storage
+    /// are walked concurrently, sharing `tx` between the threads. The account trie is then walked
+    /// on the calling thread with the computed storage roots, so branch nodes are merged in key
+    /// order regardless of the order in which the threads finish, and the root is the same as the
+    /// one of [Self::state_root_slow].
+    pub fn state_root_slow_parallel<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        num_threads: usize,
+    ) -> Result<H256, StateRootError> {
+        let accounts = self.bundle.state().iter().collect::<Vec<_>>();
+        let hashed_accounts = map_on_threads(&accounts, num_threads, |(address, account)| {
+            hash_account(address, account)
+        });
+        let hashed_state = Self::hashed_post_state(hashed_accounts);
+        let (account_prefix_set, storage_prefix_sets) = hashed_state.construct_prefix_sets();
+        let storage_roots =
+            Self::changed_storage_roots(tx, &hashed_state, &storage_prefix_sets, num_threads)?;
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, &hashed_state);
+        StateRoot::new(tx)
+            .with_hashed_cursor_factory(&hashed_cursor_factory)
+            .with_changed_account_prefixes(account_prefix_set)
+            .with_changed_storage_prefixes(storage_prefix_sets)
+            .with_storage_root_overrides(storage_roots)
+            .root()
+    }
+
+    /// Calculate the state root for this [BundleState] applied on top of `prev`.
+    ///
+    /// Only the changes of this bundle are hashed, they are merged into a copy of `prev` with this
+    /// bundle taking precedence for accounts and storage slots touched by both. Prefix sets are
+    /// constructed from the merged state, since changes of `prev` are not in the database trie
+    /// either.
+    pub fn state_root_incremental<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        prev: &HashedPostState,
+    ) -> Result<H256, StateRootError> {
+        let mut hashed_post_state = prev.clone();
+        hashed_post_state.extend(self.hash_state_slow());
+        hashed_post_state.sort();
+        Self::state_root_with_hashed_state(tx, &hashed_post_state)
+    }
+
+    /// Calculate the state root with only the changes of the given accounts applied.
+    ///
+    /// The post state and prefix sets are restricted to the hashed keys of `accounts`, changes of
+    /// other accounts in the bundle are ignored and addresses that are not in the bundle are
+    /// skipped. The result is not the state root of the bundle, it is only meaningful relative to
+    /// the known state root of the database the changes are applied to.
+    pub fn partial_state_root<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        accounts: &[Address],
+    ) -> Result<H256, StateRootError> {
+        let hashed_post_state = Self::hashed_post_state(
+            accounts
+                .iter()
+                .filter_map(|address| Some(hash_account(address, self.bundle.account(address)?))),
+        );
+        Self::state_root_with_hashed_state(tx, &hashed_post_state)
+    }
+
+    /// Calculate the state root with the account changes of the bundle applied and the storage of
+    /// every account taken from the database.
+    ///
+    /// Storage changes and storage wipes of the bundle are ignored, storage roots come from the
+    /// existing storage tries. The result is the state root of the bundle only if the bundle has
+    /// no storage changes and no destroyed accounts that are recreated with storage.
+    pub fn account_trie_root_slow<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+    ) -> Result<H256, StateRootError> {
+        let mut hashed_post_state = HashedPostState::default();
+        for (address, account) in self.bundle.state() {
+            let hashed_address = keccak256(address);
+            match account.info.clone().map(into_reth_acc) {
+                Some(account) => hashed_post_state.insert_account(hashed_address, account),
+                None => hashed_post_state.insert_cleared_account(hashed_address),
+            }
+        }
+        Self::state_root_with_hashed_state(tx, &hashed_post_state.sorted())
+    }
+
+    /// Calculate the state root for this bundle with the given storage roots used instead of the
+    /// ones derived from the bundle and the database.
+    ///
+    /// This is a debugging tool to tell account trie issues from storage trie issues. Overridden
+    /// accounts are always re-hashed into the account trie, even if the bundle does not change
+    /// them. Overrides of accounts that don't exist after the bundle are ignored.
+    pub fn state_root_slow_with_overrides<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        overrides: HashMap<Address, H256>,
+    ) -> Result<H256, StateRootError> {
+        let cache = self.hashed_state_cache();
+        let overrides = overrides
+            .into_iter()
+            .map(|(address, storage_root)| (keccak256(address), storage_root))
+            .collect::<HashMap<_, _>>();
+
+        let mut account_prefix_set = PrefixSetMut::default();
+        for hashed_address in
+            self.bundle.state.keys().map(keccak256).chain(overrides.keys().copied())
+        {
+            account_prefix_set.insert(Nibbles::unpack(hashed_address));
+        }
+
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, &cache.hashed_state);
+        StateRoot::new(tx)
+            .with_hashed_cursor_factory(&hashed_cursor_factory)
+            .with_changed_account_prefixes(account_prefix_set.freeze())
+            .with_changed_storage_prefixes(cache.storage_prefix_sets.clone())
+            .with_storage_root_overrides(overrides)
+            .root()
+    }
+
+    /// Calculate the state root for the given sorted [HashedPostState].
+    fn state_root_with_hashed_state<'a, 'tx, TX: DbTx<'tx>>(
+        tx: &'a TX,
+        hashed_post_state: &HashedPostState,
+    ) -> Result<H256, StateRootError> {
+        let (account_prefix_set, storage_prefix_set) = hashed_post_state.construct_prefix_sets();
+        Self::state_root_with_prefix_sets(
+            tx,
+            hashed_post_state,
+            account_prefix_set,
+            storage_prefix_set,
+        )
+    }
+
+    /// Calculate the state root for the given sorted [HashedPostState] and its prefix sets.
+    fn state_root_with_prefix_sets<'a, 'tx, TX: DbTx<'tx>>(
+        tx: &'a TX,
+        hashed_post_state: &HashedPostState,
+        account_prefix_set: PrefixSet,
+        storage_prefix_set: HashMap<H256, PrefixSet>,
+    ) -> Result<H256, StateRootError> {
+        let hashed_cursor_factory = HashedPostStateCursorFactory::new(tx, hashed_post_state);
+        StateRoot::new(tx)
+            .with_hashed_cursor_factory(&hashed_cursor_factory)
+            .with_changed_account_prefixes(account_prefix_set)
+            .with_changed_storage_prefixes(storage_prefix_set)
+            .root()
+    }
+
+    /// Check whether both bundles produce the same state root on top of the database.
+    ///
+    /// Unlike [PartialEq], receipts and reverts are not compared.
+    pub fn same_state_root<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        other: &Self,
+        tx: &'a TX,
+    ) -> Result<bool, StateRootError> {
+        Ok(self.state_root_slow(tx)? == other.state_root_slow(tx)?)
+    }
+
+    /// Compare present accounts and storage slots against [tables::PlainAccountState] and
+    /// [tables::PlainStorageState] and count the real changes and no-ops.
+    ///
+    /// Only values present in the bundle are compared, database slots removed by a storage wipe
+    /// are not counted.
+    pub fn effective_diff<'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &TX,
+    ) -> Result<EffectiveDiff, DatabaseError> {
+        let mut accounts_cursor = tx.cursor_read::<tables::PlainAccountState>()?;
+        let mut storages_cursor = tx.cursor_dup_read::<tables::PlainStorageState>()?;
+
+        let mut diff = EffectiveDiff::default();
+        for (address, account) in self.bundle.state() {
+            let present = account.info.clone().map(into_reth_acc);
+            let db_account = accounts_cursor.seek_exact(*address)?.map(|(_, account)| account);
+            if present == db_account {
+                diff.unchanged_accounts += 1;
+            } else {
+                diff.changed_accounts += 1;
+            }
+
+            for (key, slot) in account.storage.iter() {
+                let key = H256(key.to_be_bytes());
+                let db_value = storages_cursor
+                    .seek_by_key_subkey(*address, key)?
+                    .filter(|entry| entry.key == key)
+                    .map(|entry| entry.value)
+                    .unwrap_or_default();
+                if slot.present_value == db_value {
+                    diff.unchanged_storage += 1;
+                } else {
+                    diff.changed_storage += 1;
+                }
+            }
+        }
+        Ok(diff)
+    }
+
+    /// Check that the bytecode of every present account with code is either in the bundle or
+    /// in [tables::Bytecodes].
+    ///
+    /// [Self::write_to_db] does not check it, so this can be run before writing the bundle to
+    /// avoid persisting accounts that reference missing bytecode. Accounts are checked in address
+    /// order and the first missing bytecode is returned.
+    pub fn verify_bytecode_references<'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &TX,
+    ) -> Result<(), MissingBytecode> {
+        for (address, account) in self.sorted_accounts() {
+            let Some(code_hash) = account.and_then(|account| account.bytecode_hash) else {
+                continue
+            };
+            if self.bundle.contracts.contains_key(&code_hash) {
+                continue
+            }
+            if tx.get::<tables::Bytecodes>(code_hash)?.is_none() {
+                return Err(MissingBytecode::NotFound { address, code_hash })
+            }
+        }
+        Ok(())
+    }
+
+    /// Return size of the reverts of every block in the bundle, ordered by block number.
+    ///
+    /// Counts the reverts as they are passed to [StateReverts::write_to_db]. Reverts of the
+    /// bundle are cloned to compute them, so this is meant for diagnostics.
+    pub fn reverts_report(&self) -> Vec<BlockRevertStats> {
+        let reverts = self.bundle.reverts.clone().into_plain_state_reverts();
+        reverts
+            .accounts
+            .iter()
+            .zip(reverts.storage.iter())
+            .enumerate()
+            .map(|(index, (accounts, storage))| BlockRevertStats {
+                block_number: self.first_block + index as BlockNumber,
+                account_reverts: accounts.len(),
+                storage_reverts: storage.iter().map(|revert| revert.storage_revert.len()).sum(),
+                wiped_accounts: storage.iter().filter(|revert| revert.wiped).count(),
+            })
+            .collect()
+    }
+
+    /// Return block numbers of the blocks with account reverts, in ascending order.
+    ///
+    /// Other blocks add no rows to [tables::AccountChangeSet]. Computed from
+    /// [Self::reverts_report].
+    pub fn blocks_with_account_reverts(&self) -> impl Iterator<Item = BlockNumber> {
+        self.reverts_report()
+            .into_iter()
+            .filter(|block| block.account_reverts != 0)
+            .map(|block| block.block_number)
+    }
+
+    /// Return block numbers of the blocks with storage reverts or wiped storage, in ascending
+    /// order.
+    ///
+    /// Other blocks add no rows to [tables::StorageChangeSet]. Computed from
+    /// [Self::reverts_report].
+    pub fn blocks_with_storage_reverts(&self) -> impl Iterator<Item = BlockNumber> {
+        self.reverts_report()
+            .into_iter()
+            .filter(|block| block.storage_reverts != 0 || block.wiped_accounts != 0)
+            .map(|block| block.block_number)
+    }
+
+    /// Return a geth style prestate diff of the block, `{"pre": {..}, "post": {..}}`, for comparing
+    /// the state changes with other clients.
+    ///
+    /// Only accounts with a revert in the block are included, with their balance, nonce, code and
+    /// the storage slots changed in the block. Zero balance and nonce, empty code and storage are
+    /// omitted like in geth. An account that did not exist before the block is left out of `pre`
+    /// and an account destroyed in the block is left out of `post`.
+    ///
+    /// Values before the block are taken from the reverts of the block, values after the block
+    /// from the reverts of the next block that changed them or the present values.
+    ///
+    /// Returns [serde_json::Value::Null] if the block has no reverts in the bundle.
+    pub fn to_state_diff_json(&self, block_number: BlockNumber) -> serde_json::Value {
+        let Some(index) = self.block_number_to_index(block_number) else {
+            return serde_json::Value::Null
+        };
+        let Some(block_reverts) = self.bundle.reverts.get(index) else {
+            return serde_json::Value::Null
+        };
+        let later_reverts = &self.bundle.reverts[index + 1..];
+
+        let mut pre = BTreeMap::new();
+        let mut post = BTreeMap::new();
+        for (address, revert) in block_reverts {
+            let later = later_reverts
+                .iter()
+                .flatten()
+                .filter(|(later_address, _)| later_address == address)
+                .map(|(_, later)| later);
+
+            let post_info = later
+                .clone()
+                .find_map(|later| match &later.account {
+                    AccountInfoRevert::DoNothing => None,
+                    AccountInfoRevert::DeleteIt => Some(None),
+                    AccountInfoRevert::RevertTo(info) => Some(Some(info.clone())),
+                })
+                .unwrap_or_else(|| self.bundle.account(address).and_then(|a| a.info.clone()));
+            let pre_info = match &revert.account {
+                AccountInfoRevert::DoNothing => post_info.clone(),
+                AccountInfoRevert::DeleteIt => None,
+                AccountInfoRevert::RevertTo(info) => Some(info.clone()),
+            };
+
+            let mut pre_storage = BTreeMap::new();
+            let mut post_storage = BTreeMap::new();
+            for (key, slot) in &revert.storage {
+                let post_value = later
+                    .clone()
+                    .find_map(|later| later.storage.get(key))
+                    .map(|later| later.to_previous_value())
+                    .unwrap_or_else(|| self.storage(address, *key).unwrap_or_default());
+                let key = H256(key.to_be_bytes());
+                pre_storage.insert(key, H256(slot.to_previous_value().to_be_bytes()));
+                post_storage.insert(key, H256(post_value.to_be_bytes()));
+            }
+
+            if let Some(info) = pre_info {
+                pre.insert(*address, self.state_diff_account(&info, pre_storage));
+            }
+            if let Some(info) = post_info {
+                post.insert(*address, self.state_diff_account(&info, post_storage));
+            }
+        }
+        serde_json::json!({ "pre": pre, "post": post })
+    }
+
+    /// Geth style prestate of an account, see [Self::to_state_diff_json].
+    fn state_diff_account(
+        &self,
+        info: &AccountInfo,
+        storage: BTreeMap<H256, H256>,
+    ) -> serde_json::Value {
+        let mut account = serde_json::Map::new();
+        if info.balance != U256::ZERO {
+            account.insert("balance".to_string(), serde_json::json!(info.balance));
+        }
+        if info.nonce != 0 {
+            account.insert("nonce".to_string(), info.nonce.into());
+        }
+        let code = info.code.clone().or_else(|| self.bundle.bytecode(&info.code_hash));
+        let code = code.map(|code| Bytes::from(code.original_bytes())).unwrap_or_default();
+        if !code.is_empty() {
+            account.insert("code".to_string(), serde_json::json!(code));
+        }
+        if !storage.is_empty() {
+            account.insert("storage".to_string(), serde_json::json!(storage));
+        }
+        account.into()
+    }
+
+    /// Return the number of rows [Self::write_to_db] adds to [tables::AccountChangeSet] and
+    /// [tables::StorageChangeSet].
+    ///
+    /// Wiped storage is read from the plain state on write, so the storage row count is only a
+    /// lower bound for bundles with wiped storage. Use [StateReverts::wiped_storage_counts] for
+    /// the number of wiped slots in the database.
+    pub fn changeset_row_counts(&self) -> (usize, usize) {
+        self.reverts_report().iter().fold((0, 0), |(accounts, storage), block| {
+            (accounts + block.account_reverts, storage + block.storage_reverts)
+        })
+    }
+
+    /// Number of blocks with reverts in the bundle.
+    ///
+    /// Every block of a bundle built from execution has a revert, so this is equal to
+    /// [Self::len], see [Self::check_block_alignment].
+    pub fn reverts_len(&self) -> usize {
+        self.bundle.reverts.len()
+    }
+
+    /// Return false if a lower part of the bundle was detached, for example with
+    /// [Self::split_at], so the original values of the bundle are the values before the detached
+    /// part and not the values in the database the bundle is written on top of.
+    ///
+    /// Present values are always complete. See [Self::rebuild_plain_state_from_reverts] to make
+    /// the original values whole again.
+    pub fn has_complete_plain_state(&self) -> bool {
+        !self.detached
+    }
+
+    /// Return the [OriginalValuesKnown] to pass to [Self::write_to_db] for this bundle, see
+    /// [Self::has_complete_plain_state].
+    pub fn original_values_known(&self) -> OriginalValuesKnown {
+        if self.has_complete_plain_state() {
+            OriginalValuesKnown::Yes
+        } else {
+            OriginalValuesKnown::No
+        }
+    }
+
+    /// Return true if any block of the bundle has account or storage reverts.
+    ///
+    /// Blocks with empty reverts can't be reverted, for example the blocks whose reverts were
+    /// detached with [Self::detach_lower_reverts_keep_receipts].
+    pub fn has_reverts(&self) -> bool {
+        self.bundle.reverts.iter().any(|block_reverts| !block_reverts.is_empty())
+    }
+
+    /// Check that the bundle has one revert for every block of receipts.
+    ///
+    /// Reverts are written to the changesets of blocks by their position relative to the first
+    /// block, so misaligned reverts would be written to wrong blocks. Bundles without any
+    /// receipts recorded, like the genesis state, are the only exception.
+    pub fn check_block_alignment(&self) -> Result<(), AlignmentError> {
+        let (receipts, reverts) = (self.len(), self.reverts_len());
+        if receipts != 0 && receipts != reverts {
+            return Err(AlignmentError { receipts, reverts })
+        }
+        Ok(())
+    }
+
+    /// Transform block number to the index of block.
+    fn block_number_to_index(&self, block_number: BlockNumber) -> Option<usize> {
+        if self.first_block > block_number {
+            return None
+        }
+        let index = block_number - self.first_block;
+        if index >= self.receipts.len() as u64 {
+            return None
+        }
+        Some(index as usize)
+    }
+
+    /// Returns an iterator over all block logs.
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub fn logs(&self, block_number: BlockNumber) -> Option<impl Iterator<Item = &Log>> {
+        self.block_logs(block_number)
+    }
+
+    /// Return all block logs, decompressed with [Self::receipts_by_block].
+    ///
+    /// Returns None if the block is not in the bundle, or the error of the first receipt of the
+    /// block that fails to decode.
+    #[cfg(feature = "compressed-receipts")]
+    pub fn logs(&self, block_number: BlockNumber) -> Result<Option<Vec<Log>>, DatabaseError> {
+        if self.block_number_to_index(block_number).is_none() {
+            return Ok(None)
+        }
+        let receipts = self.receipts_by_block(block_number)?;
+        Ok(Some(receipts.into_iter().flatten().flat_map(|receipt| receipt.logs).collect()))
+    }
+
+    /// Returns an iterator over all block logs, borrowed from the receipts of the bundle.
+    fn block_logs(&self, block_number: BlockNumber) -> Option<impl Iterator<Item = &Log>> {
+        let index = self.block_number_to_index(block_number)?;
+        let receipts = self.receipts.block(index)?;
+        Some(receipts.iter().filter_map(|r| Some(r.as_ref()?.logs.iter())).flatten())
+    }
+
+    /// Returns an iterator over all block logs paired with their index in the block.
+    ///
+    /// Logs of pruned receipts are skipped like in [Self::logs] and are not counted, so the index
+    /// matches the log index of the block only if none of its receipts are pruned.
+    pub fn logs_with_index(
+        &self,
+        block_number: BlockNumber,
+    ) -> Option<impl Iterator<Item = (usize, &Log)>> {
+        Some(self.block_logs(block_number)?.enumerate())
+    }
+
+    /// Return number of logs in the block, pruned receipts are skipped like in [Self::logs].
+    pub fn block_log_count(&self, block_number: BlockNumber) -> Option<usize> {
+        let index = self.block_number_to_index(block_number)?;
+        let receipts = self.receipts.block(index)?;
+        Some(receipts.iter().flatten().map(|receipt| receipt.logs.len()).sum())
+    }
+
+    /// Returns an iterator over the receipts of the block with the bloom of their logs, pruned
+    /// receipts are skipped.
+    pub fn receipts_with_blooms(
+        &self,
+        block_number: BlockNumber,
+    ) -> Option<impl Iterator<Item = (&Receipt, Bloom)>> {
+        let index = self.block_number_to_index(block_number)?;
+        let receipts = self.receipts.block(index)?;
+        Some(receipts.iter().flatten().map(|receipt| (receipt, logs_bloom(&receipt.logs))))
+    }
+
+    /// Return blocks logs bloom
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub fn block_logs_bloom(&self, block_number: BlockNumber) -> Option<Bloom> {
+        Some(logs_bloom(self.logs(block_number)?))
+    }
+
+    /// Return blocks logs bloom, computed from the logs of [Self::logs].
+    ///
+    /// Returns None if the block is not in the bundle or any of its receipts fails to decode, see
+    /// [Self::logs] for the error.
+    #[cfg(feature = "compressed-receipts")]
+    pub fn block_logs_bloom(&self, block_number: BlockNumber) -> Option<Bloom> {
+        Some(logs_bloom(&self.logs(block_number).ok()??))
+    }
+
+    /// Return blocks logs bloom, rejecting blocks with more than `max_logs` logs.
+    ///
+    /// Logs are counted before any of them is hashed, so oversized blocks are rejected cheaply.
+    /// Without `max_logs` this is the same as [Self::block_logs_bloom].
+    pub fn try_block_logs_bloom(
+        &self,
+        block_number: BlockNumber,
+        max_logs: Option<usize>,
+    ) -> Result<Option<Bloom>, TooManyLogs> {
+        let Some(logs) = self.block_log_count(block_number) else { return Ok(None) };
+        if let Some(max_logs) = max_logs {
+            if logs > max_logs {
+                return Err(TooManyLogs { block_number, logs, max_logs })
+            }
+        }
+        Ok(self.block_logs_bloom(block_number))
+    }
+
+    /// Check that `blooms` match the logs of the receipts of the block.
+    ///
+    /// Receipts of the bundle do not store a bloom, so the blooms that came with imported
+    /// receipts, for example [reth_primitives::ReceiptWithBloom::bloom], are passed in the order
+    /// of the receipts. The bloom of every receipt is recomputed from its logs and the first
+    /// receipt whose bloom differs is reported. Pruned receipts are skipped, a missing or extra
+    /// bloom is reported as a mismatch at its index. Blocks that are not in the bundle have no
+    /// receipts.
+    pub fn verify_receipt_blooms(
+        &self,
+        block_number: BlockNumber,
+        blooms: &[Bloom],
+    ) -> Result<(), BloomMismatch> {
+        let receipts = self.block_receipts(block_number);
+        for tx_index in 0..receipts.len().max(blooms.len()) {
+            let matches = match (receipts.get(tx_index), blooms.get(tx_index)) {
+                (Some(Some(receipt)), Some(bloom)) => receipt.bloom_slow() == *bloom,
+                (Some(None), Some(_)) => true,
+                _ => false,
+            };
+            if !matches {
+                return Err(BloomMismatch { block_number, tx_index })
+            }
+        }
+        Ok(())
+    }
+
+    /// Return logs bloom of all blocks in the bundle.
+    ///
+    /// If the aggregate bloom does not match a log, none of the block blooms do.
+    pub fn aggregate_logs_bloom(&self) -> Bloom {
+        (self.first_block..self.first_block + self.len() as BlockNumber)
+            .filter_map(|block_number| self.block_logs_bloom(block_number))
+            .fold(Bloom::zero(), |mut bloom, block_bloom| {
+                bloom.accrue_bloom(&block_bloom);
+                bloom
+            })
+    }
+
+    /// Return logs bloom of the blocks in the range, see [Self::aggregate_logs_bloom].
+    ///
+    /// Blocks of the range outside of the bundle are skipped.
+    pub fn range_logs_bloom(&self, range: RangeInclusive<BlockNumber>) -> Bloom {
+        logs_bloom(
+            self.receipts_by_range(range)
+                .flat_map(|(_, receipts)| receipts.iter().flatten())
+                .flat_map(|receipt| receipt.logs.iter()),
+        )
+    }
+
+    /// Return gas used by the block, taken from the cumulative gas of its last receipt.
+    ///
+    /// Returns None if the block is not in the bundle or its last receipt is pruned.
+    ///
+    /// Note: receipts do not record blob gas, see [Self::block_blob_gas_used].
+    pub fn block_gas_used(&self, block_number: BlockNumber) -> Option<u64> {
+        let receipts = self.receipts.block(self.block_number_to_index(block_number)?)?;
+        match receipts.last() {
+            Some(receipt) => receipt.as_ref().map(|receipt| receipt.cumulative_gas_used),
+            None => Some(0),
+        }
+    }
+
+    /// Return blob gas used by the block, for example to validate the `blob_gas_used` of its
+    /// header.
+    ///
+    /// Receipts do not record blob gas, so it is only known if it was recorded with
+    /// [Self::set_block_blob_gas_used], from the transactions of the block, see
+    /// [reth_primitives::SealedBlock::blob_gas_used]. Returns None if the block is not in the
+    /// bundle or its blob gas used was not recorded, like for blocks before Cancun.
+    pub fn block_blob_gas_used(&self, block_number: BlockNumber) -> Option<u64> {
+        self.receipts.blob_gas_used(self.block_number_to_index(block_number)?)
+    }
+
+    /// Record blob gas used by the block, see [Self::block_blob_gas_used].
+    pub fn set_block_blob_gas_used(
+        &mut self,
+        block_number: BlockNumber,
+        blob_gas_used: u64,
+    ) -> Result<(), OutOfRange> {
+        let index = self.block_number_to_index(block_number).ok_or(OutOfRange { block_number })?;
+        self.receipts.set_blob_gas_used(index, blob_gas_used);
+        Ok(())
+    }
+
+    /// Return gas used by all blocks of the bundle, see [Self::block_gas_used].
+    ///
+    /// Blocks without receipts and blocks whose last receipt is pruned count as zero.
+    pub fn total_gas_used(&self) -> u64 {
+        self.receipts
+            .iter_blocks()
+            .filter_map(|receipts| receipts.last()?.as_ref())
+            .map(|receipt| receipt.cumulative_gas_used)
+            .sum()
+    }
+
+    /// Returns the receipt root for all recorded receipts.
+    /// Note: this function calculated Bloom filters for every receipt and created merkle trees
+    /// of receipt. This is a expensive operation.
+    pub fn receipts_root_slow(&self, block_number: BlockNumber) -> Option<H256> {
+        self.receipts.root_slow(self.block_number_to_index(block_number)?)
+    }
+
+    /// Return the state root and the receipts root for a block, for example to fill in the header
+    /// of a built block.
+    ///
+    /// Note: the state root is computed with [Self::state_root_slow] over the whole bundle, so
+    /// it is the state root after the block only if `block_number` is the last block of the
+    /// bundle. The receipts root is computed with [Self::receipts_root_slow] for the given block
+    /// only.
+    ///
+    /// Returns None if the block is not in the bundle or any of its receipts is pruned, in which
+    /// case the state root is not computed.
+    pub fn roots_for_block<'a, 'tx, TX: DbTx<'tx>>(
+        &self,
+        tx: &'a TX,
+        block_number: BlockNumber,
+    ) -> Result<Option<(H256, H256)>, StateRootError> {
+        let Some(receipts_root) = self.receipts_root_slow(block_number) else { return Ok(None) };
+        Ok(Some((self.state_root_slow(tx)?, receipts_root)))
+    }
+
+    /// Mutate every receipt of the bundle in place, pruned receipts are skipped.
+    ///
+    /// Can be used to drop data that should not be written by [Self::write_to_db], for example
+    /// clearing the logs of receipts. Block structure and receipt indices are kept.
+    ///
+    /// Note: [Self::block_logs_bloom], [Self::aggregate_logs_bloom] and
+    /// [Self::receipts_root_slow] are computed from the mutated receipts, so clearing logs
+    /// invalidates them.
+    pub fn map_receipts(&mut self, f: impl FnMut(&mut Receipt)) {
+        self.receipts.map_receipts(f);
+    }
+
+    /// Replace receipts of the block, for example to repair receipts that were computed
+    /// incorrectly.
+    ///
+    /// Blooms and receipt roots are not cached, [Self::block_logs_bloom] and
+    /// [Self::receipts_root_slow] return values for the new receipts. State is not changed.
+    pub fn set_block_receipts(
+        &mut self,
+        block_number: BlockNumber,
+        receipts: Vec<Receipt>,
+    ) -> Result<(), OutOfRange> {
+        let index = self.block_number_to_index(block_number).ok_or(OutOfRange { block_number })?;
+        self.receipts.set_block(index, receipts.into_iter().map(Some).collect());
+        Ok(())
+    }
+
+    /// Return the number of bytes of the receipt values [Self::write_to_db] writes to
+    /// [tables::Receipts].
+    ///
+    /// Receipts are encoded the same way as in the table, pruned receipts are not written and not
+    /// counted. Keys and database page overhead are not included.
+    pub fn receipts_encoded_size(&self) -> usize {
+        self.receipts
+            .iter_blocks()
+            .flatten()
+            .flatten()
+            .map(|receipt| receipt.clone().compress().as_ref().len())
+            .sum()
+    }
+
+    /// Return reference to receipts.
+    ///
+    /// Receipts are stored in a flat layout, the nested [Receipts] are built on the first call
+    /// after the receipts change. Use [Self::receipts_view] to read receipts without copying them.
+    pub fn receipts(&self) -> &Receipts {
+        self.receipts.nested()
+    }
+
+    /// Return read-only view of the receipts.
+    pub fn receipts_view(&self) -> BundleReceipts<'_> {
+        BundleReceipts::new(&self.receipts, self.first_block)
+    }
+
+    /// Return all block receipts
+    #[cfg(not(feature = "compressed-receipts"))]
+    pub fn receipts_by_block(&self, block_number: BlockNumber) -> &[Option<Receipt>] {
+        self.block_receipts(block_number)
+    }
+
+    /// Return all block receipts, decompressed from the compressed receipts of the block.
+    ///
+    /// Only the receipts of the block are decompressed and they are not cached. Returns no
+    /// receipts if the block is not in the bundle, or the error of the first receipt that fails to
+    /// decode.
+    #[cfg(feature = "compressed-receipts")]
+    pub fn receipts_by_block(
+        &self,
+        block_number: BlockNumber,
+    ) -> Result<Vec<Option<Receipt>>, DatabaseError> {
+        let Some(index) = self.block_number_to_index(block_number) else { return Ok(Vec::new()) };
+        self.receipts.decompress_block(index).unwrap_or_else(|| Ok(Vec::new()))
+    }
+
+    /// Return number of bytes of the compressed receipts of all blocks.
+    #[cfg(feature = "compressed-receipts")]
+    pub fn compressed_receipts_size(&self) -> usize {
+        self.receipts.compressed_size()
+    }
+
+    /// Return all block receipts, borrowed from the receipts of the bundle.
+    fn block_receipts(&self, block_number: BlockNumber) -> &[Option<Receipt>] {
+        let Some(index) = self.block_number_to_index(block_number) else { return &[] };
+        self.receipts.block(index).unwrap_or_default()
+    }
+
+    /// Compare receipts with the receipts of `other` block by block.
+    ///
+    /// Returns the first receipt that differs, a receipt that is missing or pruned in only one of
+    /// the bundles differs as well. Blocks outside of a bundle have no receipts.
+    pub fn receipts_equal(&self, other: &Self) -> Result<(), ReceiptMismatch> {
+        let first_block = self.first_block.min(other.first_block);
+        let end_block = (self.first_block + self.len() as BlockNumber)
+            .max(other.first_block + other.len() as BlockNumber);
+        for block_number in first_block..end_block {
+            let (left, right) =
+                (self.block_receipts(block_number), other.block_receipts(block_number));
+            for tx_index in 0..left.len().max(right.len()) {
+                let (left, right) = (left.get(tx_index), right.get(tx_index));
+                if left == right {
+                    continue
+                }
+                let (left, right) = (left.cloned().flatten(), right.cloned().flatten());
+                let fields = match (&left, &right) {
+                    (Some(left), Some(right)) => receipt_diff(left, right),
+                    _ => Vec::new(),
+                };
+                return Err(ReceiptMismatch { block_number, tx_index, left, right, fields })
+            }
+        }
+        Ok(())
+    }
+
+    /// Return receipt of the transaction at `tx_index` in the block.
+    ///
+    /// Returns None if the block or transaction is not in the bundle, or if the receipt is pruned.
+    pub fn receipt(&self, block_number: BlockNumber, tx_index: usize) -> Option<&Receipt> {
+        self.block_receipts(block_number).get(tx_index)?.as_ref()
+    }
+
+    /// Return owned receipts of the block, for example to serve them over RPC.
+    ///
+    /// Receipts are ordered by transaction index, the same order as the transactions of the
+    /// block body and the receipts root. Returns None if the block is not in the bundle or if any
+    /// of its receipts is pruned, as the receipts of the block would be incomplete.
+    pub fn block_receipts_owned(&self, block_number: BlockNumber) -> Option<Vec<Receipt>> {
+        let index = self.block_number_to_index(block_number)?;
+        self.receipts.block(index).unwrap_or_default().iter().cloned().collect()
+    }
+
+    /// Return receipts of all blocks, from the last block down to the first block.
+    pub fn receipts_rev(&self) -> impl Iterator<Item = (BlockNumber, &[Option<Receipt>])> + '_ {
+        self.receipts_view().iter_blocks().rev()
+    }
+
+    /// Return receipts of all blocks in the range that are part of the bundle.
+    ///
+    /// Blocks of the range outside of the bundle are skipped.
+    pub fn receipts_by_range(
+        &self,
+        range: RangeInclusive<BlockNumber>,
+    ) -> impl Iterator<Item = (BlockNumber, &[Option<Receipt>])> + '_ {
+        // Clamp the range to the bundle so a wide range does not walk blocks of other bundles.
+        let start = (*range.start()).max(self.first_block);
+        let end =
+            (*range.end()).min((self.first_block + self.len() as BlockNumber).saturating_sub(1));
+        (start..=end).filter_map(move |block_number| {
+            let index = self.block_number_to_index(block_number)?;
+            Some((block_number, self.receipts.block(index)?))
+        })
     }
 
     /// Is bundle state empty of blocks.
@@ -255,486 +1998,3002 @@ impl BundleStateWithReceipts {
         self.len() == 0
     }
 
-    /// Number of blocks in bundle state.
-    pub fn len(&self) -> usize {
-        self.receipts.len()
-    }
+    /// Number of blocks in bundle state.
+    pub fn len(&self) -> usize {
+        self.receipts.len()
+    }
+
+    /// Return first block of the bundle
+    pub fn first_block(&self) -> BlockNumber {
+        self.first_block
+    }
+
+    /// Return last block of the bundle.
+    pub fn last_block(&self) -> BlockNumber {
+        self.first_block + self.len() as BlockNumber
+    }
+
+    /// Revert to given block number.
+    ///
+    /// If number is in future, or in the past return false
+    ///
+    /// Note: Given Block number will stay inside the bundle state.
+    pub fn revert_to(&mut self, block_number: BlockNumber) -> bool {
+        self.try_revert_to(block_number).is_ok()
+    }
+
+    /// Revert to given block number and return the number of reverted blocks.
+    ///
+    /// Returns an error if the block is not in the bundle, the bundle is not modified in that case.
+    ///
+    /// Note: Given Block number will stay inside the bundle state.
+    pub fn try_revert_to(&mut self, block_number: BlockNumber) -> Result<usize, RevertError> {
+        let first_block = self.first_block;
+        let len = self.len();
+        self.revert_to_returning(block_number)
+            .map(|receipts| receipts.len())
+            .ok_or(RevertError::BlockNotInBundle { block_number, first_block, len })
+    }
+
+    /// Revert to given block number and return the removed receipts.
+    ///
+    /// Returned receipts are ordered by block, starting with the block after `block_number`.
+    /// If number is in future, or in the past return None.
+    ///
+    /// Note: Given Block number will stay inside the bundle state.
+    pub fn revert_to_returning(
+        &mut self,
+        block_number: BlockNumber,
+    ) -> Option<Vec<Vec<Option<Receipt>>>> {
+        let index = self.block_number_to_index(block_number)?;
+
+        // +1 is for number of blocks that we have as index is included.
+        let new_len = index + 1;
+        let rm_trx: usize = self.len() - new_len;
+
+        // remove receipts
+        let reverted_receipts = self.receipts.split_off(new_len);
+        // Revert last n reverts.
+        self.bundle.revert(rm_trx);
+        self.hashed_state.take();
+
+        Some(reverted_receipts)
+    }
+
+    /// This will detach lower part of the chain and return it back.
+    /// Specified block number will be included in detachment
+    ///
+    /// This plain state will contains some additional information that
+    /// are is a artifacts of the lower part state.
+    ///
+    /// If block number is in future, return None.
+    pub fn split_at(&mut self, block_number: BlockNumber) -> Option<Self> {
+        let last_block = self.last_block();
+        let first_block = self.first_block;
+        if block_number >= last_block {
+            return None
+        }
+        if block_number < first_block {
+            return Some(Self::default())
+        }
+
+        // detached number should be included so we are adding +1 to it.
+        // for example if block number is same as first_block then
+        // number of detached block shoud be 1.
+        let num_of_detached_block = (block_number - first_block) + 1;
+
+        let mut detached_bundle_state: BundleStateWithReceipts = self.clone();
+        detached_bundle_state.revert_to(block_number);
+
+        // split is done as [0, num) and [num, len]
+        self.receipts.remove_first(num_of_detached_block as usize);
+        self.bundle.take_n_reverts(num_of_detached_block as usize);
+
+        self.first_block = block_number + 1;
+        self.detached = true;
+
+        Some(detached_bundle_state)
+    }
+
+    /// Detach the upper part of the chain and return it back, keeping blocks up to and including
+    /// `block_number`.
+    ///
+    /// This is the counterpart of [Self::split_at]. The kept lower part is reverted to
+    /// `block_number`, its plain state is the state at that block. The returned upper part keeps
+    /// the plain state of the whole bundle and is marked as detached, as its original values are
+    /// the values before the lower part.
+    ///
+    /// Returns None if the block is not in the bundle or is its last block.
+    pub fn detach_upper_part_at(&mut self, block_number: BlockNumber) -> Option<Self> {
+        let index = self.block_number_to_index(block_number)?;
+        // Index is included in the lower part.
+        let num_of_lower_blocks = index + 1;
+        if num_of_lower_blocks == self.len() {
+            return None
+        }
+
+        let mut upper = self.clone();
+        upper.receipts.remove_first(num_of_lower_blocks);
+        upper.bundle.take_n_reverts(num_of_lower_blocks);
+        upper.first_block = block_number + 1;
+        upper.detached = true;
+
+        self.revert_to(block_number);
+
+        Some(upper)
+    }
+
+    /// Detach the reverts of blocks up to and including `block_number` and return them, keeping
+    /// the receipts of all blocks in this bundle.
+    ///
+    /// Unlike [Self::split_at], blocks are partitioned as follows:
+    /// - The returned part has the plain state at `block_number` and the reverts of the blocks
+    ///   from the first block up to `block_number`. It has no receipts, so its length is zero, but
+    ///   it starts at the same first block and its reverts are written to the changesets of the
+    ///   detached blocks.
+    /// - This bundle keeps its first block, plain state and the receipts of all blocks. Reverts
+    ///   of the detached blocks are replaced with empty reverts, so the remaining reverts stay at
+    ///   the index of their block and [Self::check_block_alignment] still holds.
+    ///
+    /// This bundle is marked as detached, as its original values are the values before the
+    /// detached blocks.
+    ///
+    /// Returns None if the block is not in the bundle.
+    pub fn detach_lower_reverts_keep_receipts(
+        &mut self,
+        block_number: BlockNumber,
+    ) -> Option<Self> {
+        let index = self.block_number_to_index(block_number)?;
+        // Index is included in the detached part.
+        let num_of_detached_blocks = index + 1;
+
+        let mut lower = self.clone();
+        lower.revert_to(block_number);
+        lower.receipts = FlatReceipts::default();
+
+        self.bundle.take_n_reverts(num_of_detached_blocks);
+        self.bundle
+            .reverts
+            .splice(0..0, std::iter::repeat_with(Vec::new).take(num_of_detached_blocks));
+        self.detached = true;
+
+        Some(lower)
+    }
+
+    /// Rebuild the original values of a detached bundle from its reverts, so it can be written
+    /// with [OriginalValuesKnown::Yes].
+    ///
+    /// Original values of a bundle whose lower part was detached are the values before the
+    /// detached part. They are replaced with the values before the first block of this bundle:
+    /// - Accounts and storage slots with reverts take the value from their oldest revert.
+    /// - Accounts and storage slots without reverts did not change in this bundle, their original
+    ///   value is the present value. They are skipped when writing, so the detached lower part
+    ///   must be written first.
+    ///
+    /// Present values, reverts and receipts are not changed. The account status can't be
+    /// recovered and is kept, so an account destroyed in the detached part still wipes its
+    /// storage when written. Storage of a destroyed account that is not in the bundle is not
+    /// known either way.
+    pub fn rebuild_plain_state_from_reverts(&mut self) {
+        let mut known_accounts = HashSet::new();
+        let mut known_slots = HashSet::new();
+        // Oldest revert of an account or slot holds its value before this bundle.
+        for (address, revert) in self.bundle.reverts.iter().flatten() {
+            let Some(account) = self.bundle.state.get_mut(address) else { continue };
+            if !known_accounts.contains(address) {
+                match &revert.account {
+                    AccountInfoRevert::DoNothing => (),
+                    AccountInfoRevert::DeleteIt => {
+                        account.original_info = None;
+                        known_accounts.insert(*address);
+                    }
+                    AccountInfoRevert::RevertTo(info) => {
+                        account.original_info = Some(info.clone());
+                        known_accounts.insert(*address);
+                    }
+                }
+            }
+            for (key, slot) in &revert.storage {
+                if known_slots.insert((*address, *key)) {
+                    if let Some(storage_slot) = account.storage.get_mut(key) {
+                        storage_slot.previous_or_original_value = slot.to_previous_value();
+                    }
+                }
+            }
+        }
+
+        for (address, account) in self.bundle.state.iter_mut() {
+            if !known_accounts.contains(address) {
+                account.original_info = account.info.clone();
+            }
+            for (key, slot) in account.storage.iter_mut() {
+                if !known_slots.contains(&(*address, *key)) {
+                    slot.previous_or_original_value = slot.present_value;
+                }
+            }
+        }
+        self.detached = false;
+    }
+
+    /// Detach the oldest blocks with at most `max_entries` revert entries in total and return
+    /// them, see [Self::split_at].
+    ///
+    /// Every account and storage revert entry of a block counts as one, the same units as
+    /// [Self::size_hint], the encoded size of the entries is not measured. Blocks are taken
+    /// greedily until the next block would exceed `max_entries`, at least one block is always
+    /// taken even if it alone exceeds it.
+    ///
+    /// Returns None if the bundle has no blocks.
+    pub fn take_first_blocks_under(&mut self, max_entries: usize) -> Option<Self> {
+        if self.is_empty() {
+            return None
+        }
+
+        let mut entries = 0;
+        let mut num_blocks = 0;
+        for index in 0..self.len() {
+            entries += self.bundle.reverts.get(index).map_or(0, |block_reverts| {
+                block_reverts.iter().map(|(_, revert)| 1 + revert.storage.len()).sum()
+            });
+            if num_blocks > 0 && entries > max_entries {
+                break
+            }
+            num_blocks += 1;
+        }
+
+        self.split_at(self.first_block + num_blocks as BlockNumber - 1)
+    }
+
+    /// Return true if the bundle has more than `max_depth` blocks.
+    ///
+    /// A bundle with exactly `max_depth` blocks is within the limit.
+    pub fn exceeds_depth(&self, max_depth: u64) -> bool {
+        self.len() as u64 > max_depth
+    }
+
+    /// Detach and discard the oldest blocks, keeping at most `max_depth` blocks, see
+    /// [Self::split_at].
+    ///
+    /// Returns the number of discarded blocks. If any block is discarded, the bundle is marked as
+    /// detached.
+    pub fn truncate_to_depth(&mut self, max_depth: u64) -> usize {
+        if !self.exceeds_depth(max_depth) {
+            return 0
+        }
+        let num_blocks = self.len() - max_depth as usize;
+        self.split_at(self.first_block + num_blocks as BlockNumber - 1);
+        num_blocks
+    }
+
+    /// Extend one state from another
+    ///
+    /// For state this is very sensitive opperation and should be used only when
+    /// we know that other state was build on top of this one.
+    /// In most cases this would be true.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if neither bundle is empty and `other` does not start at the block
+    /// following the last block of this bundle, for example when the same bundle is extended
+    /// twice.
+    pub fn extend(&mut self, other: Self) {
+        debug_assert!(
+            self.is_empty() || other.is_empty() || other.first_block == self.last_block(),
+            "extending bundle ending before block {} with bundle starting at block {}",
+            self.last_block(),
+            other.first_block
+        );
+        self.bundle.extend(other.bundle);
+        self.receipts.extend(other.receipts);
+        self.hashed_state.take();
+    }
+
+    /// Append the blocks of `other` above `shared_up_to`, where both bundles contain the same
+    /// blocks up to and including `shared_up_to`, for example two forks with a shared base.
+    ///
+    /// This bundle has to end at `shared_up_to` and both bundles have to start at the same block.
+    /// Before appending, the shared blocks are checked to be identical:
+    /// - receipts of every shared block are equal, see [Self::receipts_equal],
+    /// - reverts of every shared block are equal,
+    /// - values of the accounts and storage slots before the first appended block, as recorded by
+    ///   its reverts, equal the present values of this bundle. Values that are not in this bundle
+    ///   are not checked.
+    ///
+    /// On error this bundle is not changed.
+    pub fn merge_shared_base(
+        &mut self,
+        other: Self,
+        shared_up_to: BlockNumber,
+    ) -> Result<(), MergeError> {
+        let is_shared = self.first_block == other.first_block &&
+            self.block_number_to_index(shared_up_to).map(|index| index + 1) == Some(self.len()) &&
+            other.block_number_to_index(shared_up_to).is_some();
+        if !is_shared {
+            return Err(MergeError::NotShared {
+                shared_up_to,
+                first_block: self.first_block,
+                other_first_block: other.first_block,
+            })
+        }
+
+        let mut upper = other;
+        // Other bundle ending at the shared block has nothing to append.
+        let lower = upper.split_at(shared_up_to).unwrap_or_else(|| std::mem::take(&mut upper));
+
+        self.receipts_equal(&lower)?;
+        for index in 0..self.len() {
+            // Reverts of a block are not ordered.
+            let [left, right] = [&self.bundle.reverts, &lower.bundle.reverts].map(|reverts| {
+                reverts.get(index).map(|block_reverts| {
+                    block_reverts
+                        .iter()
+                        .map(|(address, revert)| (address, revert))
+                        .collect::<HashMap<_, _>>()
+                })
+            });
+            if left != right {
+                return Err(MergeError::Reverts(self.first_block + index as BlockNumber))
+            }
+        }
+
+        for (address, revert) in upper.bundle.reverts.first().into_iter().flatten() {
+            let Some(account) = self.bundle.account(address) else { continue };
+            let info_matches = match &revert.account {
+                AccountInfoRevert::DoNothing => true,
+                AccountInfoRevert::DeleteIt => account.info.is_none(),
+                AccountInfoRevert::RevertTo(info) => account.info.as_ref() == Some(info),
+            };
+            let storage_matches = revert.storage.iter().all(|(key, slot)| match slot {
+                RevertToSlot::Some(value) => {
+                    account.storage.get(key).map_or(true, |slot| slot.present_value == *value)
+                }
+                RevertToSlot::Destroyed => true,
+            });
+            if !info_matches || !storage_matches {
+                return Err(MergeError::Base(*address))
+            }
+        }
+
+        if !upper.is_empty() {
+            self.extend(upper);
+        }
+        Ok(())
+    }
+
+    /// Extend the state with another one like [Self::extend], discarding its receipts.
+    ///
+    /// Every block of `other` is kept as a block with empty receipts, so block numbers of this
+    /// bundle stay valid and reverts stay aligned with the blocks. Receipts of `other` are lost:
+    /// they are not written by [Self::write_to_db], blooms, receipt roots and gas used of its
+    /// blocks are those of empty blocks and their blob gas used is not recorded. Use this only
+    /// when the receipts are not needed, for example to compute the state root of a checkpoint.
+    pub fn extend_state_only(&mut self, other: Self) {
+        let empty_blocks = vec![Vec::new(); other.len()];
+        self.bundle.extend(other.bundle);
+        self.receipts.extend(FlatReceipts::from(Receipts::from_vec(empty_blocks)));
+        self.hashed_state.take();
+    }
+
+    /// Concatenate consecutive bundles into one.
+    ///
+    /// Same as calling [Self::extend] with every bundle in order, but receipts of all bundles are
+    /// collected in a single pass into preallocated storage. Returns an empty bundle if there are
+    /// no bundles.
+    ///
+    /// Returns [ConcatError::NonContiguous] if a bundle does not start at the block following the
+    /// last block of the previous bundle.
+    pub fn concat(bundles: impl IntoIterator<Item = Self>) -> Result<Self, ConcatError> {
+        let bundles = bundles.into_iter().collect::<Vec<_>>();
+        let mut expected = bundles.first().map_or(0, |first| first.first_block);
+        for (index, bundle) in bundles.iter().enumerate() {
+            if bundle.first_block != expected {
+                return Err(ConcatError::NonContiguous { index, expected, got: bundle.first_block })
+            }
+            expected += bundle.len() as BlockNumber;
+        }
+        let (num_blocks, num_receipts) = bundles.iter().fold((0, 0), |(blocks, receipts), b| {
+            (blocks + b.receipts.len(), receipts + b.receipts.total_count())
+        });
+
+        let mut bundles = bundles.into_iter();
+        let Some(first) = bundles.next() else { return Ok(Self::default()) };
+        let mut receipts = FlatReceipts::with_capacity(num_blocks, num_receipts);
+        receipts.extend(first.receipts);
+        let mut bundle = first.bundle;
+
+        for other in bundles {
+            bundle.extend(other.bundle);
+            receipts.extend(other.receipts);
+        }
+
+        Ok(Self {
+            bundle,
+            receipts,
+            first_block: first.first_block,
+            detached: first.detached,
+            hashed_state: OnceLock::new(),
+        })
+    }
+
+    /// Concatenate bundles of single consecutive blocks into one, keeping receipts grouped by
+    /// block.
+    ///
+    /// Same as [Self::concat], but also rejects bundles that do not contain exactly one block.
+    /// Returns an empty bundle if there are no bundles.
+    pub fn from_per_block(blocks: Vec<Self>) -> Result<Self, ConcatError> {
+        for (index, block) in blocks.iter().enumerate() {
+            if block.len() != 1 {
+                return Err(ConcatError::NotSingleBlock {
+                    index,
+                    first_block: block.first_block,
+                    len: block.len(),
+                })
+            }
+        }
+        Self::concat(blocks)
+    }
+
+    /// Append receipts of the next block, without any state changes.
+    ///
+    /// An empty revert is added for the block, so the bundle keeps one revert per block and
+    /// [Self::revert_to] stays consistent.
+    pub fn append_receipts(&mut self, block_receipts: Vec<Receipt>) {
+        let block = Self::new_init(
+            HashMap::new(),
+            HashMap::from([(0, HashMap::new())]),
+            Vec::new(),
+            Receipts::from_vec(vec![block_receipts.into_iter().map(Some).collect()]),
+            self.last_block(),
+        );
+        self.extend(block);
+    }
+
+    /// Write bundle state to database.
+    ///
+    /// `is_value_known` should be set to [OriginalValuesKnown::No] if bundle has some of it data
+    /// detached, This would make some original values not known, see
+    /// [Self::original_values_known]. Only the tables selected by `write_tables` are written.
+    ///
+    /// Returns [BundleWriteError::UnknownOriginalValues] without writing anything if a lower part
+    /// of the bundle was detached with [Self::split_at] and `is_value_known` is
+    /// [OriginalValuesKnown::Yes], as unchanged values would be skipped based on wrong original
+    /// values.
+    ///
+    /// Bytecode references of the accounts are not checked, see
+    /// [Self::verify_bytecode_references].
+    pub fn write_to_db<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+        is_value_known: OriginalValuesKnown,
+        write_tables: WriteTables,
+    ) -> Result<(), BundleWriteError> {
+        self.write_to_db_inner(tx, is_value_known, write_tables, None)?;
+        Ok(())
+    }
+
+    /// Write bundle state to database like [Self::write_to_db], skipping the bytecodes that are in
+    /// `checkpoint`.
+    ///
+    /// This is meant for a bundle that is extended and written repeatedly, for example a copy of
+    /// it at every partial commit, where bytecodes written by the previous commit don't have to be
+    /// written again. Returns the checkpoint to pass to the next write, which contains all
+    /// bytecodes of this bundle if [WriteTables::BYTECODES] is selected and is `checkpoint`
+    /// otherwise.
+    pub fn write_to_db_since_checkpoint<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+        is_value_known: OriginalValuesKnown,
+        write_tables: WriteTables,
+        checkpoint: ContractCheckpoint,
+    ) -> Result<ContractCheckpoint, BundleWriteError> {
+        let next_checkpoint = if write_tables.contains(WriteTables::BYTECODES) {
+            let mut next_checkpoint = self.contract_checkpoint();
+            next_checkpoint.code_hashes.extend(checkpoint.code_hashes.iter().copied());
+            next_checkpoint
+        } else {
+            checkpoint.clone()
+        };
+        self.write_to_db_inner(tx, is_value_known, write_tables, Some(&checkpoint))?;
+        Ok(next_checkpoint)
+    }
+
+    /// Write bundle state to database and return the range of [tables::Receipts] keys assigned to
+    /// the receipts of every block.
+    ///
+    /// Same as [Self::write_to_db] with all tables, original values are treated as not known.
+    /// Ranges are ordered by block number and blocks without receipts are omitted. A range covers
+    /// all transactions of the block, including the ones whose receipts are pruned and not
+    /// written.
+    pub fn write_to_db_with_receipt_indices<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+    ) -> Result<Vec<(BlockNumber, Range<u64>)>, BundleWriteError> {
+        let (receipt_indices, _) =
+            self.write_to_db_inner(tx, OriginalValuesKnown::No, WriteTables::default(), None)?;
+        Ok(receipt_indices)
+    }
+
+    /// Write bundle state to database, see [Self::write_to_db]. Bytecodes in `written_contracts`
+    /// are skipped.
+    ///
+    /// Returns the receipt index ranges of the blocks if [WriteTables::RECEIPTS] is selected and
+    /// the number of written bytecodes that were already in [tables::Bytecodes], which is also
+    /// recorded in the `deduplicated_bytecodes` field of a debug event.
+    fn write_to_db_inner<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+        is_value_known: OriginalValuesKnown,
+        write_tables: WriteTables,
+        written_contracts: Option<&ContractCheckpoint>,
+    ) -> Result<(Vec<(BlockNumber, Range<u64>)>, usize), BundleWriteError> {
+        if self.detached && matches!(is_value_known, OriginalValuesKnown::Yes) {
+            return Err(BundleWriteError::UnknownOriginalValues)
+        }
+        // Misaligned reverts would be written to the changesets of wrong blocks.
+        if write_tables.contains(WriteTables::CHANGESETS) {
+            self.check_block_alignment()?;
+        }
+        let (mut plain_state, reverts) = self.bundle.into_plain_state_and_reverts(is_value_known);
+
+        if write_tables.contains(WriteTables::CHANGESETS) {
+            StateReverts(reverts).write_to_db(tx, self.first_block)?;
+        }
+
+        // write receipts
+        let mut receipt_indices = Vec::new();
+        if write_tables.contains(WriteTables::RECEIPTS) {
+            receipt_indices = write_receipts(tx, self.receipts, self.first_block)
+                .map_err(BundleWriteError::Receipts)?;
+        }
+
+        if !write_tables.contains(WriteTables::PLAIN_STATE) {
+            plain_state.accounts.clear();
+            plain_state.storage.clear();
+        }
+        if !write_tables.contains(WriteTables::BYTECODES) {
+            plain_state.contracts.clear();
+        } else if let Some(written_contracts) = written_contracts {
+            plain_state.contracts.retain(|(code_hash, _)| !written_contracts.contains(code_hash));
+        }
+        let deduplicated_bytecodes = StateChanges(plain_state).write_to_db(tx)?;
+        tracing::debug!(
+            target: "provider::post_state",
+            deduplicated_bytecodes,
+            "Wrote bundle state"
+        );
+
+        Ok((receipt_indices, deduplicated_bytecodes))
+    }
+
+    /// Write bundle state to database after checking that its state root matches
+    /// `expected_root`.
+    ///
+    /// The state root is computed with [Self::state_root_slow] against the database before the
+    /// bundle is written, so the hashed state tables have to be in sync with the plain state. On
+    /// mismatch nothing is written and the transaction should be dropped without committing.
+    /// Original values are treated as not known.
+    pub fn write_to_db_verified<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+        expected_root: H256,
+    ) -> Result<(), VerifiedWriteError> {
+        let got = self.state_root_slow(tx)?;
+        if got != expected_root {
+            return Err(VerifiedWriteError::StateRootMismatch { expected: expected_root, got })
+        }
+        self.write_to_db(tx, OriginalValuesKnown::No, WriteTables::default())?;
+        Ok(())
+    }
+
+    /// Write only the account and storage changesets of the bundle.
+    ///
+    /// Same as [Self::write_to_db] with [WriteTables::CHANGESETS]. Receipts, bytecodes and plain
+    /// state are not written, so the caller is responsible for keeping the plain state in sync
+    /// with the changesets. Plain state has to be the state before the first block of the bundle,
+    /// as the wiped storage of destroyed accounts is read from it. If it is not, the changesets
+    /// will not match the plain state.
+    pub fn write_reverts_only<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+    ) -> Result<(), BundleWriteError> {
+        self.write_to_db(tx, OriginalValuesKnown::No, WriteTables::CHANGESETS)
+    }
+
+    /// Write bundle state to database on the blocking thread pool and commit the transaction.
+    ///
+    /// Runs [Self::write_to_db] inside [tokio::task::spawn_blocking], so the calling task is not
+    /// blocked while the bundle is persisted. A panic of the write is resumed on the caller.
+    pub async fn write_to_db_spawned<DB>(
+        self,
+        factory: ProviderFactory<DB>,
+        is_value_known: OriginalValuesKnown,
+        write_tables: WriteTables,
+    ) -> RethResult<()>
+    where
+        DB: Database + Send + 'static,
+    {
+        let task = tokio::task::spawn_blocking(move || -> RethResult<()> {
+            let provider = factory.provider_rw()?;
+            self.write_to_db(provider.tx_ref(), is_value_known, write_tables)?;
+            provider.commit()?;
+            Ok(())
+        });
+        match task.await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+            Err(err) => Err(RethError::Custom(err.to_string())),
+        }
+    }
+}
+
+/// Return names of the fields that differ between two receipts.
+fn receipt_diff(left: &Receipt, right: &Receipt) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if left.tx_type != right.tx_type {
+        fields.push("tx_type");
+    }
+    if left.success != right.success {
+        fields.push("success");
+    }
+    if left.cumulative_gas_used != right.cumulative_gas_used {
+        fields.push("cumulative_gas_used");
+    }
+    if left.logs != right.logs {
+        fields.push("logs");
+    }
+    fields
+}
+
+/// Write receipts of the bundle starting at `first_block`, pruned receipts are skipped.
+///
+/// Returns the range of receipt indices of every block that has receipts.
+fn write_receipts<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    receipts: FlatReceipts,
+    first_block: BlockNumber,
+) -> Result<Vec<(BlockNumber, Range<u64>)>, DatabaseError> {
+    let rows = receipts.total_count();
+    let _span = tracing::info_span!(target: "provider::receipts", "write_receipts", rows).entered();
+    let mut bodies_cursor = tx.cursor_read::<tables::BlockBodyIndices>()?;
+    let mut receipts_cursor = tx.cursor_write::<tables::Receipts>()?;
+
+    #[cfg(not(feature = "compressed-receipts"))]
+    let (receipts, block_lengths) = receipts.into_flattened();
+    #[cfg(feature = "compressed-receipts")]
+    let (receipts, block_lengths) = receipts.into_flattened()?;
+    let mut receipts = receipts.into_iter();
+    let mut indices = Vec::new();
+    for (idx, block_length) in block_lengths.into_iter().enumerate() {
+        if block_length != 0 {
+            let block_number = first_block + idx as u64;
+            let (_, body_indices) =
+                bodies_cursor.seek_exact(block_number)?.expect("body indices exist");
+
+            let first_tx_index = body_indices.first_tx_num();
+            for (tx_idx, receipt) in receipts.by_ref().take(block_length).enumerate() {
+                if let Some(receipt) = receipt {
+                    receipts_cursor.append(first_tx_index + tx_idx as u64, receipt)?;
+                }
+            }
+            indices.push((block_number, first_tx_index..first_tx_index + block_length as u64));
+        }
+    }
+    Ok(indices)
+}
+
+/// Apply `f` to all items on up to `num_threads` scoped threads, returning the results in the
+/// order of the items.
+fn map_on_threads<T: Sync, R: Send>(
+    items: &[T],
+    num_threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    if num_threads <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect()
+    }
+    let chunk_size = (items.len() + num_threads - 1) / num_threads;
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|err| std::panic::resume_unwind(err)))
+            .collect()
+    })
+}
+
+/// Hash address and storage keys of the account.
+///
+/// Returns the hashed address, the present account or None if it does not exist and the hashed
+/// storage.
+fn hash_account(
+    address: &Address,
+    account: &BundleAccount,
+) -> (H256, Option<Account>, HashedStorage) {
+    let mut hashed_storage = HashedStorage::new(account.status.was_destroyed());
+    for (key, value) in account.storage.iter() {
+        let hashed_key = keccak256(H256(key.to_be_bytes()));
+        if value.present_value == U256::ZERO {
+            hashed_storage.insert_zero_valued_slot(hashed_key);
+        } else {
+            hashed_storage.insert_non_zero_valued_storage(hashed_key, value.present_value);
+        }
+    }
+    (keccak256(address), account.info.clone().map(into_reth_acc), hashed_storage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatabaseError, ProviderError, RethError, StateChanges, StateReverts};
+    use crate::{
+        bundle_state::{
+            AccountChange, AlignmentError, BlockRevertStats, BloomMismatch, BundleChangesets,
+            BundleStateBuilder, BundleWriteError, BytecodeSink, ConcatError, ContractCheckpoint,
+            EffectiveDiff, InMemoryState, MergeError, MissingBytecode, OutOfRange, PlainStateOp,
+            PlainStorageOp, ReceiptMismatch, RevertError, StorageChange, StorageSlotChange,
+            TooManyLogs, VerifiedWriteError, WriteTables,
+        },
+        AccountReader, BundleStateWithReceipts, ProviderFactory,
+    };
+    use assert_matches::assert_matches;
+    use proptest::prelude::*;
+    use reth_db::{
+        cursor::{DbCursorRO, DbDupCursorRO},
+        database::Database,
+        models::{AccountBeforeTx, BlockNumberAddress, StoredBlockBodyIndices},
+        table::Compress,
+        tables,
+        test_utils::create_test_rw_db,
+        transaction::{DbTx, DbTxMut},
+        DatabaseEnv,
+    };
+    use reth_primitives::{
+        bloom::logs_bloom,
+        keccak256,
+        proofs::{calculate_receipt_root_ref, EMPTY_ROOT},
+        trie::Nibbles,
+        Account as RethAccount, Address, Bloom, Bytecode, Log, Receipt, Receipts, StorageEntry,
+        H160, H256, MAINNET, U256,
+    };
+    use reth_revm_primitives::{
+        into_reth_acc,
+        primitives::{Bytecode as RevmBytecode, HashMap},
+    };
+    use reth_trie::{
+        hashed_cursor::HashedPostStateCursorFactory, test_utils::storage_root_prehashed,
+    };
+    use revm::{
+        db::{
+            states::{
+                bundle_state::{BundleRetention, OriginalValuesKnown},
+                changes::{PlainStateReverts, PlainStorageRevert},
+                PlainStorageChangeset, RevertToSlot, StateChangeset,
+            },
+            BundleState,
+        },
+        primitives::{Account, AccountInfo as RevmAccountInfo, AccountStatus, StorageSlot},
+        CacheState, DatabaseCommit, State,
+    };
+    use std::{collections::BTreeMap, sync::Arc};
+
+    /// Receipts of all blocks in the bundle.
+    fn block_receipts(bundle: &BundleStateWithReceipts) -> Vec<Vec<Option<Receipt>>> {
+        bundle.receipts_view().iter_blocks().map(|(_, receipts)| receipts.to_vec()).collect()
+    }
+
+    #[test]
+    fn write_to_db_account_info() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+
+        let account_a = RevmAccountInfo { balance: U256::from(1), nonce: 1, ..Default::default() };
+        let account_b = RevmAccountInfo { balance: U256::from(2), nonce: 2, ..Default::default() };
+        let account_b_changed =
+            RevmAccountInfo { balance: U256::from(3), nonce: 3, ..Default::default() };
+
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_not_existing(address_a);
+        cache_state.insert_account(address_b, account_b.clone());
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+
+        // 0x00.. is created
+        state.commit(HashMap::from([(
+            address_a,
+            Account {
+                info: account_a.clone(),
+                status: AccountStatus::Touched | AccountStatus::Created,
+                storage: HashMap::default(),
+            },
+        )]));
+
+        // 0xff.. is changed (balance + 1, nonce + 1)
+        state.commit(HashMap::from([(
+            address_b,
+            Account {
+                info: account_b_changed.clone(),
+                status: AccountStatus::Touched,
+                storage: HashMap::default(),
+            },
+        )]));
+
+        state.merge_transitions(BundleRetention::Reverts);
+        let mut revm_bundle_state = state.take_bundle();
+
+        // Write plain state and reverts separately.
+        let reverts = revm_bundle_state.take_all_reverts().into_plain_state_reverts();
+        let plain_state = revm_bundle_state.into_plain_state(OriginalValuesKnown::Yes);
+        assert!(plain_state.storage.is_empty());
+        assert!(plain_state.contracts.is_empty());
+        StateChanges(plain_state)
+            .write_to_db(provider.tx_ref())
+            .expect("Could not write plain state to DB");
+
+        assert_eq!(reverts.storage, [[]]);
+        StateReverts(reverts)
+            .write_to_db(provider.tx_ref(), 1)
+            .expect("Could not write reverts to DB");
+
+        let reth_account_a = into_reth_acc(account_a);
+        let reth_account_b = into_reth_acc(account_b);
+        let reth_account_b_changed = into_reth_acc(account_b_changed.clone());
+
+        // Check plain state
+        assert_eq!(
+            provider.basic_account(address_a).expect("Could not read account state"),
+            Some(reth_account_a),
+            "Account A state is wrong"
+        );
+        assert_eq!(
+            provider.basic_account(address_b).expect("Could not read account state"),
+            Some(reth_account_b_changed),
+            "Account B state is wrong"
+        );
+
+        // Check change set
+        let mut changeset_cursor = provider
+            .tx_ref()
+            .cursor_dup_read::<tables::AccountChangeSet>()
+            .expect("Could not open changeset cursor");
+        assert_eq!(
+            changeset_cursor.seek_exact(1).expect("Could not read account change set"),
+            Some((1, AccountBeforeTx { address: address_a, info: None })),
+            "Account A changeset is wrong"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().expect("Changeset table is malformed"),
+            Some((1, AccountBeforeTx { address: address_b, info: Some(reth_account_b) })),
+            "Account B changeset is wrong"
+        );
+
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_account(address_b, account_b_changed.clone());
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+
+        // 0xff.. is destroyed
+        state.commit(HashMap::from([(
+            address_b,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
+                info: account_b_changed,
+                storage: HashMap::default(),
+            },
+        )]));
+
+        state.merge_transitions(BundleRetention::Reverts);
+        let mut revm_bundle_state = state.take_bundle();
+
+        // Write plain state and reverts separately.
+        let reverts = revm_bundle_state.take_all_reverts().into_plain_state_reverts();
+        let plain_state = revm_bundle_state.into_plain_state(OriginalValuesKnown::Yes);
+        // Account B selfdestructed so flag for it should be present.
+        assert_eq!(
+            plain_state.storage,
+            [PlainStorageChangeset { address: address_b, wipe_storage: true, storage: vec![] }]
+        );
+        assert!(plain_state.contracts.is_empty());
+        StateChanges(plain_state)
+            .write_to_db(provider.tx_ref())
+            .expect("Could not write plain state to DB");
+
+        assert_eq!(
+            reverts.storage,
+            [[PlainStorageRevert { address: address_b, wiped: true, storage_revert: vec![] }]]
+        );
+        StateReverts(reverts)
+            .write_to_db(provider.tx_ref(), 2)
+            .expect("Could not write reverts to DB");
+
+        // Check new plain state for account B
+        assert_eq!(
+            provider.basic_account(address_b).expect("Could not read account state"),
+            None,
+            "Account B should be deleted"
+        );
+
+        // Check change set
+        assert_eq!(
+            changeset_cursor.seek_exact(2).expect("Could not read account change set"),
+            Some((2, AccountBeforeTx { address: address_b, info: Some(reth_account_b_changed) })),
+            "Account B changeset is wrong after deletion"
+        );
+    }
+
+    #[test]
+    fn write_to_db_storage() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+
+        let account_b = RevmAccountInfo { balance: U256::from(2), nonce: 2, ..Default::default() };
+
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_not_existing(address_a);
+        cache_state.insert_account_with_storage(
+            address_b,
+            account_b.clone(),
+            HashMap::from([(U256::from(1), U256::from(1))]),
+        );
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+
+        state.commit(HashMap::from([
+            (
+                address_a,
+                Account {
+                    status: AccountStatus::Touched | AccountStatus::Created,
+                    info: RevmAccountInfo::default(),
+                    // 0x00 => 0 => 1
+                    // 0x01 => 0 => 2
+                    storage: HashMap::from([
+                        (
+                            U256::from(0),
+                            StorageSlot { present_value: U256::from(1), ..Default::default() },
+                        ),
+                        (
+                            U256::from(1),
+                            StorageSlot { present_value: U256::from(2), ..Default::default() },
+                        ),
+                    ]),
+                },
+            ),
+            (
+                address_b,
+                Account {
+                    status: AccountStatus::Touched,
+                    info: account_b,
+                    // 0x01 => 1 => 2
+                    storage: HashMap::from([(
+                        U256::from(1),
+                        StorageSlot {
+                            present_value: U256::from(2),
+                            previous_or_original_value: U256::from(1),
+                        },
+                    )]),
+                },
+            ),
+        ]));
+
+        state.merge_transitions(BundleRetention::Reverts);
+
+        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 1)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write bundle state to DB");
+
+        // Check plain storage state
+        let mut storage_cursor = provider
+            .tx_ref()
+            .cursor_dup_read::<tables::PlainStorageState>()
+            .expect("Could not open plain storage state cursor");
+
+        assert_eq!(
+            storage_cursor.seek_exact(address_a).unwrap(),
+            Some((address_a, StorageEntry { key: H256::zero(), value: U256::from(1) })),
+            "Slot 0 for account A should be 1"
+        );
+        assert_eq!(
+            storage_cursor.next_dup().unwrap(),
+            Some((
+                address_a,
+                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
+            )),
+            "Slot 1 for account A should be 2"
+        );
+        assert_eq!(
+            storage_cursor.next_dup().unwrap(),
+            None,
+            "Account A should only have 2 storage slots"
+        );
+
+        assert_eq!(
+            storage_cursor.seek_exact(address_b).unwrap(),
+            Some((
+                address_b,
+                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
+            )),
+            "Slot 1 for account B should be 2"
+        );
+        assert_eq!(
+            storage_cursor.next_dup().unwrap(),
+            None,
+            "Account B should only have 1 storage slot"
+        );
+
+        // Check change set
+        let mut changeset_cursor = provider
+            .tx_ref()
+            .cursor_dup_read::<tables::StorageChangeSet>()
+            .expect("Could not open storage changeset cursor");
+        assert_eq!(
+            changeset_cursor.seek_exact(BlockNumberAddress((1, address_a))).unwrap(),
+            Some((
+                BlockNumberAddress((1, address_a)),
+                StorageEntry { key: H256::zero(), value: U256::from(0) }
+            )),
+            "Slot 0 for account A should have changed from 0"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().unwrap(),
+            Some((
+                BlockNumberAddress((1, address_a)),
+                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(0) }
+            )),
+            "Slot 1 for account A should have changed from 0"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().unwrap(),
+            None,
+            "Account A should only be in the changeset 2 times"
+        );
+
+        assert_eq!(
+            changeset_cursor.seek_exact(BlockNumberAddress((1, address_b))).unwrap(),
+            Some((
+                BlockNumberAddress((1, address_b)),
+                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(1) }
+            )),
+            "Slot 1 for account B should have changed from 1"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().unwrap(),
+            None,
+            "Account B should only be in the changeset 1 time"
+        );
+
+        // Delete account A
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_account(address_a, RevmAccountInfo::default());
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+
+        state.commit(HashMap::from([(
+            address_a,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
+                info: RevmAccountInfo::default(),
+                storage: HashMap::default(),
+            },
+        )]));
+
+        state.merge_transitions(BundleRetention::Reverts);
+        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 2)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write bundle state to DB");
+
+        assert_eq!(
+            storage_cursor.seek_exact(address_a).unwrap(),
+            None,
+            "Account A should have no storage slots after deletion"
+        );
+
+        assert_eq!(
+            changeset_cursor.seek_exact(BlockNumberAddress((2, address_a))).unwrap(),
+            Some((
+                BlockNumberAddress((2, address_a)),
+                StorageEntry { key: H256::zero(), value: U256::from(1) }
+            )),
+            "Slot 0 for account A should have changed from 1 on deletion"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().unwrap(),
+            Some((
+                BlockNumberAddress((2, address_a)),
+                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
+            )),
+            "Slot 1 for account A should have changed from 2 on deletion"
+        );
+        assert_eq!(
+            changeset_cursor.next_dup().unwrap(),
+            None,
+            "Account A should only be in the changeset 2 times on deletion"
+        );
+    }
+
+    #[test]
+    fn write_to_db_selected_tables() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .build()
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::PLAIN_STATE)
+            .expect("Could not write bundle state to DB");
+
+        assert_eq!(provider.basic_account(address).unwrap(), Some(account));
+        assert_eq!(provider.tx_ref().entries::<tables::PlainStorageState>().unwrap(), 1);
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 0);
+        assert_eq!(provider.tx_ref().entries::<tables::StorageChangeSet>().unwrap(), 0);
+    }
+
+    #[test]
+    fn transient_accounts() {
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let transient = Address::repeat_byte(0x11);
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, transient, None, Some(account))
+            .add_account_change(2, transient, Some(account), None)
+            .add_account_change(1, Address::repeat_byte(0x22), None, Some(account))
+            .add_account_change(2, Address::repeat_byte(0x33), Some(account), None)
+            .build();
+
+        assert_eq!(bundle.transient_accounts().collect::<Vec<_>>(), vec![transient]);
+        assert_eq!(BundleStateWithReceipts::default().transient_accounts().count(), 0);
+    }
+
+    #[test]
+    fn wiped_storage_accounts() {
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_account(address_a, RevmAccountInfo::default());
+        cache_state.insert_account(address_b, RevmAccountInfo::default());
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+
+        state.commit(HashMap::from([
+            (
+                address_a,
+                Account {
+                    status: AccountStatus::Touched | AccountStatus::SelfDestructed,
+                    info: RevmAccountInfo::default(),
+                    storage: HashMap::default(),
+                },
+            ),
+            (
+                address_b,
+                Account {
+                    status: AccountStatus::Touched,
+                    info: RevmAccountInfo { nonce: 1, ..Default::default() },
+                    storage: HashMap::default(),
+                },
+            ),
+        ]));
+        state.merge_transitions(BundleRetention::Reverts);
+
+        let bundle = BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 1);
+        assert_eq!(bundle.wiped_storage_accounts().collect::<Vec<_>>(), vec![address_a]);
+    }
+
+    #[test]
+    fn account_original() {
+        let created = Address::repeat_byte(0x11);
+        let changed = Address::repeat_byte(0x22);
+        let account_1 = RethAccount { nonce: 1, ..Default::default() };
+        let account_2 = RethAccount { nonce: 2, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, created, None, Some(account_1))
+            .add_account_change(1, changed, Some(account_1), Some(account_2))
+            .build();
+
+        assert_eq!(bundle.account_original(&created), Some(None));
+        assert_eq!(bundle.account_original(&changed), Some(Some(account_1)));
+        assert_eq!(bundle.account(&changed), Some(Some(account_2)));
+        assert_eq!(bundle.account_original(&Address::zero()), None);
+    }
+
+    #[test]
+    fn accounts_or_db() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let in_db = Address::repeat_byte(0x11);
+        let changed = Address::repeat_byte(0x22);
+        let destroyed = Address::repeat_byte(0x33);
+        let account_1 = RethAccount { nonce: 1, ..Default::default() };
+        let account_2 = RethAccount { nonce: 2, ..Default::default() };
+
+        let tx = db.tx_mut().unwrap();
+        for address in [in_db, changed, destroyed] {
+            tx.put::<tables::PlainAccountState>(address, account_1).unwrap();
+        }
+        tx.commit().unwrap();
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, changed, Some(account_1), Some(account_2))
+            .add_account_change(1, destroyed, Some(account_1), None)
+            .build();
+
+        let tx = db.tx().unwrap();
+        assert_eq!(
+            bundle
+                .accounts_or_db(&tx, &[destroyed, Address::zero(), changed, in_db, in_db])
+                .unwrap(),
+            vec![None, None, Some(account_2), Some(account_1), Some(account_1)]
+        );
+        assert_eq!(bundle.accounts_or_db(&tx, &[]).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn storage_original() {
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, Some(account), Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::from(1), U256::from(2))
+            .build();
+
+        assert_eq!(bundle.storage_original(&address, U256::from(1)), Some(U256::from(1)));
+        assert_eq!(bundle.storage(&address, U256::from(1)), Some(U256::from(2)));
+        assert_eq!(bundle.storage_original(&address, U256::from(2)), None);
+        assert_eq!(bundle.storage_original(&Address::zero(), U256::from(1)), None);
+    }
+
+    #[test]
+    fn is_storage_only_change() {
+        let storage_only = Address::repeat_byte(0x11);
+        let nonce_changed = Address::repeat_byte(0x22);
+        let unchanged = Address::repeat_byte(0x33);
+        let account_1 = RethAccount { nonce: 1, ..Default::default() };
+        let account_2 = RethAccount { nonce: 2, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, storage_only, Some(account_1), Some(account_1))
+            .add_storage_change(1, storage_only, slot, U256::from(1), U256::from(2))
+            .add_account_change(1, nonce_changed, Some(account_1), Some(account_2))
+            .add_storage_change(1, nonce_changed, slot, U256::from(1), U256::from(2))
+            .add_account_change(1, unchanged, Some(account_1), Some(account_1))
+            .add_storage_change(1, unchanged, slot, U256::from(1), U256::from(1))
+            .build();
+
+        assert_eq!(bundle.is_storage_only_change(&storage_only), Some(true));
+        assert_eq!(bundle.is_storage_only_change(&nonce_changed), Some(false));
+        assert_eq!(bundle.is_storage_only_change(&unchanged), Some(false));
+        assert_eq!(bundle.is_storage_only_change(&Address::zero()), None);
+    }
+
+    #[test]
+    fn all_storage_changes() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let slot = |slot| H256::from_low_u64_be(slot);
+        let bundle = BundleStateBuilder::new(1)
+            .add_storage_change(1, address_a, slot(1), U256::ZERO, U256::from(1))
+            .add_storage_change(1, address_a, slot(2), U256::from(2), U256::from(2))
+            .add_storage_change(2, address_b, slot(1), U256::from(3), U256::ZERO)
+            .build();
+
+        let mut changes = bundle.all_storage_changes().collect::<Vec<_>>();
+        changes.sort_unstable();
+        assert_eq!(
+            changes,
+            vec![
+                (address_a, U256::from(1), U256::ZERO, U256::from(1)),
+                (address_b, U256::from(1), U256::from(3), U256::ZERO),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_to_memory() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let slot = |slot| H256::from_low_u64_be(slot);
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        let bytecode_hash = H256::repeat_byte(0xaa);
+
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+        let mut memory = InMemoryState::default();
+        for address in [address_a, address_b] {
+            tx.put::<tables::PlainAccountState>(address, account).unwrap();
+            memory.accounts.insert(address, account);
+            for key in [1, 2] {
+                let entry = StorageEntry { key: slot(key), value: U256::from(key) };
+                tx.put::<tables::PlainStorageState>(address, entry).unwrap();
+                memory.storage.entry(address).or_default().insert(entry.key, entry.value);
+            }
+        }
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, Some(account), Some(account))
+            .add_storage_change(1, address_a, slot(1), U256::from(1), U256::ZERO)
+            .add_storage_change(1, address_a, slot(3), U256::ZERO, U256::from(3))
+            .add_account_change(1, address_b, Some(account), None)
+            .add_contract(bytecode_hash, bytecode.clone())
+            .build();
+        bundle.apply_to_memory(&mut memory);
+        bundle.write_to_db(tx, OriginalValuesKnown::No, WriteTables::default()).unwrap();
+
+        let mut from_db = InMemoryState::default();
+        for entry in tx.cursor_read::<tables::PlainAccountState>().unwrap().walk(None).unwrap() {
+            let (address, account) = entry.unwrap();
+            from_db.accounts.insert(address, account);
+        }
+        for entry in tx.cursor_read::<tables::PlainStorageState>().unwrap().walk(None).unwrap() {
+            let (address, entry) = entry.unwrap();
+            from_db.storage.entry(address).or_default().insert(entry.key, entry.value);
+        }
+        for entry in tx.cursor_read::<tables::Bytecodes>().unwrap().walk(None).unwrap() {
+            let (hash, bytecode) = entry.unwrap();
+            from_db.bytecodes.insert(hash, bytecode);
+        }
+
+        assert_eq!(memory, from_db);
+        assert_eq!(memory.accounts.keys().collect::<Vec<_>>(), vec![&address_a]);
+        assert_eq!(
+            memory.storage[&address_a],
+            BTreeMap::from([(slot(2), U256::from(2)), (slot(3), U256::from(3))])
+        );
+        assert_eq!(memory.bytecodes[&bytecode_hash], bytecode);
+    }
+
+    #[test]
+    fn to_plain_state() {
+        let address = Address::repeat_byte(0x11);
+        let destroyed = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_account_change(1, destroyed, Some(account), None)
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_storage_change(1, address, H256::from_low_u64_be(2), U256::from(2), U256::ZERO)
+            .build();
+
+        let storage = std::collections::HashMap::from([(H256::from_low_u64_be(1), U256::from(1))]);
+        assert_eq!(
+            bundle.to_plain_state(),
+            std::collections::HashMap::from([(address, (account, storage))])
+        );
+    }
+
+    #[test]
+    fn write_reverts_only() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_receipt(1, Receipt::default())
+            .build()
+            .write_reverts_only(provider.tx_ref())
+            .expect("Could not write reverts to DB");
+
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 1);
+        assert_eq!(provider.tx_ref().entries::<tables::StorageChangeSet>().unwrap(), 1);
+        assert_eq!(provider.tx_ref().entries::<tables::PlainAccountState>().unwrap(), 0);
+        assert_eq!(provider.tx_ref().entries::<tables::PlainStorageState>().unwrap(), 0);
+        assert_eq!(provider.tx_ref().entries::<tables::Receipts>().unwrap(), 0);
+    }
+
+    #[test]
+    fn bundle_state_is_send() {
+        fn assert_send<T: Send + 'static>() {}
+        assert_send::<BundleState>();
+        assert_send::<BundleStateWithReceipts>();
+    }
+
+    #[tokio::test]
+    async fn write_to_db_spawned() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .build()
+            .write_to_db_spawned(factory.clone(), OriginalValuesKnown::Yes, WriteTables::default())
+            .await
+            .expect("Could not write bundle state to DB");
+
+        let provider = factory.provider().unwrap();
+        assert_eq!(provider.basic_account(address).unwrap(), Some(account));
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 1);
+    }
+
+    const SPLIT_ADDRESS: Address = H160([0x11; 20]);
+    const SPLIT_ACCOUNT_1: RethAccount =
+        RethAccount { nonce: 1, balance: U256::ZERO, bytecode_hash: None };
+    const SPLIT_ACCOUNT_2: RethAccount =
+        RethAccount { nonce: 2, balance: U256::ZERO, bytecode_hash: None };
+
+    /// Account changes in the lower part and back to its original value in the upper part.
+    fn split_bundle() -> (BundleStateWithReceipts, BundleStateWithReceipts) {
+        let mut upper = BundleStateBuilder::new(1)
+            .add_account_change(1, SPLIT_ADDRESS, Some(SPLIT_ACCOUNT_1), Some(SPLIT_ACCOUNT_2))
+            .add_account_change(2, SPLIT_ADDRESS, Some(SPLIT_ACCOUNT_2), Some(SPLIT_ACCOUNT_1))
+            .build();
+        let lower = upper.split_at(1).unwrap();
+        (lower, upper)
+    }
+
+    #[test]
+    fn write_to_db_empty_bundle() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+
+        fn entries<'a>(tx: &impl DbTx<'a>) -> [usize; 6] {
+            [
+                tx.entries::<tables::Receipts>().unwrap(),
+                tx.entries::<tables::AccountChangeSet>().unwrap(),
+                tx.entries::<tables::StorageChangeSet>().unwrap(),
+                tx.entries::<tables::PlainAccountState>().unwrap(),
+                tx.entries::<tables::PlainStorageState>().unwrap(),
+                tx.entries::<tables::Bytecodes>().unwrap(),
+            ]
+        }
+
+        // Fresh database.
+        BundleStateWithReceipts::default()
+            .write_to_db(tx, OriginalValuesKnown::Yes, WriteTables::default())
+            .unwrap();
+        assert_eq!(entries(tx), [0; 6]);
+
+        // Database with existing rows.
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let entry = StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(1) };
+        tx.put::<tables::Receipts>(0, Receipt::default()).unwrap();
+        tx.put::<tables::AccountChangeSet>(1, AccountBeforeTx { address, info: None }).unwrap();
+        tx.put::<tables::StorageChangeSet>(BlockNumberAddress((1, address)), entry).unwrap();
+        tx.put::<tables::PlainAccountState>(address, account).unwrap();
+        tx.put::<tables::PlainStorageState>(address, entry).unwrap();
+        let before = entries(tx);
+
+        for bundle in [BundleStateWithReceipts::default(), BundleStateBuilder::new(5).build()] {
+            bundle.write_to_db(tx, OriginalValuesKnown::Yes, WriteTables::default()).unwrap();
+        }
+        assert_eq!(entries(tx), before);
+        assert_eq!(tx.get::<tables::Receipts>(0).unwrap(), Some(Receipt::default()));
+        assert_eq!(provider.basic_account(address).unwrap(), Some(account));
+        assert_eq!(
+            tx.cursor_dup_read::<tables::PlainStorageState>()
+                .unwrap()
+                .seek_by_key_subkey(address, entry.key)
+                .unwrap(),
+            Some(entry)
+        );
+    }
+
+    #[test]
+    fn detach_upper_part_at() {
+        let (lower, upper) = split_bundle();
+        let mut full = lower.clone();
+        full.extend(upper.clone());
+
+        let mut kept = full.clone();
+        assert_eq!(kept.detach_upper_part_at(1), Some(upper));
+        assert_eq!(kept.account(&SPLIT_ADDRESS), Some(Some(SPLIT_ACCOUNT_2)));
+        assert_eq!(kept, lower);
+
+        let mut kept = full.clone();
+        assert_eq!(kept.detach_upper_part_at(2), None);
+        assert_eq!(kept.detach_upper_part_at(0), None);
+        assert_eq!(kept, full);
+    }
+
+    #[test]
+    fn detach_lower_reverts_keep_receipts() {
+        let (lower, upper) = split_bundle();
+        let mut full = lower.clone();
+        full.extend(upper);
+        let full_report = full.reverts_report();
+
+        let mut kept = full.clone();
+        let detached = kept.detach_lower_reverts_keep_receipts(1).unwrap();
+        assert_eq!(detached.first_block(), 1);
+        assert!(detached.is_empty());
+        assert_eq!(detached.reverts_len(), 1);
+        assert_eq!(detached.account(&SPLIT_ADDRESS), Some(Some(SPLIT_ACCOUNT_2)));
+        assert_eq!(detached.reverts_report(), &full_report[..1]);
+
+        // Receipts and reverts of the kept part are still indexed from the first block.
+        assert_eq!((kept.first_block(), kept.len(), kept.reverts_len()), (1, 2, 2));
+        assert_eq!(kept.check_block_alignment(), Ok(()));
+        assert_eq!(block_receipts(&kept), block_receipts(&full));
+        assert_eq!(kept.account(&SPLIT_ADDRESS), Some(Some(SPLIT_ACCOUNT_1)));
+        assert_eq!(
+            kept.reverts_report(),
+            vec![BlockRevertStats { block_number: 1, ..Default::default() }, full_report[1]]
+        );
+
+        assert_eq!(full.clone().detach_lower_reverts_keep_receipts(3), None);
+        assert_eq!(full.clone().detach_lower_reverts_keep_receipts(0), None);
+    }
+
+    #[test]
+    fn truncate_to_depth() {
+        let address = Address::repeat_byte(0x11);
+        let account = |nonce| RethAccount { nonce, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(1)))
+            .add_account_change(2, address, Some(account(1)), Some(account(2)))
+            .add_account_change(3, address, Some(account(2)), Some(account(3)))
+            .build();
+        assert!(bundle.exceeds_depth(2));
+        assert!(!bundle.exceeds_depth(3));
+
+        let mut truncated = bundle.clone();
+        assert_eq!(truncated.truncate_to_depth(3), 0);
+        assert_eq!(truncated, bundle);
+
+        assert_eq!(truncated.truncate_to_depth(1), 2);
+        assert_eq!((truncated.first_block(), truncated.len()), (3, 1));
+        assert_eq!(truncated.account(&address), Some(Some(account(3))));
+
+        assert_eq!(truncated.truncate_to_depth(0), 1);
+        assert!(truncated.is_empty());
+    }
+
+    #[test]
+    fn take_first_blocks_under() {
+        let address = Address::repeat_byte(0x11);
+        let account = |nonce| RethAccount { nonce, ..Default::default() };
+        let slot = |slot| H256::from_low_u64_be(slot);
+        // Reverts of blocks 1, 2 and 3 have 1, 3 and 1 entries.
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(1)))
+            .add_account_change(2, address, Some(account(1)), Some(account(2)))
+            .add_storage_change(2, address, slot(1), U256::ZERO, U256::from(1))
+            .add_storage_change(2, address, slot(2), U256::ZERO, U256::from(2))
+            .add_account_change(3, address, Some(account(2)), Some(account(3)))
+            .build();
+
+        let mut upper = bundle.clone();
+        let lower = upper.take_first_blocks_under(4).unwrap();
+        assert_eq!((lower.first_block(), lower.len()), (1, 2));
+        assert_eq!((upper.first_block(), upper.len()), (3, 1));
+
+        // First block is taken even if it has more entries than the limit.
+        let mut upper = bundle.clone();
+        let lower = upper.take_first_blocks_under(0).unwrap();
+        assert_eq!((lower.first_block(), lower.len()), (1, 1));
+
+        let mut upper = bundle.clone();
+        assert_eq!(upper.take_first_blocks_under(usize::MAX).unwrap().len(), 3);
+        assert!(upper.is_empty());
+        assert_eq!(upper.take_first_blocks_under(usize::MAX), None);
+    }
+
+    #[test]
+    fn write_to_db_full_bundle_with_known_values() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        provider.tx_ref().put::<tables::PlainAccountState>(SPLIT_ADDRESS, SPLIT_ACCOUNT_1).unwrap();
+
+        let (mut full, upper) = split_bundle();
+        full.extend(upper);
+        full.write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write bundle state to DB");
+
+        assert_eq!(provider.basic_account(SPLIT_ADDRESS).unwrap(), Some(SPLIT_ACCOUNT_1));
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 2);
+    }
+
+    #[test]
+    fn write_to_db_detached_bundle() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        provider.tx_ref().put::<tables::PlainAccountState>(SPLIT_ADDRESS, SPLIT_ACCOUNT_1).unwrap();
+
+        let (lower, upper) = split_bundle();
+        lower
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write lower bundle state to DB");
+        assert_eq!(provider.basic_account(SPLIT_ADDRESS).unwrap(), Some(SPLIT_ACCOUNT_2));
+
+        // Upper part has the original value from before the lower part.
+        upper
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::No, WriteTables::default())
+            .expect("Could not write upper bundle state to DB");
+        assert_eq!(provider.basic_account(SPLIT_ADDRESS).unwrap(), Some(SPLIT_ACCOUNT_1));
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 2);
+    }
+
+    #[test]
+    fn write_to_db_detached_bundle_with_known_values() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let (lower, upper) = split_bundle();
+        assert!(lower.has_complete_plain_state());
+        assert!(matches!(lower.original_values_known(), OriginalValuesKnown::Yes));
+        assert!(!upper.has_complete_plain_state());
+        assert!(matches!(upper.original_values_known(), OriginalValuesKnown::No));
+
+        assert_eq!(
+            upper.write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default()),
+            Err(BundleWriteError::UnknownOriginalValues)
+        );
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 0);
+    }
+
+    #[test]
+    fn rebuild_plain_state_from_reverts() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        provider.tx_ref().put::<tables::PlainAccountState>(SPLIT_ADDRESS, SPLIT_ACCOUNT_1).unwrap();
+
+        let address = Address::repeat_byte(0x33);
+        let slot = H256::from_low_u64_be(1);
+        let mut upper = BundleStateBuilder::new(1)
+            .add_account_change(1, SPLIT_ADDRESS, Some(SPLIT_ACCOUNT_1), Some(SPLIT_ACCOUNT_2))
+            .add_account_change(2, SPLIT_ADDRESS, Some(SPLIT_ACCOUNT_2), Some(SPLIT_ACCOUNT_1))
+            .add_account_change(1, address, None, Some(SPLIT_ACCOUNT_1))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .build();
+        let lower = upper.split_at(1).unwrap();
+        upper.rebuild_plain_state_from_reverts();
+
+        assert_eq!(upper.account_original(&SPLIT_ADDRESS), Some(Some(SPLIT_ACCOUNT_2)));
+        assert_eq!(upper.account_original(&address), Some(Some(SPLIT_ACCOUNT_1)));
+        assert_eq!(upper.storage_original(&address, U256::from(1)), Some(U256::from(1)));
+
+        lower
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write lower bundle state to DB");
+        upper
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write upper bundle state to DB");
+        assert_eq!(provider.basic_account(SPLIT_ADDRESS).unwrap(), Some(SPLIT_ACCOUNT_1));
+        assert_eq!(provider.basic_account(address).unwrap(), Some(SPLIT_ACCOUNT_1));
+        assert_eq!(
+            provider
+                .tx_ref()
+                .cursor_dup_read::<tables::PlainStorageState>()
+                .unwrap()
+                .seek_by_key_subkey(address, slot)
+                .unwrap(),
+            Some(StorageEntry { key: slot, value: U256::from(1) })
+        );
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 3);
+    }
+
+    #[test]
+    fn write_to_db_storage_zero_values() {
+        proptest!(ProptestConfig::with_cases(10), |(
+            initial: BTreeMap<u8, u8>,
+            changes: BTreeMap<u8, u8>,
+            wipe_storage: bool,
+        )| {
+            let db: Arc<DatabaseEnv> = create_test_rw_db();
+            let factory = ProviderFactory::new(db, MAINNET.clone());
+            let provider = factory.provider_rw().unwrap();
+
+            let address = Address::repeat_byte(0x11);
+            let to_storage = |slots: &BTreeMap<u8, u8>| {
+                slots.iter().map(|(k, v)| (U256::from(*k), U256::from(*v))).collect::<Vec<_>>()
+            };
+
+            StateChanges(StateChangeset {
+                accounts: vec![],
+                storage: vec![PlainStorageChangeset {
+                    address,
+                    wipe_storage: false,
+                    storage: to_storage(&initial),
+                }],
+                contracts: vec![],
+            })
+            .write_to_db(provider.tx_ref())
+            .expect("Could not write initial storage to DB");
+
+            StateChanges(StateChangeset {
+                accounts: vec![],
+                storage: vec![PlainStorageChangeset {
+                    address,
+                    wipe_storage,
+                    storage: to_storage(&changes),
+                }],
+                contracts: vec![],
+            })
+            .write_to_db(provider.tx_ref())
+            .expect("Could not write storage changes to DB");
+
+            let mut expected = if wipe_storage { BTreeMap::new() } else { initial };
+            expected.extend(changes);
+            expected.retain(|_, value| *value != 0);
+            let expected = expected
+                .into_iter()
+                .map(|(k, v)| (H256::from(U256::from(k).to_be_bytes()), U256::from(v)))
+                .collect::<Vec<_>>();
+
+            let mut storage_cursor = provider
+                .tx_ref()
+                .cursor_dup_read::<tables::PlainStorageState>()
+                .expect("Could not open plain storage state cursor");
+            let actual = storage_cursor
+                .walk_dup(Some(address), None)
+                .unwrap()
+                .map(|entry| entry.map(|(_, entry)| (entry.key, entry.value)))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            prop_assert_eq!(actual, expected);
+        });
+    }
+
+    #[test]
+    fn write_to_db_since_checkpoint() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        let other_bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x01].into()));
+        let mut bundle =
+            BundleStateBuilder::new(1).add_contract(H256::repeat_byte(1), bytecode.clone()).build();
+        assert_eq!(
+            bundle.new_contracts_since(ContractCheckpoint::default()).collect::<Vec<_>>(),
+            vec![(H256::repeat_byte(1), bytecode)]
+        );
+
+        let checkpoint = bundle
+            .clone()
+            .write_to_db_since_checkpoint(
+                tx,
+                OriginalValuesKnown::No,
+                WriteTables::BYTECODES,
+                ContractCheckpoint::default(),
+            )
+            .unwrap();
+        assert_eq!(checkpoint, bundle.contract_checkpoint());
+        assert!(bundle.new_contracts_since(checkpoint.clone()).next().is_none());
+
+        bundle.extend(
+            BundleStateBuilder::new(1)
+                .add_contract(H256::repeat_byte(2), other_bytecode.clone())
+                .build(),
+        );
+        assert_eq!(
+            bundle.new_contracts_since(checkpoint.clone()).collect::<Vec<_>>(),
+            vec![(H256::repeat_byte(2), other_bytecode)]
+        );
+
+        // Bytecode written by the previous write is not written again.
+        tx.delete::<tables::Bytecodes>(H256::repeat_byte(1), None).unwrap();
+        let next_checkpoint = bundle
+            .clone()
+            .write_to_db_since_checkpoint(
+                tx,
+                OriginalValuesKnown::No,
+                WriteTables::BYTECODES,
+                checkpoint.clone(),
+            )
+            .unwrap();
+        assert_eq!(next_checkpoint, bundle.contract_checkpoint());
+        assert!(tx.get::<tables::Bytecodes>(H256::repeat_byte(1)).unwrap().is_none());
+        assert!(tx.get::<tables::Bytecodes>(H256::repeat_byte(2)).unwrap().is_some());
+
+        // Checkpoint is kept if bytecodes are not written.
+        let kept = bundle
+            .write_to_db_since_checkpoint(
+                tx,
+                OriginalValuesKnown::No,
+                WriteTables::empty(),
+                checkpoint.clone(),
+            )
+            .unwrap();
+        assert_eq!(kept, checkpoint);
+    }
+
+    #[test]
+    fn write_to_db_reports_deduplicated_bytecodes() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        let other_bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x01].into()));
+        let bundle =
+            BundleStateBuilder::new(1).add_contract(H256::repeat_byte(1), bytecode.clone()).build();
+        let (_, deduplicated) = bundle
+            .write_to_db_inner(tx, OriginalValuesKnown::No, WriteTables::BYTECODES, None)
+            .unwrap();
+        assert_eq!(deduplicated, 0);
+
+        // Second bundle has the same code hash, only the other bytecode is new.
+        let bundle = BundleStateBuilder::new(2)
+            .add_contract(H256::repeat_byte(1), bytecode)
+            .add_contract(H256::repeat_byte(2), other_bytecode)
+            .build();
+        let (_, deduplicated) = bundle
+            .write_to_db_inner(tx, OriginalValuesKnown::No, WriteTables::BYTECODES, None)
+            .unwrap();
+        assert_eq!(deduplicated, 1);
+        assert_eq!(tx.entries::<tables::Bytecodes>().unwrap(), 2);
+    }
+
+    #[test]
+    fn write_to_db_deduplicates_bytecodes() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let bytecode = RevmBytecode::new_raw(vec![0x60, 0x00].into());
+        let code_hash = keccak256([0x60, 0x00]);
+        let other_bytecode = RevmBytecode::new_raw(vec![0x60, 0x01].into());
+        let other_code_hash = keccak256([0x60, 0x01]);
+
+        let deduplicated = StateChanges(StateChangeset {
+            accounts: vec![],
+            storage: vec![],
+            contracts: vec![(code_hash, bytecode.clone())],
+        })
+        .write_to_db(provider.tx_ref())
+        .expect("Could not write bytecodes to DB");
+        assert_eq!(deduplicated, 0);
+
+        let deduplicated = StateChanges(StateChangeset {
+            accounts: vec![],
+            storage: vec![],
+            contracts: vec![(code_hash, bytecode), (other_code_hash, other_bytecode)],
+        })
+        .write_to_db(provider.tx_ref())
+        .expect("Could not write bytecodes to DB");
+        assert_eq!(deduplicated, 1);
+
+        assert_eq!(provider.tx_ref().entries::<tables::Bytecodes>().unwrap(), 2);
+        assert!(provider.tx_ref().get::<tables::Bytecodes>(other_code_hash).unwrap().is_some());
+    }
+
+    #[test]
+    fn write_to_db_with_bytecode_sink() {
+        /// Sink collecting bytecodes in memory.
+        #[derive(Default)]
+        struct MemorySink(BTreeMap<H256, Bytecode>);
+
+        impl BytecodeSink for MemorySink {
+            fn write_bytecode(
+                &mut self,
+                hash: H256,
+                bytecode: Bytecode,
+            ) -> Result<bool, DatabaseError> {
+                Ok(self.0.insert(hash, bytecode).is_none())
+            }
+        }
+
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let bytecode = RevmBytecode::new_raw(vec![0x60, 0x00].into());
+        let code_hash = H256::repeat_byte(1);
+        let changes = || {
+            StateChanges(StateChangeset {
+                accounts: vec![(Address::zero(), Some(RevmAccountInfo::default()))],
+                storage: vec![],
+                contracts: vec![(code_hash, bytecode.clone())],
+            })
+        };
+
+        let mut sink = MemorySink::default();
+        assert_eq!(changes().write_to_db_with_bytecode_sink(provider.tx_ref(), &mut sink), Ok(0));
+        assert_eq!(changes().write_to_db_with_bytecode_sink(provider.tx_ref(), &mut sink), Ok(1));
+        assert_eq!(sink.0, BTreeMap::from([(code_hash, Bytecode(bytecode))]));
+        // Plain state is written to the database, bytecodes are not.
+        assert_eq!(provider.tx_ref().entries::<tables::PlainAccountState>().unwrap(), 1);
+        assert_eq!(provider.tx_ref().entries::<tables::Bytecodes>().unwrap(), 0);
+    }
+
+    #[test]
+    fn storage_known() {
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, Some(account), Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::from(1), U256::ZERO)
+            .build();
+
+        // Slot set to zero in the bundle is known.
+        assert_eq!(bundle.storage(&address, U256::from(1)), Some(U256::ZERO));
+        assert!(bundle.storage_known(&address, U256::from(1)));
+        // Untouched slot of an existing account has to be read from the database.
+        assert_eq!(bundle.storage(&address, U256::from(2)), None);
+        assert!(!bundle.storage_known(&address, U256::from(2)));
+        assert!(!bundle.storage_known(&Address::zero(), U256::from(1)));
+    }
+
+    #[test]
+    fn concat() {
+        let address = Address::repeat_byte(0x11);
+        let slot = H256::from_low_u64_be(1);
+        let block = |number: u64| {
+            let old = (number > 1).then(|| RethAccount { nonce: number - 1, ..Default::default() });
+            let new = RethAccount { nonce: number, ..Default::default() };
+            BundleStateBuilder::new(number)
+                .add_account_change(number, address, old, Some(new))
+                .add_storage_change(
+                    number,
+                    address,
+                    slot,
+                    U256::from(number - 1),
+                    U256::from(number),
+                )
+                .add_receipt(number, Receipt { cumulative_gas_used: number, ..Default::default() })
+                .build()
+        };
+
+        let mut extended = block(1);
+        extended.extend(block(2));
+        extended.extend(block(3));
+
+        let concatenated = BundleStateWithReceipts::concat((1..=3).map(block)).unwrap();
+        assert_eq!(concatenated, extended);
+        assert_eq!(concatenated.first_block(), 1);
+        assert_eq!(concatenated.len(), 3);
+
+        assert_eq!(
+            BundleStateWithReceipts::concat(Vec::new()),
+            Ok(BundleStateWithReceipts::default())
+        );
+    }
+
+    #[test]
+    fn concat_not_contiguous() {
+        let block = |number| {
+            BundleStateBuilder::new(number).add_receipt(number, Receipt::default()).build()
+        };
+        assert_eq!(
+            BundleStateWithReceipts::concat([block(1), block(3)]),
+            Err(ConcatError::NonContiguous { index: 1, expected: 2, got: 3 })
+        );
+        // Bundle following a bundle of two blocks.
+        let two_blocks = BundleStateBuilder::new(1).add_receipt(2, Receipt::default()).build();
+        assert_eq!(
+            BundleStateWithReceipts::concat([two_blocks, block(2)]),
+            Err(ConcatError::NonContiguous { index: 1, expected: 3, got: 2 })
+        );
+    }
+
+    #[test]
+    fn from_per_block() {
+        let block = |number| {
+            BundleStateBuilder::new(number)
+                .add_receipt(number, Receipt { cumulative_gas_used: number, ..Default::default() })
+                .build()
+        };
 
-    /// Return first block of the bundle
-    pub fn first_block(&self) -> BlockNumber {
-        self.first_block
-    }
+        let bundle = BundleStateWithReceipts::from_per_block(vec![block(1), block(2)]).unwrap();
+        assert_eq!(bundle, BundleStateWithReceipts::concat([block(1), block(2)]).unwrap());
+        assert_eq!(
+            bundle.receipts_view().by_block(2).unwrap_or_default(),
+            block(2).receipts_view().by_block(2).unwrap_or_default()
+        );
+        assert_eq!(
+            BundleStateWithReceipts::from_per_block(Vec::new()),
+            Ok(BundleStateWithReceipts::default())
+        );
 
-    /// Return last block of the bundle.
-    pub fn last_block(&self) -> BlockNumber {
-        self.first_block + self.len() as BlockNumber
+        assert_eq!(
+            BundleStateWithReceipts::from_per_block(vec![block(1), block(3)]),
+            Err(ConcatError::NonContiguous { index: 1, expected: 2, got: 3 })
+        );
+        assert_eq!(
+            BundleStateWithReceipts::from_per_block(vec![
+                block(1),
+                BundleStateWithReceipts::concat([block(2), block(3)]).unwrap(),
+            ]),
+            Err(ConcatError::NotSingleBlock { index: 1, first_block: 2, len: 2 })
+        );
+        assert_eq!(
+            BundleStateWithReceipts::from_per_block(vec![BundleStateWithReceipts::default()]),
+            Err(ConcatError::NotSingleBlock { index: 0, first_block: 0, len: 0 })
+        );
     }
 
-    /// Revert to given block number.
-    ///
-    /// If number is in future, or in the past return false
-    ///
-    /// Note: Given Block number will stay inside the bundle state.
-    pub fn revert_to(&mut self, block_number: BlockNumber) -> bool {
-        let Some(index) = self.block_number_to_index(block_number) else { return false };
+    #[test]
+    fn receipts_by_range() {
+        let receipt = |gas| Some(Receipt { cumulative_gas_used: gas, ..Default::default() });
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![receipt(1)], vec![], vec![receipt(3), receipt(4)]]),
+            10,
+        );
 
-        // +1 is for number of blocks that we have as index is included.
-        let new_len = index + 1;
-        let rm_trx: usize = self.len() - new_len;
+        assert_eq!(
+            bundle.receipts_by_range(11..=20).collect::<Vec<_>>(),
+            vec![(11, &[][..]), (12, &[receipt(3), receipt(4)][..])]
+        );
+        assert_eq!(
+            bundle.receipts_by_range(0..=10).collect::<Vec<_>>(),
+            vec![(10, &[receipt(1)][..])]
+        );
+        assert_eq!(bundle.receipts_by_range(0..=u64::MAX).count(), 3);
+        assert_eq!(bundle.receipts_by_range(13..=20).count(), 0);
+        assert_eq!(BundleStateWithReceipts::default().receipts_by_range(0..=10).count(), 0);
+    }
 
-        // remove receipts
-        self.receipts.truncate(new_len);
-        // Revert last n reverts.
-        self.bundle.revert(rm_trx);
+    #[test]
+    fn map_receipts() {
+        let receipt = Receipt {
+            cumulative_gas_used: 21000,
+            logs: vec![Log { address: Address::zero(), topics: vec![], data: Default::default() }],
+            ..Default::default()
+        };
+        let mut bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![Some(receipt.clone()), None], vec![Some(receipt)]]),
+            1,
+        );
+
+        bundle.map_receipts(|receipt| receipt.logs.clear());
 
-        true
+        let cleared = Some(Receipt { cumulative_gas_used: 21000, ..Default::default() });
+        assert_eq!(block_receipts(&bundle), vec![vec![cleared.clone(), None], vec![cleared]]);
     }
 
-    /// This will detach lower part of the chain and return it back.
-    /// Specified block number will be included in detachment
-    ///
-    /// This plain state will contains some additional information that
-    /// are is a artifacts of the lower part state.
-    ///
-    /// If block number is in future, return None.
-    pub fn split_at(&mut self, block_number: BlockNumber) -> Option<Self> {
-        let last_block = self.last_block();
-        let first_block = self.first_block;
-        if block_number >= last_block {
-            return None
-        }
-        if block_number < first_block {
-            return Some(Self::default())
-        }
+    #[test]
+    fn receipt() {
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![Some(receipt(1)), None], vec![Some(receipt(2))]]),
+            1,
+        );
 
-        // detached number should be included so we are adding +1 to it.
-        // for example if block number is same as first_block then
-        // number of detached block shoud be 1.
-        let num_of_detached_block = (block_number - first_block) + 1;
+        assert_eq!(bundle.receipt(1, 0), Some(&receipt(1)));
+        assert_eq!(bundle.receipt(1, 1), None);
+        assert_eq!(bundle.receipt(1, 2), None);
+        assert_eq!(bundle.receipt(2, 0), Some(&receipt(2)));
+        assert_eq!(bundle.receipt(3, 0), None);
+    }
 
-        let mut detached_bundle_state: BundleStateWithReceipts = self.clone();
-        detached_bundle_state.revert_to(block_number);
+    #[test]
+    fn block_receipts_owned() {
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![
+                vec![Some(receipt(1)), Some(receipt(2))],
+                vec![Some(receipt(3)), None],
+                vec![],
+            ]),
+            1,
+        );
 
-        // split is done as [0, num) and [num, len]
-        let (_, this) = self.receipts.split_at(num_of_detached_block as usize);
+        assert_eq!(bundle.block_receipts_owned(1), Some(vec![receipt(1), receipt(2)]));
+        assert_eq!(bundle.block_receipts_owned(2), None);
+        assert_eq!(bundle.block_receipts_owned(3), Some(vec![]));
+        assert_eq!(bundle.block_receipts_owned(4), None);
+    }
 
-        self.receipts = Receipts::from_vec(this.to_vec().clone());
-        self.bundle.take_n_reverts(num_of_detached_block as usize);
+    #[test]
+    fn roots_for_block() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
 
-        self.first_block = block_number + 1;
+        let address = Address::repeat_byte(0x11);
+        let account = |nonce| RethAccount { nonce, ..Default::default() };
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(1)))
+            .add_receipt(1, receipt(1))
+            .add_account_change(2, address, Some(account(1)), Some(account(2)))
+            .add_receipt(2, receipt(2))
+            .build();
 
-        Some(detached_bundle_state)
+        // State root is the one after the last block.
+        let state_root = bundle.state_root_slow(&tx).unwrap();
+        assert_eq!(
+            bundle.roots_for_block(&tx, 1).unwrap(),
+            Some((state_root, calculate_receipt_root_ref(&[&receipt(1)])))
+        );
+        assert_eq!(
+            bundle.roots_for_block(&tx, 2).unwrap(),
+            Some((state_root, calculate_receipt_root_ref(&[&receipt(2)])))
+        );
+        assert_eq!(bundle.roots_for_block(&tx, 3).unwrap(), None);
     }
 
-    /// Extend one state from another
-    ///
-    /// For state this is very sensitive opperation and should be used only when
-    /// we know that other state was build on top of this one.
-    /// In most cases this would be true.
-    pub fn extend(&mut self, other: Self) {
-        self.bundle.extend(other.bundle);
-        self.receipts.extend(other.receipts.receipt_vec);
+    #[test]
+    fn receipts() {
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let blocks = vec![vec![Some(receipt(1)), None], vec![Some(receipt(2))]];
+        let mut bundle =
+            BundleStateWithReceipts::new(BundleState::default(), Receipts::from_vec(blocks), 1);
+        assert_eq!(bundle.receipts(), &Receipts::from_vec(block_receipts(&bundle)));
+
+        bundle.set_block_receipts(2, vec![receipt(3)]).unwrap();
+        bundle.append_receipts(vec![receipt(4)]);
+        assert_eq!(
+            bundle.receipts(),
+            &Receipts::from_vec(vec![
+                vec![Some(receipt(1)), None],
+                vec![Some(receipt(3))],
+                vec![Some(receipt(4))],
+            ])
+        );
     }
 
-    /// Write bundle state to database.
-    ///
-    /// `omit_changed_check` should be set to true of bundle has some of it data
-    /// detached, This would make some original values not known.
-    pub fn write_to_db<'a, TX: DbTxMut<'a> + DbTx<'a>>(
-        self,
-        tx: &TX,
-        is_value_known: OriginalValuesKnown,
-    ) -> Result<(), DatabaseError> {
-        let (plain_state, reverts) = self.bundle.into_plain_state_and_reverts(is_value_known);
+    #[test]
+    fn set_block_receipts() {
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let mut bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![
+                vec![Some(receipt(1)), None],
+                vec![Some(receipt(2))],
+                vec![Some(receipt(3))],
+            ]),
+            1,
+        );
 
-        StateReverts(reverts).write_to_db(tx, self.first_block)?;
+        bundle.set_block_receipts(2, vec![receipt(4), receipt(5)]).unwrap();
+        bundle.set_block_receipts(1, vec![]).unwrap();
+        assert_eq!(
+            block_receipts(&bundle),
+            vec![vec![], vec![Some(receipt(4)), Some(receipt(5))], vec![Some(receipt(3))]]
+        );
+        assert_eq!(
+            bundle.receipts_root_slow(2),
+            Some(calculate_receipt_root_ref(&[&receipt(4), &receipt(5)]))
+        );
 
-        // write receipts
-        let mut bodies_cursor = tx.cursor_read::<tables::BlockBodyIndices>()?;
-        let mut receipts_cursor = tx.cursor_write::<tables::Receipts>()?;
-
-        for (idx, receipts) in self.receipts.into_iter().enumerate() {
-            if !receipts.is_empty() {
-                let (_, body_indices) = bodies_cursor
-                    .seek_exact(self.first_block + idx as u64)?
-                    .expect("body indices exist");
-
-                let first_tx_index = body_indices.first_tx_num();
-                for (tx_idx, receipt) in receipts.into_iter().enumerate() {
-                    if let Some(receipt) = receipt {
-                        receipts_cursor.append(first_tx_index + tx_idx as u64, receipt)?;
-                    }
-                }
-            }
-        }
+        assert_eq!(
+            bundle.set_block_receipts(4, vec![receipt(6)]),
+            Err(OutOfRange { block_number: 4 })
+        );
+        assert_eq!(bundle.len(), 3);
+    }
 
-        StateChanges(plain_state).write_to_db(tx)?;
+    #[test]
+    fn clear() {
+        let (_, mut bundle) = split_bundle();
+        bundle.clear();
+        assert_eq!(bundle.first_block(), 2);
+        assert!(bundle.is_empty());
+        assert_eq!(bundle.reverts_len(), 0);
+        assert_eq!(bundle.account(&SPLIT_ADDRESS), None);
+        assert_eq!(bundle.size_hint(), 0);
+        assert_eq!(bundle, BundleStateWithReceipts { first_block: 2, ..Default::default() });
 
-        Ok(())
+        bundle.append_receipts(vec![Receipt::default()]);
+        bundle.reset_to(10);
+        assert_eq!(bundle, BundleStateWithReceipts { first_block: 10, ..Default::default() });
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::{StateChanges, StateReverts};
-    use crate::{AccountReader, BundleStateWithReceipts, ProviderFactory};
-    use reth_db::{
-        cursor::{DbCursorRO, DbDupCursorRO},
-        models::{AccountBeforeTx, BlockNumberAddress},
-        tables,
-        test_utils::create_test_rw_db,
-        transaction::DbTx,
-        DatabaseEnv,
-    };
-    use reth_primitives::{Address, Receipt, Receipts, StorageEntry, H256, MAINNET, U256};
-    use reth_revm_primitives::{into_reth_acc, primitives::HashMap};
-    use revm::{
-        db::{
-            states::{
-                bundle_state::{BundleRetention, OriginalValuesKnown},
-                changes::PlainStorageRevert,
-                PlainStorageChangeset,
-            },
-            BundleState,
-        },
-        primitives::{Account, AccountInfo as RevmAccountInfo, AccountStatus, StorageSlot},
-        CacheState, DatabaseCommit, State,
-    };
-    use std::sync::Arc;
 
     #[test]
-    fn write_to_db_account_info() {
-        let db: Arc<DatabaseEnv> = create_test_rw_db();
-        let factory = ProviderFactory::new(db, MAINNET.clone());
-        let provider = factory.provider_rw().unwrap();
+    fn retain_accounts() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+        let bundle = |addresses: &[Address]| {
+            let mut builder = BundleStateBuilder::new(1)
+                .add_receipt(1, Receipt::default())
+                .add_receipt(2, Receipt::default());
+            for address in addresses {
+                builder = builder
+                    .add_account_change(1, *address, None, Some(account))
+                    .add_storage_change(2, *address, slot, U256::ZERO, U256::from(1));
+            }
+            builder.build()
+        };
 
-        let address_a = Address::zero();
-        let address_b = Address::repeat_byte(0xff);
+        let mut retained = bundle(&[address_a, address_b]);
+        retained.retain_accounts(|address| address == &address_a);
+        assert_eq!(retained.account(&address_a), Some(Some(account)));
+        assert_eq!(retained.account(&address_b), None);
+        assert_eq!(retained.blocks_with_storage_reverts().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(
+            retained.receipts_view().by_block(2).unwrap_or_default(),
+            &[Some(Receipt::default())]
+        );
 
-        let account_a = RevmAccountInfo { balance: U256::from(1), nonce: 1, ..Default::default() };
-        let account_b = RevmAccountInfo { balance: U256::from(2), nonce: 2, ..Default::default() };
-        let account_b_changed =
-            RevmAccountInfo { balance: U256::from(3), nonce: 3, ..Default::default() };
+        let expected = bundle(&[address_a]);
+        assert_eq!(retained.state(), expected.state());
+        assert_eq!(retained.size_hint(), expected.size_hint());
+        assert_eq!(retained.hash_state_slow(), expected.hash_state_slow());
 
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_not_existing(address_a);
-        cache_state.insert_account(address_b, account_b.clone());
-        let mut state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+        retained.retain_accounts(|_| false);
+        assert_eq!(retained.account_count(), 0);
+        assert_eq!(retained.len(), 2);
+        assert!(!retained.has_reverts());
+    }
 
-        // 0x00.. is created
-        state.commit(HashMap::from([(
-            address_a,
-            Account {
-                info: account_a.clone(),
-                status: AccountStatus::Touched | AccountStatus::Created,
-                storage: HashMap::default(),
-            },
-        )]));
+    #[test]
+    fn shrink_to_fit() {
+        let address = Address::repeat_byte(0x11);
+        let mut bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(RethAccount::default()))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_receipt(1, Receipt::default())
+            .add_receipt(2, Receipt::default())
+            .add_receipt(3, Receipt::default())
+            .build();
+        bundle.revert_to(1);
+        let expected = bundle.clone();
+        let size_hint = bundle.size_hint();
 
-        // 0xff.. is changed (balance + 1, nonce + 1)
-        state.commit(HashMap::from([(
-            address_b,
-            Account {
-                info: account_b_changed.clone(),
-                status: AccountStatus::Touched,
-                storage: HashMap::default(),
-            },
-        )]));
+        bundle.shrink_to_fit();
+        assert_eq!(bundle, expected);
+        assert_eq!(bundle.size_hint(), size_hint);
+    }
 
-        state.merge_transitions(BundleRetention::Reverts);
-        let mut revm_bundle_state = state.take_bundle();
+    #[test]
+    fn reverts_report() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(account))
+            .add_account_change(1, address_b, None, Some(account))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(2), U256::ZERO, U256::from(2))
+            .add_receipt(2, Receipt::default())
+            .build();
 
-        // Write plain state and reverts separately.
-        let reverts = revm_bundle_state.take_all_reverts().into_plain_state_reverts();
-        let plain_state = revm_bundle_state.into_plain_state(OriginalValuesKnown::Yes);
-        assert!(plain_state.storage.is_empty());
-        assert!(plain_state.contracts.is_empty());
-        StateChanges(plain_state)
-            .write_to_db(provider.tx_ref())
-            .expect("Could not write plain state to DB");
+        assert_eq!(
+            bundle.reverts_report(),
+            vec![
+                BlockRevertStats {
+                    block_number: 1,
+                    account_reverts: 2,
+                    storage_reverts: 2,
+                    wiped_accounts: 0
+                },
+                BlockRevertStats { block_number: 2, ..Default::default() },
+            ]
+        );
+        assert!(BundleStateWithReceipts::default().reverts_report().is_empty());
+    }
 
-        assert_eq!(reverts.storage, [[]]);
-        StateReverts(reverts)
-            .write_to_db(provider.tx_ref(), 1)
-            .expect("Could not write reverts to DB");
+    #[test]
+    fn blocks_with_reverts() {
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(2, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_receipt(3, Receipt::default())
+            .build();
 
-        let reth_account_a = into_reth_acc(account_a);
-        let reth_account_b = into_reth_acc(account_b);
-        let reth_account_b_changed = into_reth_acc(account_b_changed.clone());
+        assert_eq!(bundle.blocks_with_account_reverts().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(bundle.blocks_with_storage_reverts().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(BundleStateWithReceipts::default().blocks_with_account_reverts().count(), 0);
+    }
+
+    #[test]
+    fn to_state_diff_json() {
+        let address = Address::repeat_byte(0x11);
+        let key = "0x1111111111111111111111111111111111111111";
+        let account = |nonce| RethAccount { nonce, balance: U256::from(10), bytecode_hash: None };
+        let slot = H256::from_low_u64_be(1);
+        let word = |value| format!("{:?}", H256::from_low_u64_be(value));
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(1)))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .add_account_change(2, address, Some(account(1)), Some(account(2)))
+            .add_storage_change(2, address, slot, U256::from(1), U256::from(2))
+            .add_storage_change(3, address, slot, U256::from(2), U256::from(3))
+            .build();
 
-        // Check plain state
-        assert_eq!(
-            provider.basic_account(address_a).expect("Could not read account state"),
-            Some(reth_account_a),
-            "Account A state is wrong"
-        );
         assert_eq!(
-            provider.basic_account(address_b).expect("Could not read account state"),
-            Some(reth_account_b_changed),
-            "Account B state is wrong"
+            bundle.to_state_diff_json(1),
+            serde_json::json!({
+                "pre": {},
+                "post": { key: { "balance": "0xa", "nonce": 1, "storage": { word(1): word(1) } } }
+            })
         );
-
-        // Check change set
-        let mut changeset_cursor = provider
-            .tx_ref()
-            .cursor_dup_read::<tables::AccountChangeSet>()
-            .expect("Could not open changeset cursor");
         assert_eq!(
-            changeset_cursor.seek_exact(1).expect("Could not read account change set"),
-            Some((1, AccountBeforeTx { address: address_a, info: None })),
-            "Account A changeset is wrong"
+            bundle.to_state_diff_json(2),
+            serde_json::json!({
+                "pre": { key: { "balance": "0xa", "nonce": 1, "storage": { word(1): word(1) } } },
+                "post": { key: { "balance": "0xa", "nonce": 2, "storage": { word(1): word(2) } } }
+            })
         );
+        // Account info did not change in the last block.
         assert_eq!(
-            changeset_cursor.next_dup().expect("Changeset table is malformed"),
-            Some((1, AccountBeforeTx { address: address_b, info: Some(reth_account_b) })),
-            "Account B changeset is wrong"
+            bundle.to_state_diff_json(3),
+            serde_json::json!({
+                "pre": { key: { "balance": "0xa", "nonce": 2, "storage": { word(1): word(2) } } },
+                "post": { key: { "balance": "0xa", "nonce": 2, "storage": { word(1): word(3) } } }
+            })
         );
+        assert_eq!(bundle.to_state_diff_json(4), serde_json::Value::Null);
+    }
 
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_account(address_b, account_b_changed.clone());
-        let mut state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+    #[test]
+    fn has_reverts() {
+        let (lower, upper) = split_bundle();
+        assert!(lower.has_reverts());
+        assert!(!BundleStateWithReceipts::default().has_reverts());
+        assert!(!BundleStateBuilder::new(1)
+            .add_receipt(2, Receipt::default())
+            .build()
+            .has_reverts());
 
-        // 0xff.. is destroyed
-        state.commit(HashMap::from([(
-            address_b,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
-                info: account_b_changed,
-                storage: HashMap::default(),
-            },
-        )]));
+        let mut full = lower;
+        full.extend(upper);
+        full.detach_lower_reverts_keep_receipts(2).unwrap();
+        assert_eq!(full.len(), 2);
+        assert!(!full.has_reverts());
+    }
 
-        state.merge_transitions(BundleRetention::Reverts);
-        let mut revm_bundle_state = state.take_bundle();
+    #[test]
+    fn extend_contiguous() {
+        let (lower, upper) = split_bundle();
+        let mut empty = BundleStateWithReceipts::default();
+        empty.extend(upper.clone());
+        assert_eq!(block_receipts(&empty), block_receipts(&upper));
 
-        // Write plain state and reverts separately.
-        let reverts = revm_bundle_state.take_all_reverts().into_plain_state_reverts();
-        let plain_state = revm_bundle_state.into_plain_state(OriginalValuesKnown::Yes);
-        // Account B selfdestructed so flag for it should be present.
+        let mut full = lower;
+        full.extend(BundleStateWithReceipts::default());
+        full.extend(upper);
+        assert_eq!((full.first_block(), full.len()), (1, 2));
+    }
+
+    #[test]
+    fn merge_shared_base() {
+        let address = Address::repeat_byte(0x11);
+        let account = |nonce| RethAccount { nonce, ..Default::default() };
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let base = || {
+            BundleStateBuilder::new(1)
+                .add_account_change(1, address, None, Some(account(1)))
+                .add_receipt(1, receipt(1))
+        };
+        let fork = base()
+            .add_account_change(2, address, Some(account(1)), Some(account(2)))
+            .add_receipt(2, receipt(2))
+            .build();
+
+        let mut merged = base().build();
+        merged.merge_shared_base(fork.clone(), 1).unwrap();
+        assert_eq!((merged.first_block(), merged.len(), merged.reverts_len()), (1, 2, 2));
+        assert_eq!(merged.account(&address), Some(Some(account(2))));
+        assert_eq!(block_receipts(&merged), block_receipts(&fork));
+
+        // Nothing to append if the other bundle ends at the shared block.
+        let mut merged = base().build();
+        merged.merge_shared_base(base().build(), 1).unwrap();
+        assert_eq!(merged, base().build());
+
+        let mut merged = base().build();
         assert_eq!(
-            plain_state.storage,
-            [PlainStorageChangeset { address: address_b, wipe_storage: true, storage: vec![] }]
+            merged.merge_shared_base(fork.clone(), 2),
+            Err(MergeError::NotShared { shared_up_to: 2, first_block: 1, other_first_block: 1 })
+        );
+        assert_matches!(
+            merged.merge_shared_base(fork.clone(), 0),
+            Err(MergeError::NotShared { .. })
+        );
+
+        let different_receipts = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(1)))
+            .add_receipt(1, receipt(3))
+            .build();
+        assert_matches!(
+            different_receipts.clone().merge_shared_base(fork.clone(), 1),
+            Err(MergeError::Receipts(mismatch)) if mismatch.block_number == 1
         );
-        assert!(plain_state.contracts.is_empty());
-        StateChanges(plain_state)
-            .write_to_db(provider.tx_ref())
-            .expect("Could not write plain state to DB");
 
+        let different_reverts = BundleStateBuilder::new(1)
+            .add_account_change(1, address, Some(account(3)), Some(account(1)))
+            .add_receipt(1, receipt(1))
+            .build();
         assert_eq!(
-            reverts.storage,
-            [[PlainStorageRevert { address: address_b, wiped: true, storage_revert: vec![] }]]
+            different_reverts.clone().merge_shared_base(fork.clone(), 1),
+            Err(MergeError::Reverts(1))
         );
-        StateReverts(reverts)
-            .write_to_db(provider.tx_ref(), 2)
-            .expect("Could not write reverts to DB");
 
-        // Check new plain state for account B
+        // Same reverts and receipts, but a different value after the shared block.
+        let different_state = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account(3)))
+            .add_receipt(1, receipt(1))
+            .build();
+        let mut merged = different_state.clone();
+        assert_eq!(merged.merge_shared_base(fork, 1), Err(MergeError::Base(address)));
+        assert_eq!(merged, different_state);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "extending bundle ending before block 3")]
+    fn extend_twice() {
+        let (lower, upper) = split_bundle();
+        let mut full = lower;
+        full.extend(upper.clone());
+        full.extend(upper);
+    }
+
+    #[test]
+    fn reverts_len() {
+        let (lower, upper) = split_bundle();
+        assert_eq!((lower.reverts_len(), upper.reverts_len()), (1, 1));
+
+        let mut full = lower.clone();
+        full.extend(upper);
+        assert_eq!(full.reverts_len(), 2);
+        full.revert_to(1);
+        assert_eq!(full.reverts_len(), 1);
+        assert_eq!(BundleStateWithReceipts::default().reverts_len(), 0);
+    }
+
+    #[test]
+    fn check_block_alignment() {
+        let bundle = BundleStateBuilder::new(1).add_receipt(2, Receipt::default()).build();
+        assert_eq!(bundle.check_block_alignment(), Ok(()));
+
+        // Bundle without receipts.
+        let without_receipts =
+            BundleStateWithReceipts::new(bundle.state().clone(), Receipts::new(), 1);
+        assert_eq!(without_receipts.check_block_alignment(), Ok(()));
+
+        let misaligned = BundleStateWithReceipts::new(
+            bundle.state().clone(),
+            Receipts::from_vec(vec![vec![]; 3]),
+            1,
+        );
         assert_eq!(
-            provider.basic_account(address_b).expect("Could not read account state"),
-            None,
-            "Account B should be deleted"
+            misaligned.check_block_alignment(),
+            Err(AlignmentError { receipts: 3, reverts: 2 })
         );
 
-        // Check change set
+        // Bundle with receipts, but without reverts.
+        let without_reverts = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![]; 2]),
+            1,
+        );
         assert_eq!(
-            changeset_cursor.seek_exact(2).expect("Could not read account change set"),
-            Some((2, AccountBeforeTx { address: address_b, info: Some(reth_account_b_changed) })),
-            "Account B changeset is wrong after deletion"
+            without_reverts.check_block_alignment(),
+            Err(AlignmentError { receipts: 2, reverts: 0 })
         );
     }
 
     #[test]
-    fn write_to_db_storage() {
+    fn write_to_db_misaligned() {
         let db: Arc<DatabaseEnv> = create_test_rw_db();
         let factory = ProviderFactory::new(db, MAINNET.clone());
         let provider = factory.provider_rw().unwrap();
 
-        let address_a = Address::zero();
-        let address_b = Address::repeat_byte(0xff);
-
-        let account_b = RevmAccountInfo { balance: U256::from(2), nonce: 2, ..Default::default() };
+        let (lower, _) = split_bundle();
+        let misaligned = || {
+            BundleStateWithReceipts::new(
+                lower.state().clone(),
+                Receipts::from_vec(vec![vec![]; 2]),
+                1,
+            )
+        };
+        let err = misaligned()
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::No, WriteTables::default())
+            .unwrap_err();
+        assert_eq!(err, BundleWriteError::Misaligned { receipt_blocks: 2, revert_blocks: 1 });
+        assert_eq!(err.clone().into_inner(), None);
+        assert!(matches!(RethError::from(err), RethError::Custom(_)));
+        // Nothing was written.
+        assert_eq!(provider.tx_ref().entries::<tables::PlainAccountState>().unwrap(), 0);
+        assert_eq!(provider.tx_ref().entries::<tables::AccountChangeSet>().unwrap(), 0);
 
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_not_existing(address_a);
-        cache_state.insert_account_with_storage(
-            address_b,
-            account_b.clone(),
-            HashMap::from([(U256::from(1), U256::from(1))]),
+        // Receipts without reverts.
+        let without_reverts = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![Some(Receipt::default())]]),
+            1,
         );
-        let mut state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
-
-        state.commit(HashMap::from([
-            (
-                address_a,
-                Account {
-                    status: AccountStatus::Touched | AccountStatus::Created,
-                    info: RevmAccountInfo::default(),
-                    // 0x00 => 0 => 1
-                    // 0x01 => 0 => 2
-                    storage: HashMap::from([
-                        (
-                            U256::from(0),
-                            StorageSlot { present_value: U256::from(1), ..Default::default() },
-                        ),
-                        (
-                            U256::from(1),
-                            StorageSlot { present_value: U256::from(2), ..Default::default() },
-                        ),
-                    ]),
-                },
-            ),
-            (
-                address_b,
-                Account {
-                    status: AccountStatus::Touched,
-                    info: account_b,
-                    // 0x01 => 1 => 2
-                    storage: HashMap::from([(
-                        U256::from(1),
-                        StorageSlot {
-                            present_value: U256::from(2),
-                            previous_or_original_value: U256::from(1),
-                        },
-                    )]),
-                },
+        assert_eq!(
+            without_reverts.write_to_db(
+                provider.tx_ref(),
+                OriginalValuesKnown::No,
+                WriteTables::default()
             ),
-        ]));
+            Err(BundleWriteError::Misaligned { receipt_blocks: 1, revert_blocks: 0 })
+        );
+        assert_eq!(provider.tx_ref().entries::<tables::Receipts>().unwrap(), 0);
 
-        state.merge_transitions(BundleRetention::Reverts);
+        // Changesets are not written, so the reverts can't be misaligned.
+        misaligned()
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::No, WriteTables::PLAIN_STATE)
+            .unwrap();
+        assert_eq!(provider.tx_ref().entries::<tables::PlainAccountState>().unwrap(), 1);
+    }
 
-        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 1)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
-            .expect("Could not write bundle state to DB");
+    #[test]
+    fn content_hash() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let receipt = Receipt { cumulative_gas_used: 21_000, ..Default::default() };
+        let build = |first_block, receipt: Receipt| {
+            BundleStateBuilder::new(first_block)
+                .add_account_change(first_block, address_a, None, Some(account))
+                .add_account_change(first_block, address_b, None, Some(account))
+                .add_storage_change(
+                    first_block,
+                    address_a,
+                    H256::from_low_u64_be(1),
+                    U256::ZERO,
+                    U256::from(1),
+                )
+                .add_receipt(first_block, receipt)
+                .build()
+        };
 
-        // Check plain storage state
-        let mut storage_cursor = provider
-            .tx_ref()
-            .cursor_dup_read::<tables::PlainStorageState>()
-            .expect("Could not open plain storage state cursor");
+        let bundle = build(1, receipt.clone());
+        // Maps of the bundle are not ordered, equal bundles built separately hash the same.
+        assert_eq!(bundle.content_hash(), build(1, receipt.clone()).content_hash());
+        assert_eq!(bundle.content_hash(), bundle.clone().content_hash());
 
-        assert_eq!(
-            storage_cursor.seek_exact(address_a).unwrap(),
-            Some((address_a, StorageEntry { key: H256::zero(), value: U256::from(1) })),
-            "Slot 0 for account A should be 1"
+        assert_ne!(bundle.content_hash(), build(2, receipt.clone()).content_hash());
+        let other_receipt = Receipt { cumulative_gas_used: 42_000, ..receipt };
+        assert_ne!(bundle.content_hash(), build(1, other_receipt).content_hash());
+        assert_ne!(
+            bundle.content_hash(),
+            BundleStateBuilder::new(1)
+                .add_account_change(1, address_a, None, Some(account))
+                .add_receipt(1, Receipt { cumulative_gas_used: 21_000, ..Default::default() })
+                .build()
+                .content_hash()
         );
+    }
+
+    #[test]
+    fn encode_changesets_rlp() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount {
+            nonce: 1,
+            balance: U256::from(10),
+            bytecode_hash: Some(H256::repeat_byte(0xaa)),
+        };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_b, Some(RethAccount::default()), None)
+            .add_account_change(1, address_a, None, Some(account))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(2), U256::ZERO, U256::from(2))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(1), U256::from(1), U256::ZERO)
+            .build();
+
+        let changesets = bundle.changesets();
         assert_eq!(
-            storage_cursor.next_dup().unwrap(),
-            Some((
-                address_a,
-                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
-            )),
-            "Slot 1 for account A should be 2"
+            changesets,
+            BundleChangesets {
+                accounts: vec![
+                    AccountChange::new(address_a, Some(account)),
+                    AccountChange::new(address_b, None),
+                ],
+                storage: vec![StorageChange {
+                    address: address_a,
+                    wipe_storage: false,
+                    slots: vec![
+                        StorageSlotChange { key: H256::from_low_u64_be(1), value: U256::ZERO },
+                        StorageSlotChange { key: H256::from_low_u64_be(2), value: U256::from(2) },
+                    ],
+                }],
+            }
         );
+        assert_eq!(changesets.accounts[0].account(), Some(account));
+        assert_eq!(changesets.accounts[1].account(), None);
+
+        let encoded = bundle.encode_changesets_rlp();
+        assert_eq!(BundleStateWithReceipts::decode_changesets_rlp(&encoded), Ok(changesets));
+
+        let mut trailing = encoded;
+        trailing.push(0);
+        assert!(BundleStateWithReceipts::decode_changesets_rlp(&trailing).is_err());
+    }
+
+    #[test]
+    fn partial_state_root() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
+
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+        let bundle_a = || {
+            BundleStateBuilder::new(1)
+                .add_account_change(1, address_a, None, Some(account))
+                .add_storage_change(1, address_a, slot, U256::ZERO, U256::from(1))
+        };
+        let bundle = bundle_a().add_account_change(1, address_b, None, Some(account)).build();
+
         assert_eq!(
-            storage_cursor.next_dup().unwrap(),
-            None,
-            "Account A should only have 2 storage slots"
+            bundle.partial_state_root(&tx, &[address_a, Address::zero()]).unwrap(),
+            bundle_a().build().state_root_slow(&tx).unwrap()
         );
-
         assert_eq!(
-            storage_cursor.seek_exact(address_b).unwrap(),
-            Some((
-                address_b,
-                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
-            )),
-            "Slot 1 for account B should be 2"
+            bundle.partial_state_root(&tx, &[address_b, address_a]).unwrap(),
+            bundle.state_root_slow(&tx).unwrap()
         );
         assert_eq!(
-            storage_cursor.next_dup().unwrap(),
-            None,
-            "Account B should only have 1 storage slot"
+            bundle.partial_state_root(&tx, &[]).unwrap(),
+            BundleStateWithReceipts::default().state_root_slow(&tx).unwrap()
         );
+    }
 
-        // Check change set
-        let mut changeset_cursor = provider
-            .tx_ref()
-            .cursor_dup_read::<tables::StorageChangeSet>()
-            .expect("Could not open storage changeset cursor");
-        assert_eq!(
-            changeset_cursor.seek_exact(BlockNumberAddress((1, address_a))).unwrap(),
-            Some((
-                BlockNumberAddress((1, address_a)),
-                StorageEntry { key: H256::zero(), value: U256::from(0) }
-            )),
-            "Slot 0 for account A should have changed from 0"
+    #[test]
+    fn receipts_encoded_size() {
+        let receipt = Receipt {
+            cumulative_gas_used: 21_000,
+            logs: vec![Log { address: Address::zero(), topics: vec![], data: Default::default() }],
+            ..Default::default()
+        };
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![
+                vec![Some(receipt.clone()), None],
+                vec![Some(receipt.clone())],
+            ]),
+            1,
         );
+
+        assert_eq!(bundle.receipts_encoded_size(), 2 * receipt.compress().as_ref().len());
+        assert_eq!(BundleStateWithReceipts::default().receipts_encoded_size(), 0);
+    }
+
+    #[test]
+    fn write_to_db_verified() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle =
+            BundleStateBuilder::new(1).add_account_change(1, address, None, Some(account)).build();
+        let root = bundle.state_root_slow(provider.tx_ref()).unwrap();
+
         assert_eq!(
-            changeset_cursor.next_dup().unwrap(),
-            Some((
-                BlockNumberAddress((1, address_a)),
-                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(0) }
-            )),
-            "Slot 1 for account A should have changed from 0"
+            bundle.clone().write_to_db_verified(provider.tx_ref(), H256::zero()),
+            Err(VerifiedWriteError::StateRootMismatch { expected: H256::zero(), got: root })
         );
-        assert_eq!(
-            changeset_cursor.next_dup().unwrap(),
-            None,
-            "Account A should only be in the changeset 2 times"
+        assert_eq!(provider.basic_account(address).unwrap(), None);
+
+        bundle.write_to_db_verified(provider.tx_ref(), root).unwrap();
+        assert_eq!(provider.basic_account(address).unwrap(), Some(account));
+    }
+
+    #[test]
+    fn receipts_rev() {
+        let receipt = |gas| Some(Receipt { cumulative_gas_used: gas, ..Default::default() });
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![receipt(1)], vec![], vec![receipt(3), receipt(4)]]),
+            10,
         );
 
         assert_eq!(
-            changeset_cursor.seek_exact(BlockNumberAddress((1, address_b))).unwrap(),
-            Some((
-                BlockNumberAddress((1, address_b)),
-                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(1) }
-            )),
-            "Slot 1 for account B should have changed from 1"
+            bundle.receipts_rev().collect::<Vec<_>>(),
+            vec![(12, &[receipt(3), receipt(4)][..]), (11, &[][..]), (10, &[receipt(1)][..])]
         );
+        assert_eq!(BundleStateWithReceipts::default().receipts_rev().count(), 0);
+    }
+
+    #[test]
+    fn receipts_equal() {
+        let receipt = |gas| Receipt { cumulative_gas_used: gas, ..Default::default() };
+        let bundle = |receipts: Vec<Vec<Option<Receipt>>>| {
+            BundleStateWithReceipts::new(BundleState::default(), Receipts::from_vec(receipts), 1)
+        };
+        let base = bundle(vec![vec![Some(receipt(1))], vec![Some(receipt(2)), None]]);
+
+        assert_eq!(base.receipts_equal(&base.clone()), Ok(()));
+
+        let other = bundle(vec![
+            vec![Some(receipt(1))],
+            vec![Some(Receipt { success: true, ..receipt(3) }), None],
+        ]);
         assert_eq!(
-            changeset_cursor.next_dup().unwrap(),
-            None,
-            "Account B should only be in the changeset 1 time"
+            base.receipts_equal(&other),
+            Err(ReceiptMismatch {
+                block_number: 2,
+                tx_index: 0,
+                left: Some(receipt(2)),
+                right: Some(Receipt { success: true, ..receipt(3) }),
+                fields: vec!["success", "cumulative_gas_used"],
+            })
         );
 
-        // Delete account A
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_account(address_a, RevmAccountInfo::default());
-        let mut state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+        // Pruned receipt differs from a present one.
+        let other = bundle(vec![vec![Some(receipt(1))], vec![Some(receipt(2)), Some(receipt(3))]]);
+        let mismatch = base.receipts_equal(&other).unwrap_err();
+        assert_eq!((mismatch.block_number, mismatch.tx_index), (2, 1));
+        assert_eq!((mismatch.left, mismatch.fields), (None, vec![]));
 
-        state.commit(HashMap::from([(
-            address_a,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
-                info: RevmAccountInfo::default(),
-                storage: HashMap::default(),
-            },
-        )]));
+        // Missing block.
+        let other = bundle(vec![vec![Some(receipt(1))]]);
+        let mismatch = base.receipts_equal(&other).unwrap_err();
+        assert_eq!((mismatch.block_number, mismatch.tx_index), (2, 0));
+        assert_eq!(mismatch.right, None);
+    }
 
-        state.merge_transitions(BundleRetention::Reverts);
-        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 2)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
-            .expect("Could not write bundle state to DB");
+    #[test]
+    fn changeset_row_counts() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(account))
+            .add_account_change(2, address_b, None, Some(account))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_storage_change(2, address_a, H256::from_low_u64_be(1), U256::from(1), U256::ZERO)
+            .add_storage_change(2, address_a, H256::from_low_u64_be(2), U256::ZERO, U256::from(2))
+            .build();
 
-        assert_eq!(
-            storage_cursor.seek_exact(address_a).unwrap(),
-            None,
-            "Account A should have no storage slots after deletion"
-        );
+        assert_eq!(bundle.changeset_row_counts(), (2, 3));
+        assert_eq!(BundleStateWithReceipts::default().changeset_row_counts(), (0, 0));
+    }
+
+    #[test]
+    fn touched_prefix_sets() {
+        let address = Address::repeat_byte(0x11);
+        let slot = H256::from_low_u64_be(1);
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(RethAccount::default()))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .build();
+
+        let (mut account_prefix_set, mut storage_prefix_sets) = bundle.touched_prefix_sets();
+        let hashed_address = keccak256(address);
+        assert!(account_prefix_set.contains(Nibbles::unpack(hashed_address)));
+        assert!(!account_prefix_set.contains(Nibbles::unpack(keccak256(Address::zero()))));
+        assert_eq!(storage_prefix_sets.len(), 1);
+        let storage_prefix_set = storage_prefix_sets.get_mut(&hashed_address).unwrap();
+        assert!(storage_prefix_set.contains(Nibbles::unpack(keccak256(slot))));
+    }
+
+    #[test]
+    fn block_prefix_sets() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let slot_a = H256::from_low_u64_be(1);
+        let slot_b = H256::from_low_u64_be(2);
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(RethAccount::default()))
+            .add_storage_change(1, address_a, slot_a, U256::ZERO, U256::from(1))
+            .add_account_change(2, address_b, None, Some(RethAccount::default()))
+            .add_storage_change(2, address_b, slot_b, U256::ZERO, U256::from(1))
+            .build();
+
+        let (mut account_prefix_set, mut storage_prefix_sets) =
+            bundle.block_prefix_sets(2).unwrap();
+        assert!(account_prefix_set.contains(Nibbles::unpack(keccak256(address_b))));
+        assert!(!account_prefix_set.contains(Nibbles::unpack(keccak256(address_a))));
+        assert_eq!(storage_prefix_sets.len(), 1);
+        let storage_prefix_set = storage_prefix_sets.get_mut(&keccak256(address_b)).unwrap();
+        assert!(storage_prefix_set.contains(Nibbles::unpack(keccak256(slot_b))));
+        assert!(!storage_prefix_set.contains(Nibbles::unpack(keccak256(slot_a))));
+
+        assert!(bundle.block_prefix_sets(0).is_none());
+        assert!(bundle.block_prefix_sets(3).is_none());
+    }
 
+    #[test]
+    fn bytecodes_iter() {
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        let other_bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x01].into()));
+        let bundle = BundleStateBuilder::new(1)
+            .add_contract(H256::repeat_byte(1), bytecode.clone())
+            .add_contract(H256::repeat_byte(2), other_bytecode.clone())
+            .build();
+
+        let mut bytecodes = bundle.bytecodes_iter().collect::<Vec<_>>();
+        bytecodes.sort_by_key(|(code_hash, _)| *code_hash);
         assert_eq!(
-            changeset_cursor.seek_exact(BlockNumberAddress((2, address_a))).unwrap(),
-            Some((
-                BlockNumberAddress((2, address_a)),
-                StorageEntry { key: H256::zero(), value: U256::from(1) }
-            )),
-            "Slot 0 for account A should have changed from 1 on deletion"
+            bytecodes,
+            vec![(H256::repeat_byte(1), bytecode), (H256::repeat_byte(2), other_bytecode)]
         );
-        assert_eq!(
-            changeset_cursor.next_dup().unwrap(),
-            Some((
-                BlockNumberAddress((2, address_a)),
-                StorageEntry { key: H256::from(U256::from(1).to_be_bytes()), value: U256::from(2) }
-            )),
-            "Slot 1 for account A should have changed from 2 on deletion"
+        assert!(BundleStateWithReceipts::default().bytecodes_iter().next().is_none());
+    }
+
+    #[test]
+    fn state_changes_sort() {
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+        let bytecode = RevmBytecode::new_raw(vec![0x60, 0x00].into());
+        let slots = |keys: &[u64]| -> Vec<(U256, U256)> {
+            keys.iter().map(|key| (U256::from(*key), U256::from(1))).collect()
+        };
+
+        let mut changes = StateChanges(StateChangeset {
+            accounts: vec![(address_b, None), (address_a, Some(RevmAccountInfo::default()))],
+            storage: vec![
+                PlainStorageChangeset {
+                    address: address_b,
+                    wipe_storage: false,
+                    storage: slots(&[3, 1, 2]),
+                },
+                PlainStorageChangeset {
+                    address: address_a,
+                    wipe_storage: true,
+                    storage: slots(&[256, 1]),
+                },
+            ],
+            contracts: vec![
+                (H256::repeat_byte(2), bytecode.clone()),
+                (H256::repeat_byte(1), bytecode),
+            ],
+        });
+        changes.sort();
+
+        let accounts = changes.0.accounts.iter().map(|(address, _)| *address).collect::<Vec<_>>();
+        assert_eq!(accounts, vec![address_a, address_b]);
+        let storage = changes
+            .0
+            .storage
+            .iter()
+            .map(|changeset| (changeset.address, changeset.storage.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(storage, vec![(address_a, slots(&[1, 256])), (address_b, slots(&[1, 2, 3]))]);
+        let contracts = changes.0.contracts.iter().map(|(hash, _)| *hash).collect::<Vec<_>>();
+        assert_eq!(contracts, vec![H256::repeat_byte(1), H256::repeat_byte(2)]);
+    }
+
+    #[test]
+    fn plain_state_ops() {
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+        let account = RevmAccountInfo { nonce: 1, ..Default::default() };
+        let mut changes = StateChanges(StateChangeset {
+            accounts: vec![(address_b, None), (address_a, Some(account.clone()))],
+            storage: vec![
+                PlainStorageChangeset {
+                    address: address_b,
+                    wipe_storage: true,
+                    storage: vec![(U256::from(1), U256::from(1))],
+                },
+                PlainStorageChangeset {
+                    address: address_a,
+                    wipe_storage: false,
+                    storage: vec![(U256::from(2), U256::ZERO), (U256::from(1), U256::from(1))],
+                },
+            ],
+            contracts: vec![],
+        });
+        changes.sort();
+
+        assert_eq!(
+            changes.account_ops().collect::<Vec<_>>(),
+            vec![
+                PlainStateOp::Upsert(address_a, into_reth_acc(account)),
+                PlainStateOp::Delete(address_b)
+            ]
         );
+        let entry = |key| StorageEntry { key: H256::from_low_u64_be(key), value: U256::from(1) };
         assert_eq!(
-            changeset_cursor.next_dup().unwrap(),
-            None,
-            "Account A should only be in the changeset 2 times on deletion"
+            changes.storage_ops().collect::<Vec<_>>(),
+            vec![
+                PlainStorageOp::Upsert(address_a, entry(1)),
+                PlainStorageOp::Delete(address_a, H256::from_low_u64_be(2)),
+                PlainStorageOp::Wipe(address_b),
+                PlainStorageOp::Upsert(address_b, entry(1)),
+            ]
+        );
+        assert_eq!(changes.plain_state_delete_counts(), (1, 1));
+    }
+
+    #[test]
+    fn write_to_db_append() {
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0x11);
+        let address_c = Address::repeat_byte(0xff);
+        let account = RevmAccountInfo { nonce: 1, ..Default::default() };
+        let changes = |accounts, storage: Vec<(Address, Vec<(u64, u64)>)>| {
+            StateChanges(StateChangeset {
+                accounts,
+                storage: storage
+                    .into_iter()
+                    .map(|(address, slots)| PlainStorageChangeset {
+                        address,
+                        wipe_storage: false,
+                        storage: slots
+                            .into_iter()
+                            .map(|(key, value)| (U256::from(key), U256::from(value)))
+                            .collect(),
+                    })
+                    .collect(),
+                contracts: vec![],
+            })
+        };
+        fn plain_state<'a>(
+            tx: &impl DbTx<'a>,
+        ) -> (Vec<(Address, RethAccount)>, Vec<(Address, StorageEntry)>) {
+            let accounts = tx
+                .cursor_read::<tables::PlainAccountState>()
+                .unwrap()
+                .walk(None)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let storage = tx
+                .cursor_read::<tables::PlainStorageState>()
+                .unwrap()
+                .walk(None)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            (accounts, storage)
+        }
+        let initial = || {
+            changes(
+                vec![
+                    (address_c, None),
+                    (address_b, Some(account.clone())),
+                    (address_a, Some(account.clone())),
+                ],
+                vec![(address_b, vec![(2, 2), (1, 1), (3, 0)]), (address_a, vec![(1, 1)])],
+            )
+        };
+
+        let upserted_db: Arc<DatabaseEnv> = create_test_rw_db();
+        let upserted_factory = ProviderFactory::new(upserted_db, MAINNET.clone());
+        let upserted = upserted_factory.provider_rw().unwrap();
+        initial().write_to_db(upserted.tx_ref()).unwrap();
+
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        initial().write_to_db_append(provider.tx_ref()).unwrap();
+        assert_eq!(plain_state(provider.tx_ref()), plain_state(upserted.tx_ref()));
+        assert_eq!(plain_state(provider.tx_ref()).1.len(), 3);
+
+        // Plain state is not empty, existing entries are updated.
+        changes(vec![(address_a, None)], vec![(address_b, vec![(1, 0), (2, 3)])])
+            .write_to_db_append(provider.tx_ref())
+            .unwrap();
+        assert_eq!(
+            plain_state(provider.tx_ref()),
+            (
+                vec![(address_b, into_reth_acc(account))],
+                vec![
+                    (
+                        address_a,
+                        StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(1) }
+                    ),
+                    (
+                        address_b,
+                        StorageEntry { key: H256::from_low_u64_be(2), value: U256::from(3) }
+                    ),
+                ]
+            )
         );
     }
 
@@ -773,7 +5032,7 @@ mod tests {
         )]));
         init_state.merge_transitions(BundleRetention::Reverts);
         BundleStateWithReceipts::new(init_state.take_bundle(), Receipts::new(), 0)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
             .expect("Could not write init bundle state to DB");
 
         let mut cache_state = CacheState::new(true);
@@ -844,328 +5103,1086 @@ mod tests {
                         StorageSlot { present_value: U256::from(4), ..Default::default() },
                     ),
                     (
-                        U256::from(6),
-                        StorageSlot { present_value: U256::from(6), ..Default::default() },
+                        U256::from(6),
+                        StorageSlot { present_value: U256::from(6), ..Default::default() },
+                    ),
+                ]),
+            },
+        )]));
+        state.merge_transitions(BundleRetention::Reverts);
+
+        // Block #5: Destroy account again.
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
+                info: account_info.clone(),
+                storage: HashMap::default(),
+            },
+        )]));
+        state.merge_transitions(BundleRetention::Reverts);
+
+        // Block #6: Create, change, destroy and re-create in the same block.
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::Created,
+                info: account_info.clone(),
+                storage: HashMap::default(),
+            },
+        )]));
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched,
+                info: account_info.clone(),
+                // 0x00 => 0 => 2
+                storage: HashMap::from([(
+                    U256::ZERO,
+                    StorageSlot { present_value: U256::from(2), ..Default::default() },
+                )]),
+            },
+        )]));
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
+                info: account_info.clone(),
+                storage: HashMap::default(),
+            },
+        )]));
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched | AccountStatus::Created,
+                info: account_info.clone(),
+                storage: HashMap::default(),
+            },
+        )]));
+        state.merge_transitions(BundleRetention::Reverts);
+
+        // Block #7: Change storage.
+        state.commit(HashMap::from([(
+            address1,
+            Account {
+                status: AccountStatus::Touched,
+                info: account_info.clone(),
+                // 0x00 => 0 => 9
+                storage: HashMap::from([(
+                    U256::ZERO,
+                    StorageSlot { present_value: U256::from(9), ..Default::default() },
+                )]),
+            },
+        )]));
+        state.merge_transitions(BundleRetention::Reverts);
+
+        let bundle = state.take_bundle();
+
+        BundleStateWithReceipts::new(bundle, Receipts::new(), 1)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write bundle state to DB");
+
+        let mut storage_changeset_cursor = provider
+            .tx_ref()
+            .cursor_dup_read::<tables::StorageChangeSet>()
+            .expect("Could not open plain storage state cursor");
+        let mut storage_changes = storage_changeset_cursor.walk_range(..).unwrap();
+
+        // Iterate through all storage changes
+
+        // Block <number>
+        // <slot>: <expected value before>
+        // ...
+
+        // Block #0
+        // 0x00: 0
+        // 0x01: 0
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((0, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((0, address1)),
+                StorageEntry { key: H256::from_low_u64_be(1), value: U256::ZERO }
+            )))
+        );
+
+        // Block #1
+        // 0x00: 1
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((1, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(1) }
+            )))
+        );
+
+        // Block #2 (destroyed)
+        // 0x00: 2
+        // 0x01: 2
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((2, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(2) }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((2, address1)),
+                StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(2) }
+            )))
+        );
+
+        // Block #3
+        // no storage changes
+
+        // Block #4
+        // 0x00: 0
+        // 0x02: 0
+        // 0x06: 0
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((4, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((4, address1)),
+                StorageEntry { key: H256::from_low_u64_be(2), value: U256::ZERO }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((4, address1)),
+                StorageEntry { key: H256::from_low_u64_be(6), value: U256::ZERO }
+            )))
+        );
+
+        // Block #5 (destroyed)
+        // 0x00: 2
+        // 0x02: 4
+        // 0x06: 6
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((5, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(2) }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((5, address1)),
+                StorageEntry { key: H256::from_low_u64_be(2), value: U256::from(4) }
+            )))
+        );
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((5, address1)),
+                StorageEntry { key: H256::from_low_u64_be(6), value: U256::from(6) }
+            )))
+        );
+
+        // Block #6
+        // no storage changes (only inter block changes)
+
+        // Block #7
+        // 0x00: 0
+        assert_eq!(
+            storage_changes.next(),
+            Some(Ok((
+                BlockNumberAddress((7, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
+            )))
+        );
+        assert_eq!(storage_changes.next(), None);
+    }
+
+    #[test]
+    fn storage_change_after_selfdestruct_within_block() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address1 = Address::random();
+        let account1 = RevmAccountInfo { nonce: 1, ..Default::default() };
+
+        // Block #0: initial state.
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_not_existing(address1);
+        let mut init_state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+        init_state.commit(HashMap::from([(
+            address1,
+            Account {
+                info: account1.clone(),
+                status: AccountStatus::Touched | AccountStatus::Created,
+                // 0x00 => 0 => 1
+                // 0x01 => 0 => 2
+                storage: HashMap::from([
+                    (
+                        U256::ZERO,
+                        StorageSlot { present_value: U256::from(1), ..Default::default() },
+                    ),
+                    (
+                        U256::from(1),
+                        StorageSlot { present_value: U256::from(2), ..Default::default() },
                     ),
                 ]),
             },
         )]));
-        state.merge_transitions(BundleRetention::Reverts);
+        init_state.merge_transitions(BundleRetention::Reverts);
+        BundleStateWithReceipts::new(init_state.take_bundle(), Receipts::new(), 0)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
+            .expect("Could not write init bundle state to DB");
 
-        // Block #5: Destroy account again.
-        state.commit(HashMap::from([(
+        let mut cache_state = CacheState::new(true);
+        cache_state.insert_account_with_storage(
             address1,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
-                info: account_info.clone(),
-                storage: HashMap::default(),
-            },
-        )]));
-        state.merge_transitions(BundleRetention::Reverts);
+            account1.clone(),
+            HashMap::from([(U256::ZERO, U256::from(1)), (U256::from(1), U256::from(2))]),
+        );
+        let mut state =
+            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
 
-        // Block #6: Create, change, destroy and re-create in the same block.
-        state.commit(HashMap::from([(
-            address1,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::Created,
-                info: account_info.clone(),
-                storage: HashMap::default(),
-            },
-        )]));
-        state.commit(HashMap::from([(
-            address1,
-            Account {
-                status: AccountStatus::Touched,
-                info: account_info.clone(),
-                // 0x00 => 0 => 2
-                storage: HashMap::from([(
-                    U256::ZERO,
-                    StorageSlot { present_value: U256::from(2), ..Default::default() },
-                )]),
-            },
-        )]));
+        // Block #1: Destroy, re-create, change storage.
         state.commit(HashMap::from([(
             address1,
             Account {
                 status: AccountStatus::Touched | AccountStatus::SelfDestructed,
-                info: account_info.clone(),
+                info: account1.clone(),
                 storage: HashMap::default(),
             },
         )]));
+
         state.commit(HashMap::from([(
             address1,
             Account {
                 status: AccountStatus::Touched | AccountStatus::Created,
-                info: account_info.clone(),
+                info: account1.clone(),
                 storage: HashMap::default(),
             },
         )]));
-        state.merge_transitions(BundleRetention::Reverts);
 
-        // Block #7: Change storage.
         state.commit(HashMap::from([(
             address1,
             Account {
                 status: AccountStatus::Touched,
-                info: account_info.clone(),
-                // 0x00 => 0 => 9
+                info: account1.clone(),
+                // 0x01 => 0 => 5
                 storage: HashMap::from([(
-                    U256::ZERO,
-                    StorageSlot { present_value: U256::from(9), ..Default::default() },
+                    U256::from(1),
+                    StorageSlot { present_value: U256::from(5), ..Default::default() },
                 )]),
             },
         )]));
-        state.merge_transitions(BundleRetention::Reverts);
-
-        let bundle = state.take_bundle();
 
-        BundleStateWithReceipts::new(bundle, Receipts::new(), 1)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
+        // Commit block #1 changes to the database.
+        state.merge_transitions(BundleRetention::Reverts);
+        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 1)
+            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes, WriteTables::default())
             .expect("Could not write bundle state to DB");
 
         let mut storage_changeset_cursor = provider
             .tx_ref()
             .cursor_dup_read::<tables::StorageChangeSet>()
             .expect("Could not open plain storage state cursor");
-        let mut storage_changes = storage_changeset_cursor.walk_range(..).unwrap();
-
-        // Iterate through all storage changes
-
-        // Block <number>
-        // <slot>: <expected value before>
-        // ...
+        let range = BlockNumberAddress::range(1..=1);
+        let mut storage_changes = storage_changeset_cursor.walk_range(range).unwrap();
 
-        // Block #0
-        // 0x00: 0
-        // 0x01: 0
         assert_eq!(
             storage_changes.next(),
             Some(Ok((
-                BlockNumberAddress((0, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
+                BlockNumberAddress((1, address1)),
+                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(1) }
             )))
         );
         assert_eq!(
             storage_changes.next(),
             Some(Ok((
-                BlockNumberAddress((0, address1)),
-                StorageEntry { key: H256::from_low_u64_be(1), value: U256::ZERO }
+                BlockNumberAddress((1, address1)),
+                StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(2) }
             )))
         );
+        assert_eq!(storage_changes.next(), None);
+    }
+
+    #[test]
+    fn wiped_storage_counts() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+
+        for key in 0..3u64 {
+            provider
+                .tx_ref()
+                .put::<tables::PlainStorageState>(
+                    address_a,
+                    StorageEntry { key: H256::from_low_u64_be(key), value: U256::from(1) },
+                )
+                .unwrap();
+        }
+
+        let reverts = StateReverts(PlainStateReverts {
+            accounts: vec![vec![], vec![]],
+            storage: vec![
+                vec![
+                    PlainStorageRevert { address: address_b, wiped: true, storage_revert: vec![] },
+                    PlainStorageRevert { address: address_a, wiped: false, storage_revert: vec![] },
+                ],
+                vec![PlainStorageRevert {
+                    address: address_a,
+                    wiped: true,
+                    storage_revert: vec![],
+                }],
+            ],
+        });
 
-        // Block #1
-        // 0x00: 1
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((1, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(1) }
-            )))
+            reverts.wiped_storage_counts(provider.tx_ref(), 10).unwrap(),
+            vec![(10, address_b, 0), (11, address_a, 3)]
         );
+        // Counting must not touch the plain state.
+        assert_eq!(provider.tx_ref().entries::<tables::PlainStorageState>().unwrap(), 3);
+    }
+
+    #[test]
+    fn wiped_storage_batches() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        for key in 1..=5u64 {
+            let entry =
+                StorageEntry { key: H256::from_low_u64_be(key), value: U256::from(key * 10) };
+            provider.tx_ref().put::<tables::PlainStorageState>(address, entry).unwrap();
+        }
+
+        let expected =
+            [(0, 3), (1, 10), (2, 9), (3, 30), (4, 40), (5, 50), (6, 7)].map(|(key, value)| {
+                StorageEntry { key: H256::from_low_u64_be(key), value: U256::from(value) }
+            });
+        for (block_number, batch_size) in [(1, 1), (2, 2), (3, 5), (4, 1024)] {
+            StateReverts(PlainStateReverts {
+                accounts: vec![vec![(address, Some(RevmAccountInfo::default()))]],
+                storage: vec![vec![PlainStorageRevert {
+                    address,
+                    wiped: true,
+                    storage_revert: vec![
+                        (U256::from(6), RevertToSlot::Some(U256::from(7))),
+                        (U256::from(0), RevertToSlot::Some(U256::from(3))),
+                        (U256::from(4), RevertToSlot::Destroyed),
+                        (U256::from(2), RevertToSlot::Some(U256::from(9))),
+                    ],
+                }]],
+            })
+            .write_to_db_with_batch_size(provider.tx_ref(), block_number, batch_size)
+            .unwrap();
+
+            let changeset = provider
+                .tx_ref()
+                .cursor_dup_read::<tables::StorageChangeSet>()
+                .unwrap()
+                .walk_dup(Some(BlockNumberAddress((block_number, address))), None)
+                .unwrap()
+                .map(|entry| entry.map(|(_, entry)| entry))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(changeset, expected, "batch size {batch_size}");
+        }
+    }
+
+    #[test]
+    fn wiped_storage_of_not_existing_account() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+        let entry = StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(1) };
+
+        // Plain storage of account A is never read, as A did not exist before the first block.
+        for address in [address_a, address_b] {
+            provider.tx_ref().put::<tables::PlainStorageState>(address, entry).unwrap();
+        }
+
+        let reverts = || {
+            StateReverts(PlainStateReverts {
+                accounts: vec![
+                    vec![(address_a, None)],
+                    vec![(address_a, Some(RevmAccountInfo::default()))],
+                    vec![(address_b, Some(RevmAccountInfo::default()))],
+                ],
+                storage: vec![
+                    vec![],
+                    vec![PlainStorageRevert {
+                        address: address_a,
+                        wiped: true,
+                        storage_revert: vec![],
+                    }],
+                    vec![PlainStorageRevert {
+                        address: address_b,
+                        wiped: true,
+                        storage_revert: vec![],
+                    }],
+                ],
+            })
+        };
 
-        // Block #2 (destroyed)
-        // 0x00: 2
-        // 0x01: 2
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((2, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(2) }
-            )))
+            reverts().wiped_storage_counts(provider.tx_ref(), 1).unwrap(),
+            vec![(2, address_a, 0), (3, address_b, 1)]
         );
+
+        reverts().write_to_db(provider.tx_ref(), 1).unwrap();
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((2, address1)),
-                StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(2) }
-            )))
+            provider
+                .tx_ref()
+                .cursor_read::<tables::StorageChangeSet>()
+                .unwrap()
+                .walk(None)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap(),
+            vec![(BlockNumberAddress((3, address_b)), entry)]
         );
+    }
 
-        // Block #3
-        // no storage changes
+    #[test]
+    fn write_to_db_error_phase() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let reverts = || {
+            StateReverts(PlainStateReverts {
+                accounts: vec![vec![(Address::zero(), None)]],
+                storage: vec![vec![]],
+            })
+        };
+        reverts().write_to_db(provider.tx_ref(), 2).unwrap();
+
+        // Account changesets can't be appended before an already written block.
+        let err = reverts().write_to_db(provider.tx_ref(), 1).unwrap_err();
+        assert!(matches!(err, BundleWriteError::AccountReverts(_)));
+        assert!(err.to_string().starts_with("failed to write account reverts"));
+        assert_eq!(RethError::from(err.clone()), RethError::Database(err.into_inner().unwrap()));
+    }
+
+    #[test]
+    fn effective_diff() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let address_a = Address::zero();
+        let address_b = Address::repeat_byte(0xff);
+        let account = RethAccount { nonce: 1, balance: U256::from(10), bytecode_hash: None };
+
+        provider.tx_ref().put::<tables::PlainAccountState>(address_a, account).unwrap();
+        provider
+            .tx_ref()
+            .put::<tables::PlainStorageState>(
+                address_a,
+                StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(1) },
+            )
+            .unwrap();
+
+        let bundle = BundleStateWithReceipts::new_init(
+            HashMap::from([
+                (
+                    address_a,
+                    (
+                        Some(account),
+                        Some(account),
+                        HashMap::from([
+                            // Same as the database value.
+                            (H256::from_low_u64_be(1), (U256::from(1), U256::from(1))),
+                            // Not in the database.
+                            (H256::from_low_u64_be(2), (U256::ZERO, U256::from(5))),
+                        ]),
+                    ),
+                ),
+                (address_b, (None, Some(account), HashMap::default())),
+            ]),
+            HashMap::default(),
+            vec![],
+            Receipts::new(),
+            1,
+        );
 
-        // Block #4
-        // 0x00: 0
-        // 0x02: 0
-        // 0x06: 0
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((4, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
-            )))
+            bundle.effective_diff(provider.tx_ref()).unwrap(),
+            EffectiveDiff {
+                changed_accounts: 1,
+                unchanged_accounts: 1,
+                changed_storage: 1,
+                unchanged_storage: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn verify_bytecode_references() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+
+        let bundle_code_hash = H256::repeat_byte(1);
+        let db_code_hash = H256::repeat_byte(2);
+        let missing_code_hash = H256::repeat_byte(3);
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        provider.tx_ref().put::<tables::Bytecodes>(db_code_hash, bytecode.clone()).unwrap();
+
+        let account =
+            |code_hash| RethAccount { bytecode_hash: Some(code_hash), ..Default::default() };
+        let builder = || {
+            BundleStateBuilder::new(1)
+                .add_account_change(
+                    1,
+                    Address::repeat_byte(1),
+                    None,
+                    Some(account(bundle_code_hash)),
+                )
+                .add_account_change(1, Address::repeat_byte(2), None, Some(account(db_code_hash)))
+                .add_account_change(1, Address::repeat_byte(3), None, Some(RethAccount::default()))
+                // Destroyed account is not checked.
+                .add_account_change(
+                    1,
+                    Address::repeat_byte(4),
+                    Some(account(missing_code_hash)),
+                    None,
+                )
+                .add_contract(bundle_code_hash, bytecode.clone())
+        };
+        assert_eq!(builder().build().verify_bytecode_references(provider.tx_ref()), Ok(()));
+
+        let bundle = builder()
+            .add_account_change(2, Address::repeat_byte(5), None, Some(account(missing_code_hash)))
+            .build();
+        assert_eq!(
+            bundle.verify_bytecode_references(provider.tx_ref()),
+            Err(MissingBytecode::NotFound {
+                address: Address::repeat_byte(5),
+                code_hash: missing_code_hash
+            })
         );
+    }
+
+    #[test]
+    fn from_db_range() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+
+        let address = Address::repeat_byte(0x11);
+        let bytecode = Bytecode(RevmBytecode::new_raw(vec![0x60, 0x00].into()));
+        let bytecode_hash = H256::repeat_byte(0xaa);
+        let account_1 = RethAccount { nonce: 1, balance: U256::from(10), bytecode_hash: None };
+        let account_2 =
+            RethAccount { nonce: 2, balance: U256::from(20), bytecode_hash: Some(bytecode_hash) };
+        let slot = H256::from_low_u64_be(1);
+        let receipt = Receipt { cumulative_gas_used: 21000, ..Default::default() };
+
+        // Block #1 creates the account, block #2 updates it, deploys code and changes a storage
+        // slot.
+        tx.put::<tables::PlainAccountState>(address, account_2).unwrap();
+        tx.put::<tables::Bytecodes>(bytecode_hash, bytecode.clone()).unwrap();
+        tx.put::<tables::PlainStorageState>(
+            address,
+            StorageEntry { key: slot, value: U256::from(2) },
+        )
+        .unwrap();
+        tx.put::<tables::AccountChangeSet>(1, AccountBeforeTx { address, info: None }).unwrap();
+        tx.put::<tables::AccountChangeSet>(2, AccountBeforeTx { address, info: Some(account_1) })
+            .unwrap();
+        tx.put::<tables::StorageChangeSet>(
+            BlockNumberAddress((2, address)),
+            StorageEntry { key: slot, value: U256::from(1) },
+        )
+        .unwrap();
+        tx.put::<tables::BlockBodyIndices>(
+            1,
+            StoredBlockBodyIndices { first_tx_num: 0, tx_count: 1 },
+        )
+        .unwrap();
+        tx.put::<tables::BlockBodyIndices>(
+            2,
+            StoredBlockBodyIndices { first_tx_num: 1, tx_count: 1 },
+        )
+        .unwrap();
+        // Receipt of the second transaction is pruned.
+        tx.put::<tables::Receipts>(0, receipt.clone()).unwrap();
+
+        let bundle = BundleStateWithReceipts::from_db_range(tx, 1..=2).unwrap();
+        assert_eq!(bundle.first_block(), 1);
+        assert_eq!(bundle.len(), 2);
+        assert_eq!(block_receipts(&bundle), vec![vec![Some(receipt)], vec![None]]);
+        assert_eq!(bundle.account(&address), Some(Some(account_2)));
+        assert_eq!(bundle.storage(&address, U256::from(1)), Some(U256::from(2)));
+        assert_eq!(bundle.state().account(&address).unwrap().original_info, None);
+        assert_eq!(bundle.bytecode(&bytecode_hash), Some(bytecode));
+
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((4, address1)),
-                StorageEntry { key: H256::from_low_u64_be(2), value: U256::ZERO }
-            )))
+            BundleStateWithReceipts::from_db_range(tx, 1..=3),
+            Err(ProviderError::BlockBodyIndicesNotFound(3))
+        );
+    }
+
+    #[test]
+    fn write_to_db_with_receipt_indices() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let factory = ProviderFactory::new(db, MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+
+        for (block_number, first_tx_num, tx_count) in [(5, 10, 2), (6, 12, 0), (7, 12, 2)] {
+            tx.put::<tables::BlockBodyIndices>(
+                block_number,
+                StoredBlockBodyIndices { first_tx_num, tx_count },
+            )
+            .unwrap();
+        }
+        let receipt = Some(Receipt::default());
+        // Empty reverts of the blocks 5 to 7.
+        let reverts = BundleStateBuilder::new(5).add_receipt(7, Receipt::default()).build();
+        let bundle = BundleStateWithReceipts::new(
+            reverts.state().clone(),
+            Receipts::from_vec(vec![vec![receipt.clone(), None], vec![], vec![receipt; 2]]),
+            5,
         );
+
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((4, address1)),
-                StorageEntry { key: H256::from_low_u64_be(6), value: U256::ZERO }
-            )))
+            bundle.write_to_db_with_receipt_indices(tx).unwrap(),
+            vec![(5, 10..12), (7, 12..14)]
+        );
+        let keys = tx
+            .cursor_read::<tables::Receipts>()
+            .unwrap()
+            .walk(None)
+            .unwrap()
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(keys, vec![10, 12, 13]);
+    }
+
+    #[test]
+    fn same_state_root() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, balance: U256::from(10), bytecode_hash: None };
+        let other_account = RethAccount { nonce: 2, ..account };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_receipt(1, Receipt::default())
+            .build();
+        // Same state reached in a different block, without receipts.
+        let same =
+            BundleStateBuilder::new(1).add_account_change(2, address, None, Some(account)).build();
+        let different = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(other_account))
+            .build();
+
+        assert_ne!(bundle, same);
+        assert!(bundle.same_state_root(&same, &tx).unwrap());
+        assert!(!bundle.same_state_root(&different, &tx).unwrap());
+    }
+
+    #[test]
+    fn account_trie_root_slow() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, balance: U256::from(10), bytecode_hash: None };
+        let accounts_only = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_account_change(1, Address::repeat_byte(0x22), None, Some(account))
+            .build();
+        assert_eq!(
+            accounts_only.account_trie_root_slow(&tx).unwrap(),
+            accounts_only.state_root_slow(&tx).unwrap()
+        );
+
+        let with_storage = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .build();
+        let without_storage =
+            BundleStateBuilder::new(1).add_account_change(1, address, None, Some(account)).build();
+        let root = with_storage.account_trie_root_slow(&tx).unwrap();
+        assert_eq!(root, without_storage.state_root_slow(&tx).unwrap());
+        assert_ne!(root, with_storage.state_root_slow(&tx).unwrap());
+    }
+
+    #[test]
+    fn storage_root_slow() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+        let storage_root =
+            |value| storage_root_prehashed([(keccak256(slot), U256::from(value))].into_iter());
+        let mut bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .build();
+
+        assert_eq!(bundle.storage_root_slow(&tx, address).unwrap(), storage_root(1));
+        assert_eq!(bundle.storage_root_slow(&tx, Address::repeat_byte(0x22)).unwrap(), EMPTY_ROOT);
+
+        // Changing the bundle clears the hashed state.
+        bundle.extend(
+            BundleStateBuilder::new(2)
+                .add_account_change(2, address, Some(account), Some(account))
+                .add_storage_change(2, address, slot, U256::from(1), U256::from(2))
+                .build(),
+        );
+        assert_eq!(bundle.storage_root_slow(&tx, address).unwrap(), storage_root(2));
+        drop(tx);
+
+        // Storage in the database is read on every call.
+        let other_slot = H256::from_low_u64_be(2);
+        db.update(|tx| {
+            let entry = StorageEntry { key: keccak256(other_slot), value: U256::from(3) };
+            tx.put::<tables::HashedStorage>(keccak256(address), entry).unwrap();
+        })
+        .unwrap();
+        let tx = db.tx().unwrap();
+        assert_eq!(
+            bundle.storage_root_slow(&tx, address).unwrap(),
+            storage_root_prehashed(
+                [(keccak256(slot), U256::from(2)), (keccak256(other_slot), U256::from(3))]
+                    .into_iter()
+            )
         );
+    }
 
-        // Block #5 (destroyed)
-        // 0x00: 2
-        // 0x02: 4
-        // 0x06: 6
+    #[test]
+    fn storage_root_slow_cache() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let slot = H256::from_low_u64_be(1);
+        let other_slot = H256::from_low_u64_be(2);
+        let mut bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .build();
+
+        let tx = db.tx().unwrap();
+        bundle.state_root_slow(&tx).unwrap();
+        let cached_root = bundle.storage_root_slow(&tx, address).unwrap();
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((5, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(2) }
-            )))
+            cached_root,
+            storage_root_prehashed([(keccak256(slot), U256::from(1))].into_iter())
         );
+        drop(tx);
+
+        // The root is cached for the database view of `state_root_slow`.
+        db.update(|tx| {
+            let entry = StorageEntry { key: keccak256(other_slot), value: U256::from(3) };
+            tx.put::<tables::HashedStorage>(keccak256(address), entry).unwrap();
+        })
+        .unwrap();
+        let tx = db.tx().unwrap();
+        assert_eq!(bundle.storage_root_slow(&tx, address).unwrap(), cached_root);
+
+        // Changing the bundle clears the cached roots.
+        bundle.extend(
+            BundleStateBuilder::new(2)
+                .add_account_change(2, address, Some(account), Some(account))
+                .add_storage_change(2, address, slot, U256::from(1), U256::from(2))
+                .build(),
+        );
+        let expected = storage_root_prehashed(
+            [(keccak256(slot), U256::from(2)), (keccak256(other_slot), U256::from(3))].into_iter(),
+        );
+        assert_eq!(bundle.storage_root_slow(&tx, address).unwrap(), expected);
+
+        // A new call of `state_root_slow` replaces the cached roots with the ones of its `tx`.
+        bundle.state_root_slow(&tx).unwrap();
+        assert_eq!(bundle.storage_root_slow(&tx, address).unwrap(), expected);
+    }
+
+    #[test]
+    fn state_root_slow_with_factory() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
+
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let other_account = RethAccount { nonce: 2, ..Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .build();
+        let other = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(other_account))
+            .build();
+
+        let hashed_state = bundle.hash_state_slow();
+        let factory = HashedPostStateCursorFactory::new(&tx, &hashed_state);
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((5, address1)),
-                StorageEntry { key: H256::from_low_u64_be(2), value: U256::from(4) }
-            )))
+            bundle.state_root_slow_with_factory(&tx, &factory).unwrap(),
+            bundle.state_root_slow(&tx).unwrap()
         );
+
+        // Hashed state of the factory is used instead of the one of the bundle.
+        let other_hashed_state = other.hash_state_slow();
+        let factory = HashedPostStateCursorFactory::new(&tx, &other_hashed_state);
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((5, address1)),
-                StorageEntry { key: H256::from_low_u64_be(6), value: U256::from(6) }
-            )))
+            bundle.state_root_slow_with_factory(&tx, &factory).unwrap(),
+            other.state_root_slow(&tx).unwrap()
         );
+    }
 
-        // Block #6
-        // no storage changes (only inter block changes)
+    #[test]
+    fn state_root_slow_with_overrides() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
 
-        // Block #7
-        // 0x00: 0
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, balance: U256::from(10), bytecode_hash: None };
+        let slot = H256::from_low_u64_be(1);
+        let with_storage = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account))
+            .add_storage_change(1, address, slot, U256::ZERO, U256::from(1))
+            .add_account_change(1, Address::repeat_byte(0x22), None, Some(account))
+            .build();
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((7, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::ZERO }
-            )))
+            with_storage.state_root_slow_with_overrides(&tx, Default::default()).unwrap(),
+            with_storage.state_root_slow(&tx).unwrap()
         );
-        assert_eq!(storage_changes.next(), None);
+
+        // Empty storage root of an account with storage is the root without its storage.
+        let root = with_storage
+            .state_root_slow_with_overrides(&tx, [(address, EMPTY_ROOT)].into_iter().collect())
+            .unwrap();
+        assert_eq!(root, with_storage.account_trie_root_slow(&tx).unwrap());
+
+        // Storage root computed from the bundle is the root with the storage.
+        let storage_root = storage_root_prehashed([(keccak256(slot), U256::from(1))].into_iter());
+        let root = with_storage
+            .state_root_slow_with_overrides(&tx, [(address, storage_root)].into_iter().collect())
+            .unwrap();
+        assert_eq!(root, with_storage.state_root_slow(&tx).unwrap());
     }
 
     #[test]
-    fn storage_change_after_selfdestruct_within_block() {
+    fn state_root_incremental() {
         let db: Arc<DatabaseEnv> = create_test_rw_db();
-        let factory = ProviderFactory::new(db, MAINNET.clone());
-        let provider = factory.provider_rw().unwrap();
+        let tx = db.tx().unwrap();
 
-        let address1 = Address::random();
-        let account1 = RevmAccountInfo { nonce: 1, ..Default::default() };
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account_1 = RethAccount { nonce: 1, ..Default::default() };
+        let account_2 = RethAccount { nonce: 2, ..Default::default() };
+        let slot_1 = H256::from_low_u64_be(1);
+        let slot_2 = H256::from_low_u64_be(2);
 
-        // Block #0: initial state.
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_not_existing(address1);
-        let mut init_state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
-        init_state.commit(HashMap::from([(
-            address1,
-            Account {
-                info: account1.clone(),
-                status: AccountStatus::Touched | AccountStatus::Created,
-                // 0x00 => 0 => 1
-                // 0x01 => 0 => 2
-                storage: HashMap::from([
-                    (
-                        U256::ZERO,
-                        StorageSlot { present_value: U256::from(1), ..Default::default() },
-                    ),
-                    (
-                        U256::from(1),
-                        StorageSlot { present_value: U256::from(2), ..Default::default() },
-                    ),
-                ]),
-            },
-        )]));
-        init_state.merge_transitions(BundleRetention::Reverts);
-        BundleStateWithReceipts::new(init_state.take_bundle(), Receipts::new(), 0)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
-            .expect("Could not write init bundle state to DB");
+        let prev = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(account_1))
+            .add_storage_change(1, address_a, slot_1, U256::ZERO, U256::from(1))
+            .add_storage_change(1, address_a, slot_2, U256::ZERO, U256::from(2))
+            .build();
+        let next = BundleStateBuilder::new(2)
+            .add_account_change(2, address_a, Some(account_1), Some(account_2))
+            .add_account_change(2, address_b, None, Some(account_1))
+            .add_storage_change(2, address_a, slot_1, U256::from(1), U256::ZERO)
+            .build();
+        let combined = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(account_1))
+            .add_storage_change(1, address_a, slot_1, U256::ZERO, U256::from(1))
+            .add_storage_change(1, address_a, slot_2, U256::ZERO, U256::from(2))
+            .add_account_change(2, address_a, Some(account_1), Some(account_2))
+            .add_account_change(2, address_b, None, Some(account_1))
+            .add_storage_change(2, address_a, slot_1, U256::from(1), U256::ZERO)
+            .build();
 
-        let mut cache_state = CacheState::new(true);
-        cache_state.insert_account_with_storage(
-            address1,
-            account1.clone(),
-            HashMap::from([(U256::ZERO, U256::from(1)), (U256::from(1), U256::from(2))]),
+        assert_eq!(
+            next.state_root_incremental(&tx, &prev.hash_state_slow()).unwrap(),
+            combined.state_root_slow(&tx).unwrap()
         );
-        let mut state =
-            State::builder().with_cached_prestate(cache_state).with_bundle_update().build();
+    }
 
-        // Block #1: Destroy, re-create, change storage.
-        state.commit(HashMap::from([(
-            address1,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::SelfDestructed,
-                info: account1.clone(),
-                storage: HashMap::default(),
-            },
-        )]));
+    #[test]
+    fn state_root_slow_parallel() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        // Storage of the database is read by the storage root walks on all threads.
+        db.update(|tx| {
+            for index in (0..64u64).step_by(2) {
+                let entry = StorageEntry { key: H256::repeat_byte(0xaa), value: U256::from(index) };
+                tx.put::<tables::HashedStorage>(keccak256(Address::from_low_u64_be(index)), entry)
+                    .unwrap();
+            }
+        })
+        .unwrap();
+        let tx = db.tx().unwrap();
 
-        state.commit(HashMap::from([(
-            address1,
-            Account {
-                status: AccountStatus::Touched | AccountStatus::Created,
-                info: account1.clone(),
-                storage: HashMap::default(),
-            },
-        )]));
+        let destroyed = Address::repeat_byte(0xff);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let bundle = (0..64u64)
+            .fold(BundleStateBuilder::new(1), |builder, index| {
+                let address = Address::from_low_u64_be(index);
+                builder.add_account_change(1, address, None, Some(account)).add_storage_change(
+                    1,
+                    address,
+                    H256::from_low_u64_be(index),
+                    U256::ZERO,
+                    U256::from(index),
+                )
+            })
+            .add_account_change(1, destroyed, Some(account), None)
+            .build();
 
-        state.commit(HashMap::from([(
-            address1,
-            Account {
-                status: AccountStatus::Touched,
-                info: account1.clone(),
-                // 0x01 => 0 => 5
-                storage: HashMap::from([(
-                    U256::from(1),
-                    StorageSlot { present_value: U256::from(5), ..Default::default() },
-                )]),
-            },
-        )]));
+        let root =
+            BundleStateWithReceipts::state_root_with_hashed_state(&tx, &bundle.hash_state_slow())
+                .unwrap();
+        assert_eq!(bundle.state_root_slow(&tx).unwrap(), root);
+        for num_threads in [0, 1, 2, 4, 8, 128] {
+            assert_eq!(bundle.state_root_slow_parallel(&tx, num_threads).unwrap(), root);
+        }
+    }
 
-        // Commit block #1 changes to the database.
-        state.merge_transitions(BundleRetention::Reverts);
-        BundleStateWithReceipts::new(state.take_bundle(), Receipts::new(), 1)
-            .write_to_db(provider.tx_ref(), OriginalValuesKnown::Yes)
-            .expect("Could not write bundle state to DB");
+    #[test]
+    fn state_root_slow_cache() {
+        let db: Arc<DatabaseEnv> = create_test_rw_db();
+        let tx = db.tx().unwrap();
 
-        let mut storage_changeset_cursor = provider
-            .tx_ref()
-            .cursor_dup_read::<tables::StorageChangeSet>()
-            .expect("Could not open plain storage state cursor");
-        let range = BlockNumberAddress::range(1..=1);
-        let mut storage_changes = storage_changeset_cursor.walk_range(range).unwrap();
+        let address = Address::repeat_byte(0x11);
+        let account_1 = RethAccount { nonce: 1, ..Default::default() };
+        let account_2 = RethAccount { nonce: 2, ..Default::default() };
+        let uncached_root = |bundle: &BundleStateWithReceipts| {
+            BundleStateWithReceipts::state_root_with_hashed_state(&tx, &bundle.hash_state_slow())
+                .unwrap()
+        };
 
-        assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((1, address1)),
-                StorageEntry { key: H256::from_low_u64_be(0), value: U256::from(1) }
-            )))
+        let mut bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address, None, Some(account_1))
+            .add_account_change(2, address, Some(account_1), Some(account_2))
+            .build();
+        let root = bundle.state_root_slow(&tx).unwrap();
+        assert_eq!(root, uncached_root(&bundle));
+        assert_eq!(bundle.state_root_slow(&tx).unwrap(), root);
+
+        // Reverting the bundle clears the cache.
+        bundle.revert_to(1);
+        let reverted_root = bundle.state_root_slow(&tx).unwrap();
+        assert_ne!(reverted_root, root);
+        assert_eq!(reverted_root, uncached_root(&bundle));
+
+        // Extending the bundle clears the cache.
+        bundle.extend(
+            BundleStateBuilder::new(2)
+                .add_account_change(2, address, Some(account_1), Some(account_2))
+                .build(),
         );
+        assert_eq!(bundle.state_root_slow(&tx).unwrap(), root);
+    }
+
+    #[test]
+    fn account_and_storage_slot_count() {
+        let address_a = Address::repeat_byte(0x11);
+        let address_b = Address::repeat_byte(0x22);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_account_change(1, address_a, None, Some(account))
+            .add_storage_change(1, address_a, H256::from_low_u64_be(1), U256::ZERO, U256::from(1))
+            .add_storage_change(2, address_a, H256::from_low_u64_be(2), U256::ZERO, U256::from(2))
+            .add_storage_change(2, address_b, H256::from_low_u64_be(1), U256::ZERO, U256::from(3))
+            .build();
+
+        assert_eq!(bundle.account_count(), 2);
+        assert_eq!(bundle.account_count(), bundle.accounts_iter().count());
+        assert_eq!(bundle.storage_slot_count(), 3);
         assert_eq!(
-            storage_changes.next(),
-            Some(Ok((
-                BlockNumberAddress((1, address1)),
-                StorageEntry { key: H256::from_low_u64_be(1), value: U256::from(2) }
-            )))
+            bundle.storage_slot_count(),
+            bundle.state().state().iter().flat_map(|(_, account)| account.storage.iter()).count()
         );
-        assert_eq!(storage_changes.next(), None);
+        assert_eq!(bundle.account_storage_slot_count(&address_a), Some(2));
+        assert_eq!(bundle.account_storage_slot_count(&address_b), Some(1));
+        assert_eq!(bundle.account_storage_slot_count(&Address::zero()), None);
+    }
+
+    #[test]
+    fn block_gas_used() {
+        let receipt =
+            |cumulative_gas_used| Some(Receipt { cumulative_gas_used, ..Default::default() });
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![
+                vec![receipt(21000), receipt(42000)],
+                vec![],
+                vec![receipt(21000), None],
+            ]),
+            10,
+        );
+
+        assert_eq!(bundle.block_gas_used(9), None);
+        assert_eq!(bundle.block_gas_used(10), Some(42000));
+        assert_eq!(bundle.block_gas_used(11), Some(0));
+        assert_eq!(bundle.block_gas_used(12), None);
+        assert_eq!(bundle.block_gas_used(13), None);
+        assert_eq!(bundle.total_gas_used(), 42000);
+        assert_eq!(BundleStateWithReceipts::default().total_gas_used(), 0);
+    }
+
+    #[test]
+    fn block_blob_gas_used() {
+        let mut bundle = BundleStateBuilder::new(1).add_receipt(3, Receipt::default()).build();
+        assert_eq!(bundle.set_block_blob_gas_used(2, 131_072), Ok(()));
+        assert_eq!(bundle.set_block_blob_gas_used(4, 131_072), Err(OutOfRange { block_number: 4 }));
+
+        // Blob gas used of blocks before Cancun is not recorded.
+        assert_eq!(bundle.block_blob_gas_used(1), None);
+        assert_eq!(bundle.block_blob_gas_used(2), Some(131_072));
+        assert_eq!(bundle.block_blob_gas_used(3), None);
+        assert_eq!(bundle.block_blob_gas_used(4), None);
+
+        let mut next = BundleStateBuilder::new(4).add_receipt(4, Receipt::default()).build();
+        next.set_block_blob_gas_used(4, 262_144).unwrap();
+        bundle.extend(next);
+        assert_eq!(bundle.block_blob_gas_used(4), Some(262_144));
+
+        assert!(bundle.revert_to(2));
+        assert_eq!(bundle.block_blob_gas_used(2), Some(131_072));
+        assert_eq!(bundle.block_blob_gas_used(4), None);
     }
 
     #[test]
     fn revert_to_indices() {
         let base = BundleStateWithReceipts {
             bundle: BundleState::default(),
-            receipts: Receipts::from_vec(vec![vec![Some(Receipt::default()); 2]; 7]),
+            receipts: Receipts::from_vec(vec![vec![Some(Receipt::default()); 2]; 7]).into(),
             first_block: 10,
+            ..Default::default()
         };
 
         let mut this = base.clone();
@@ -1188,4 +6205,260 @@ mod tests {
         assert!(!this.revert_to(17));
         assert_eq!(this.receipts.len(), 7);
     }
+
+    #[test]
+    fn try_block_logs_bloom() {
+        let log =
+            Log { address: Address::repeat_byte(1), topics: vec![], data: Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_receipt(1, Receipt { logs: vec![log.clone(); 2], ..Default::default() })
+            .add_receipt(1, Receipt { logs: vec![log.clone()], ..Default::default() })
+            .build();
+
+        let bloom = bundle.block_logs_bloom(1);
+        assert_eq!(bundle.try_block_logs_bloom(1, None), Ok(bloom));
+        assert_eq!(bundle.try_block_logs_bloom(1, Some(3)), Ok(bloom));
+        assert_eq!(
+            bundle.try_block_logs_bloom(1, Some(2)),
+            Err(TooManyLogs { block_number: 1, logs: 3, max_logs: 2 })
+        );
+        assert_eq!(bundle.try_block_logs_bloom(2, Some(0)), Ok(None));
+    }
+
+    #[test]
+    fn block_log_count() {
+        let log =
+            Log { address: Address::repeat_byte(1), topics: vec![], data: Default::default() };
+        let bundle = BundleStateBuilder::new(1)
+            .add_receipt(1, Receipt { logs: vec![log.clone(); 2], ..Default::default() })
+            .add_receipt(1, Receipt { logs: vec![log], ..Default::default() })
+            .add_receipt(2, Receipt::default())
+            .build();
+
+        assert_eq!(bundle.block_log_count(1), Some(3));
+        assert_eq!(bundle.block_log_count(2), Some(0));
+        assert_eq!(bundle.block_log_count(0), None);
+        assert_eq!(bundle.block_log_count(3), None);
+    }
+
+    #[test]
+    fn logs_with_index() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![],
+            data: Default::default(),
+        };
+        let bundle = BundleStateBuilder::new(1)
+            .add_receipt(1, Receipt { logs: vec![log(1), log(2)], ..Default::default() })
+            .add_receipt(1, Receipt::default())
+            .add_receipt(1, Receipt { logs: vec![log(3)], ..Default::default() })
+            .add_receipt(2, Receipt::default())
+            .build();
+
+        let logs = bundle.logs_with_index(1).unwrap().collect::<Vec<_>>();
+        assert_eq!(logs, vec![(0, &log(1)), (1, &log(2)), (2, &log(3))]);
+        assert_eq!(bundle.logs_with_index(2).unwrap().count(), 0);
+        assert!(bundle.logs_with_index(3).is_none());
+    }
+
+    #[test]
+    fn receipts_with_blooms() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![H256::repeat_byte(byte)],
+            data: Default::default(),
+        };
+        let receipt = |logs| Receipt { logs, ..Default::default() };
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![
+                Some(receipt(vec![log(1), log(2)])),
+                None,
+                Some(receipt(vec![])),
+            ]]),
+            1,
+        );
+
+        assert_eq!(
+            bundle.receipts_with_blooms(1).unwrap().collect::<Vec<_>>(),
+            vec![
+                (&receipt(vec![log(1), log(2)]), logs_bloom(&[log(1), log(2)])),
+                (&receipt(vec![]), Bloom::zero()),
+            ]
+        );
+        assert!(bundle.receipts_with_blooms(2).is_none());
+    }
+
+    #[test]
+    fn aggregate_logs_bloom() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![H256::repeat_byte(byte)],
+            data: Default::default(),
+        };
+        let receipt = |logs| Receipt { cumulative_gas_used: 21_000, logs, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_receipt(1, receipt(vec![log(1)]))
+            .add_receipt(3, receipt(vec![log(2), log(3)]))
+            .build();
+
+        assert_eq!(bundle.aggregate_logs_bloom(), logs_bloom(&[log(1), log(2), log(3)]));
+        assert_eq!(BundleStateWithReceipts::default().aggregate_logs_bloom(), Bloom::zero());
+    }
+
+    #[test]
+    fn verify_receipt_blooms() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![H256::repeat_byte(byte)],
+            data: Default::default(),
+        };
+        let receipt = |logs| Receipt { cumulative_gas_used: 21_000, logs, ..Default::default() };
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![
+                Some(receipt(vec![log(1)])),
+                None,
+                Some(receipt(vec![log(2), log(3)])),
+            ]]),
+            1,
+        );
+        let bloom_1 = logs_bloom(&[log(1)]);
+        let bloom_2 = logs_bloom(&[log(2), log(3)]);
+
+        assert_eq!(bundle.verify_receipt_blooms(1, &[bloom_1, Bloom::zero(), bloom_2]), Ok(()));
+        assert_eq!(
+            bundle.verify_receipt_blooms(1, &[bloom_1, bloom_1, bloom_1]),
+            Err(BloomMismatch { block_number: 1, tx_index: 2 })
+        );
+        assert_eq!(
+            bundle.verify_receipt_blooms(1, &[bloom_1]),
+            Err(BloomMismatch { block_number: 1, tx_index: 1 })
+        );
+        assert_eq!(
+            bundle.verify_receipt_blooms(2, &[bloom_1]),
+            Err(BloomMismatch { block_number: 2, tx_index: 0 })
+        );
+        assert_eq!(bundle.verify_receipt_blooms(2, &[]), Ok(()));
+    }
+
+    #[test]
+    fn range_logs_bloom() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![H256::repeat_byte(byte)],
+            data: Default::default(),
+        };
+        let receipt = |logs| Receipt { cumulative_gas_used: 21_000, logs, ..Default::default() };
+
+        let bundle = BundleStateBuilder::new(1)
+            .add_receipt(1, receipt(vec![log(1)]))
+            .add_receipt(2, receipt(vec![log(2)]))
+            .add_receipt(3, receipt(vec![log(3)]))
+            .build();
+
+        assert_eq!(bundle.range_logs_bloom(2..=5), logs_bloom(&[log(2), log(3)]));
+        assert_eq!(bundle.range_logs_bloom(0..=1), logs_bloom(&[log(1)]));
+        assert_eq!(bundle.range_logs_bloom(0..=10), bundle.aggregate_logs_bloom());
+        assert_eq!(bundle.range_logs_bloom(4..=10), Bloom::zero());
+    }
+
+    #[test]
+    fn extend_state_only() {
+        let address = Address::repeat_byte(0x11);
+        let block = |number: u64| {
+            let old = (number > 1).then(|| RethAccount { nonce: number - 1, ..Default::default() });
+            let new = RethAccount { nonce: number, ..Default::default() };
+            BundleStateBuilder::new(number)
+                .add_account_change(number, address, old, Some(new))
+                .add_receipt(number, Receipt { cumulative_gas_used: number, ..Default::default() })
+                .build()
+        };
+
+        let mut extended = block(1);
+        extended.extend(block(2));
+        let mut state_only = block(1);
+        state_only.extend_state_only(block(2));
+
+        assert_eq!(state_only.state(), extended.state());
+        assert_eq!(state_only.len(), 2);
+        assert_eq!(state_only.check_block_alignment(), Ok(()));
+        assert_eq!(
+            state_only.receipts_view().by_block(1).unwrap_or_default(),
+            extended.receipts_view().by_block(1).unwrap_or_default()
+        );
+        assert!(state_only.receipts_view().by_block(2).unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn append_receipts() {
+        let address = Address::repeat_byte(0x11);
+        let account = RethAccount { nonce: 1, ..Default::default() };
+        let receipt = Receipt { cumulative_gas_used: 21_000, ..Default::default() };
+
+        let mut bundle =
+            BundleStateBuilder::new(1).add_account_change(1, address, None, Some(account)).build();
+        bundle.append_receipts(vec![receipt.clone()]);
+        bundle.append_receipts(vec![]);
+
+        assert_eq!(bundle.len(), 3);
+        assert_eq!(bundle.receipts_view().by_block(2).unwrap_or_default(), &[Some(receipt)]);
+        assert!(bundle.receipts_view().by_block(3).unwrap_or_default().is_empty());
+
+        // Reverting receipts-only blocks keeps the state changes of block 1.
+        assert_eq!(bundle.try_revert_to(1), Ok(2));
+        assert_eq!(bundle.account(&address), Some(Some(account)));
+        assert!(bundle.receipts_view().by_block(1).unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn try_revert_to() {
+        let base = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(vec![vec![]; 7]),
+            10,
+        );
+
+        let mut this = base.clone();
+        assert_eq!(this.try_revert_to(12), Ok(4));
+        assert_eq!(this.len(), 3);
+
+        let mut this = base.clone();
+        assert_eq!(this.try_revert_to(16), Ok(0));
+        assert_eq!(this.len(), 7);
+
+        for block_number in [9, 17] {
+            let mut this = base.clone();
+            assert_eq!(
+                this.try_revert_to(block_number),
+                Err(RevertError::BlockNotInBundle { block_number, first_block: 10, len: 7 })
+            );
+            assert_eq!(this, base);
+        }
+    }
+
+    #[test]
+    fn revert_to_returning_receipts() {
+        let receipts = (0..7u64)
+            .map(|block| vec![Some(Receipt { cumulative_gas_used: block, ..Default::default() })])
+            .collect::<Vec<_>>();
+        let base = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(receipts.clone()),
+            10,
+        );
+
+        let mut this = base.clone();
+        assert_eq!(this.revert_to_returning(13), Some(receipts[4..].to_vec()));
+        assert_eq!(block_receipts(&this), receipts[..4].to_vec());
+
+        let mut this = base.clone();
+        assert_eq!(this.revert_to_returning(16), Some(vec![]));
+        assert_eq!(this.receipts.len(), 7);
+
+        let mut this = base;
+        assert_eq!(this.revert_to_returning(9), None);
+        assert_eq!(this.receipts.len(), 7);
+    }
 }
@@ -0,0 +1,104 @@
+use reth_primitives::{Account, Address, H256, KECCAK_EMPTY, U256};
+use reth_revm_primitives::{db::states::BundleState, into_reth_acc};
+use reth_rlp::{RlpDecodable, RlpEncodable};
+
+/// Present account and storage changes of a bundle in a canonical RLP form.
+///
+/// Accounts and storage are sorted by address and storage slots by key, so equal changes always
+/// have the same encoding. See
+/// [BundleStateWithReceipts::encode_changesets_rlp](crate::BundleStateWithReceipts::encode_changesets_rlp).
+#[derive(Default, Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct BundleChangesets {
+    /// Changed accounts.
+    pub accounts: Vec<AccountChange>,
+    /// Changed storage.
+    pub storage: Vec<StorageChange>,
+}
+
+/// Present value of a changed account.
+#[derive(Default, Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct AccountChange {
+    /// Account address.
+    pub address: Address,
+    /// Whether the account exists, other fields are empty for a destroyed account.
+    pub exists: bool,
+    /// Account nonce.
+    pub nonce: u64,
+    /// Account balance.
+    pub balance: U256,
+    /// Hash of the account bytecode, [KECCAK_EMPTY] for an account without code.
+    pub bytecode_hash: H256,
+}
+
+impl AccountChange {
+    /// Create change of the account at `address` to `account`, None if the account was destroyed.
+    pub fn new(address: Address, account: Option<Account>) -> Self {
+        match account {
+            Some(account) => Self {
+                address,
+                exists: true,
+                nonce: account.nonce,
+                balance: account.balance,
+                bytecode_hash: account.bytecode_hash.unwrap_or(KECCAK_EMPTY),
+            },
+            None => Self { address, ..Default::default() },
+        }
+    }
+
+    /// Return the present account, None if the account was destroyed.
+    pub fn account(&self) -> Option<Account> {
+        self.exists.then(|| Account {
+            nonce: self.nonce,
+            balance: self.balance,
+            bytecode_hash: (self.bytecode_hash != KECCAK_EMPTY).then_some(self.bytecode_hash),
+        })
+    }
+}
+
+/// Present values of the changed storage slots of an account.
+#[derive(Default, Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct StorageChange {
+    /// Account address.
+    pub address: Address,
+    /// Whether the storage was wiped before the slots were set.
+    pub wipe_storage: bool,
+    /// Changed slots with their present values, zero if the slot was cleared.
+    pub slots: Vec<StorageSlotChange>,
+}
+
+/// Present value of a changed storage slot.
+#[derive(Default, Debug, Clone, PartialEq, Eq, RlpEncodable, RlpDecodable)]
+pub struct StorageSlotChange {
+    /// Storage slot.
+    pub key: H256,
+    /// Present value.
+    pub value: U256,
+}
+
+impl From<&BundleState> for BundleChangesets {
+    fn from(bundle: &BundleState) -> Self {
+        let mut changesets = Self::default();
+        for (address, account) in bundle.state() {
+            let info = account.info.clone().map(into_reth_acc);
+            changesets.accounts.push(AccountChange::new(*address, info));
+
+            let wipe_storage = account.status.was_destroyed();
+            if !wipe_storage && account.storage.is_empty() {
+                continue
+            }
+            let mut slots = account
+                .storage
+                .iter()
+                .map(|(key, slot)| StorageSlotChange {
+                    key: H256(key.to_be_bytes()),
+                    value: slot.present_value,
+                })
+                .collect::<Vec<_>>();
+            slots.sort_unstable_by_key(|slot| slot.key);
+            changesets.storage.push(StorageChange { address: *address, wipe_storage, slots });
+        }
+        changesets.accounts.sort_unstable_by_key(|account| account.address);
+        changesets.storage.sort_unstable_by_key(|storage| storage.address);
+        changesets
+    }
+}
@@ -0,0 +1,223 @@
+use reth_db::table::{Compress, Decompress};
+use reth_interfaces::db::DatabaseError;
+use reth_primitives::Receipt;
+use std::ops::Range;
+
+/// Receipts of consecutive blocks, compressed per block and decompressed on access.
+///
+/// Receipts are compressed the same way as in [reth_db::tables::Receipts], which trades CPU for
+/// memory when the receipts of a large bundle are rarely read.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CompressedReceipts {
+    /// Compressed receipts of each block.
+    blocks: Vec<CompressedBlock>,
+}
+
+/// Compressed receipts of a single block.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+struct CompressedBlock {
+    /// Compressed receipts, ordered by transaction number.
+    data: Vec<u8>,
+    /// Range of every receipt in `data`, None if the receipt is pruned.
+    ranges: Vec<Option<Range<usize>>>,
+}
+
+impl CompressedReceipts {
+    /// Create empty receipts with capacity for the given number of blocks.
+    pub(crate) fn with_capacity(num_blocks: usize) -> Self {
+        Self { blocks: Vec::with_capacity(num_blocks) }
+    }
+
+    /// Number of blocks.
+    pub(crate) fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Number of receipts in all blocks, pruned receipts included.
+    pub(crate) fn total_count(&self) -> usize {
+        self.blocks.iter().map(|block| block.ranges.len()).sum()
+    }
+
+    /// Number of bytes of the compressed receipts of all blocks.
+    pub(crate) fn compressed_size(&self) -> usize {
+        self.blocks.iter().map(|block| block.data.len()).sum()
+    }
+
+    /// Compress receipts of a block and append it.
+    pub(crate) fn push(&mut self, receipts: &[Option<Receipt>]) {
+        self.blocks.push(CompressedBlock::new(receipts));
+    }
+
+    /// Decompress receipts of the block at the given index, or None if the block is not present.
+    pub(crate) fn block(
+        &self,
+        index: usize,
+    ) -> Option<Result<Vec<Option<Receipt>>, DatabaseError>> {
+        Some(self.blocks.get(index)?.decompress())
+    }
+
+    /// Decompress receipts of all blocks.
+    pub(crate) fn iter(
+        &self,
+    ) -> impl Iterator<Item = Result<Vec<Option<Receipt>>, DatabaseError>> + '_ {
+        self.blocks.iter().map(CompressedBlock::decompress)
+    }
+
+    /// Decompress receipts of all blocks, a receipt that fails to decode is returned as pruned.
+    pub(crate) fn iter_or_pruned(&self) -> impl Iterator<Item = Vec<Option<Receipt>>> + '_ {
+        self.blocks.iter().map(CompressedBlock::decompress_or_pruned)
+    }
+
+    /// Replace receipts of the block at the given index.
+    ///
+    /// # Panics
+    ///
+    /// If the block is not present.
+    pub(crate) fn set_block(&mut self, index: usize, receipts: &[Option<Receipt>]) {
+        self.blocks[index] = CompressedBlock::new(receipts);
+    }
+
+    /// Remove blocks starting from the given index and return them.
+    pub(crate) fn split_off(&mut self, index: usize) -> Self {
+        Self { blocks: self.blocks.split_off(index) }
+    }
+
+    /// Remove first `num_blocks` blocks.
+    pub(crate) fn remove_first(&mut self, num_blocks: usize) {
+        self.blocks.drain(..num_blocks);
+    }
+
+    /// Append blocks of `other`.
+    pub(crate) fn extend(&mut self, other: Self) {
+        self.blocks.extend(other.blocks);
+    }
+
+    /// Remove all blocks, keeping the allocated capacity.
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// Release excess capacity.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.blocks.shrink_to_fit();
+    }
+}
+
+impl CompressedBlock {
+    fn new(receipts: &[Option<Receipt>]) -> Self {
+        let mut block = Self::default();
+        for receipt in receipts {
+            let range = receipt.as_ref().map(|receipt| {
+                let start = block.data.len();
+                block.data.extend_from_slice(receipt.clone().compress().as_ref());
+                start..block.data.len()
+            });
+            block.ranges.push(range);
+        }
+        block
+    }
+
+    fn decompress(&self) -> Result<Vec<Option<Receipt>>, DatabaseError> {
+        self.ranges
+            .iter()
+            .map(|range| {
+                range.clone().map(|range| Receipt::decompress(&self.data[range])).transpose()
+            })
+            .collect()
+    }
+
+    fn decompress_or_pruned(&self) -> Vec<Option<Receipt>> {
+        self.ranges
+            .iter()
+            .map(|range| Receipt::decompress(&self.data[range.clone()?]).ok())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressedReceipts;
+    use crate::{bundle_state::WriteTables, BundleStateWithReceipts, ProviderFactory};
+    use reth_db::{
+        models::StoredBlockBodyIndices,
+        tables,
+        test_utils::create_test_rw_db,
+        transaction::{DbTx, DbTxMut},
+    };
+    use reth_primitives::{bloom::logs_bloom, Address, Log, Receipt, Receipts, H256, MAINNET};
+    use reth_revm_primitives::db::states::{BundleState, OriginalValuesKnown};
+
+    #[test]
+    fn compressed_receipts() {
+        let receipt = |gas| Some(Receipt { cumulative_gas_used: gas, ..Default::default() });
+        let blocks = vec![vec![receipt(1), None], vec![], vec![receipt(2)]];
+
+        let mut compressed = CompressedReceipts::default();
+        blocks.iter().for_each(|block| compressed.push(block));
+        assert_eq!((compressed.len(), compressed.total_count()), (3, 3));
+        assert!(compressed.compressed_size() > 0);
+        assert_eq!(compressed.block(0), Some(Ok(blocks[0].clone())));
+        assert_eq!(compressed.block(1), Some(Ok(Vec::new())));
+        assert_eq!(compressed.block(3), None);
+        assert_eq!(compressed.iter().collect::<Result<Vec<_>, _>>(), Ok(blocks.clone()));
+        assert_eq!(compressed.iter_or_pruned().collect::<Vec<_>>(), blocks.clone());
+
+        let mut lower = compressed.clone();
+        let upper = lower.split_off(1);
+        assert_eq!((lower.len(), upper.len()), (1, 2));
+        lower.extend(upper);
+        assert_eq!(lower, compressed);
+
+        compressed.set_block(1, &[receipt(3)]);
+        assert_eq!(compressed.block(1), Some(Ok(vec![receipt(3)])));
+        compressed.remove_first(2);
+        assert_eq!(compressed.block(0), Some(Ok(blocks[2].clone())));
+    }
+
+    #[test]
+    fn bundle_receipts_are_compressed() {
+        let log = |byte| Log {
+            address: Address::repeat_byte(byte),
+            topics: vec![H256::repeat_byte(byte)],
+            data: Default::default(),
+        };
+        let receipt = |gas, logs| Receipt { cumulative_gas_used: gas, logs, ..Default::default() };
+        let blocks = vec![
+            vec![Some(receipt(1, vec![log(1)])), None],
+            vec![],
+            vec![Some(receipt(2, vec![log(2), log(3)]))],
+        ];
+        let bundle = BundleStateWithReceipts::new(
+            BundleState::default(),
+            Receipts::from_vec(blocks.clone()),
+            1,
+        );
+
+        assert_eq!(bundle.receipts_by_block(1), Ok(blocks[0].clone()));
+        assert_eq!(bundle.receipts_by_block(2), Ok(Vec::new()));
+        assert_eq!(bundle.receipts_by_block(4), Ok(Vec::new()));
+        assert_eq!(bundle.logs(3), Ok(Some(vec![log(2), log(3)])));
+        assert_eq!(bundle.logs(4), Ok(None));
+        assert_eq!(bundle.block_logs_bloom(1), Some(logs_bloom(&[log(1)])));
+        assert_eq!(bundle.block_logs_bloom(3), Some(logs_bloom(&[log(2), log(3)])));
+        assert_eq!(bundle.block_logs_bloom(4), None);
+        assert!(bundle.receipts_root_slow(3).is_some());
+        assert_eq!(bundle.receipts_view().by_block(3), Some(&blocks[2][..]));
+        assert_eq!(bundle.receipts(), &Receipts::from_vec(blocks.clone()));
+
+        let factory = ProviderFactory::new(create_test_rw_db(), MAINNET.clone());
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+        for (block_number, first_tx_num, tx_count) in [(1, 0, 2), (2, 2, 0), (3, 2, 1)] {
+            tx.put::<tables::BlockBodyIndices>(
+                block_number,
+                StoredBlockBodyIndices { first_tx_num, tx_count },
+            )
+            .unwrap();
+        }
+        bundle.write_to_db(tx, OriginalValuesKnown::Yes, WriteTables::RECEIPTS).unwrap();
+        assert_eq!(tx.get::<tables::Receipts>(0).unwrap(), blocks[0][0]);
+        assert_eq!(tx.get::<tables::Receipts>(1).unwrap(), None);
+        assert_eq!(tx.get::<tables::Receipts>(2).unwrap(), blocks[2][0]);
+    }
+}
@@ -1,11 +1,26 @@
 //! Bundle state module.
 //! This module contains all the logic related to bundle state.
+mod bundle_receipts;
+mod bundle_state_builder;
 mod bundle_state_with_receipts;
+mod changesets_rlp;
+#[cfg(feature = "compressed-receipts")]
+mod compressed_receipts;
 mod state_changes;
 mod state_reverts;
 
+pub use bundle_receipts::BundleReceipts;
+pub use bundle_state_builder::BundleStateBuilder;
 pub use bundle_state_with_receipts::{
-    AccountRevertInit, BundleStateInit, BundleStateWithReceipts, OriginalValuesKnown, RevertsInit,
+    AccountRevertInit, AlignmentError, BlockRevertStats, BloomMismatch, BundleStateInit,
+    BundleStateWithReceipts, BundleWriteError, ConcatError, ContractCheckpoint, EffectiveDiff,
+    MergeError, MissingBytecode, OriginalValuesKnown, OutOfRange, PlainStateSnapshot,
+    ReceiptMismatch, RevertError, RevertsInit, TooManyLogs, VerifiedWriteError, WriteTables,
+};
+pub use changesets_rlp::{AccountChange, BundleChangesets, StorageChange, StorageSlotChange};
+#[cfg(feature = "compressed-receipts")]
+pub(crate) use compressed_receipts::CompressedReceipts;
+pub use state_changes::{
+    BytecodeSink, BytecodesTableSink, InMemoryState, PlainStateOp, PlainStorageOp, StateChanges,
 };
-pub use state_changes::StateChanges;
 pub use state_reverts::StateReverts;
@@ -1,3 +1,4 @@
+use crate::bundle_state::BundleWriteError;
 use rayon::slice::ParallelSliceMut;
 use reth_db::{
     cursor::{DbCursorRO, DbCursorRW, DbDupCursorRO, DbDupCursorRW},
@@ -5,11 +6,83 @@ use reth_db::{
     transaction::{DbTx, DbTxMut},
 };
 use reth_interfaces::db::DatabaseError;
-use reth_primitives::{Bytecode, StorageEntry, U256};
+use reth_primitives::{Account, Address, Bytecode, StorageEntry, H256, U256};
 use reth_revm_primitives::{
     db::states::{PlainStorageChangeset, StateChangeset},
     into_reth_acc,
+    primitives::{AccountInfo, Bytecode as RevmBytecode},
 };
+use std::collections::BTreeMap;
+
+/// Plain state held in memory, the in-memory counterpart of [tables::PlainAccountState],
+/// [tables::PlainStorageState] and [tables::Bytecodes].
+///
+/// See [StateChanges::apply_to_memory].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InMemoryState {
+    /// Accounts by address.
+    pub accounts: BTreeMap<Address, Account>,
+    /// Non-zero storage slots by address, accounts without storage have no entry.
+    pub storage: BTreeMap<Address, BTreeMap<H256, U256>>,
+    /// Bytecodes by hash.
+    pub bytecodes: BTreeMap<H256, Bytecode>,
+}
+
+/// Write of a single account to [tables::PlainAccountState], see [StateChanges::account_ops].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlainStateOp {
+    /// Insert or replace the account.
+    Upsert(Address, Account),
+    /// Delete the account if it exists.
+    Delete(Address),
+}
+
+/// Write of account storage to [tables::PlainStorageState], see [StateChanges::storage_ops].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlainStorageOp {
+    /// Delete all storage slots of the account.
+    Wipe(Address),
+    /// Insert or replace the non-zero storage slot.
+    Upsert(Address, StorageEntry),
+    /// Delete the storage slot if it exists.
+    Delete(Address, H256),
+}
+
+/// Destination of the bytecodes written by [StateChanges::write_to_db_with_bytecode_sink].
+///
+/// [BytecodesTableSink] writes them to [tables::Bytecodes], other implementations can persist
+/// them outside of the database transaction, for example to static files.
+pub trait BytecodeSink {
+    /// Write bytecode with the given code hash.
+    ///
+    /// Returns false if the bytecode was already present and was skipped.
+    fn write_bytecode(&mut self, hash: H256, bytecode: Bytecode) -> Result<bool, DatabaseError>;
+}
+
+impl<S: BytecodeSink + ?Sized> BytecodeSink for &mut S {
+    fn write_bytecode(&mut self, hash: H256, bytecode: Bytecode) -> Result<bool, DatabaseError> {
+        (**self).write_bytecode(hash, bytecode)
+    }
+}
+
+/// [BytecodeSink] writing to [tables::Bytecodes] through a write cursor, used by
+/// [StateChanges::write_to_db].
+#[derive(Debug)]
+pub struct BytecodesTableSink<C>(pub C);
+
+impl<'a, C> BytecodeSink for BytecodesTableSink<C>
+where
+    C: DbCursorRO<'a, tables::Bytecodes> + DbCursorRW<'a, tables::Bytecodes>,
+{
+    fn write_bytecode(&mut self, hash: H256, bytecode: Bytecode) -> Result<bool, DatabaseError> {
+        // Bytecodes are keyed by their hash, so an existing entry has the same code.
+        if self.0.seek_exact(hash)?.is_some() {
+            return Ok(false)
+        }
+        self.0.upsert(hash, bytecode)?;
+        Ok(true)
+    }
+}
 
 /// A change to the state of the world.
 #[derive(Debug, Default)]
@@ -22,67 +95,331 @@ impl From<StateChangeset> for StateChanges {
 }
 
 impl StateChanges {
+    /// Sort accounts, bytecodes and storage by key, and the storage slots of every account by slot.
+    ///
+    /// [Self::write_to_db] writes the changes in this order, so every table is written in
+    /// ascending key order. This keeps the write pattern reproducible and allows
+    /// [Self::write_to_db_append] to use `append` instead of `upsert` when the tables are empty.
+    pub fn sort(&mut self) {
+        self.0.accounts.par_sort_by_key(|a| a.0);
+        self.0.storage.par_sort_by_key(|a| a.address);
+        self.0.contracts.par_sort_by_key(|a| a.0);
+        for changeset in self.0.storage.iter_mut() {
+            changeset.storage.par_sort_unstable_by_key(|a| a.0);
+        }
+    }
+
+    /// Return the account writes that [Self::write_to_db] applies to
+    /// [tables::PlainAccountState], in the order of the changes.
+    ///
+    /// Changes should be sorted with [Self::sort] first to get the ops in ascending key order.
+    pub fn account_ops(&self) -> impl Iterator<Item = PlainStateOp> + '_ {
+        account_ops(&self.0.accounts)
+    }
+
+    /// Return the storage writes that [Self::write_to_db] applies to
+    /// [tables::PlainStorageState], in the order of the changes.
+    ///
+    /// [PlainStorageOp::Wipe] of an account comes before its slots and zero values delete the
+    /// slot. Changes should be sorted with [Self::sort] first to get the ops in ascending key
+    /// order.
+    pub fn storage_ops(&self) -> impl Iterator<Item = PlainStorageOp> + '_ {
+        storage_ops(&self.0.storage)
+    }
+
+    /// Return the number of accounts and storage slots [Self::write_to_db] deletes from
+    /// [tables::PlainAccountState] and [tables::PlainStorageState].
+    ///
+    /// Counts are taken from the changes, not from the database, so a deleted row may not exist.
+    /// Slots removed by a storage wipe are not counted. See
+    /// [BundleStateWithReceipts::changeset_row_counts](crate::BundleStateWithReceipts::changeset_row_counts)
+    /// for the rows added to the changesets.
+    pub fn plain_state_delete_counts(&self) -> (usize, usize) {
+        let accounts =
+            self.account_ops().filter(|op| matches!(op, PlainStateOp::Delete(_))).count();
+        let storage =
+            self.storage_ops().filter(|op| matches!(op, PlainStorageOp::Delete(..))).count();
+        (accounts, storage)
+    }
+
     /// Write the post state to the database.
+    ///
+    /// Changes are written in ascending key order, see [Self::sort].
+    ///
+    /// Returns the number of bytecodes that were already present in the database and were
+    /// skipped.
     pub fn write_to_db<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+    ) -> Result<usize, BundleWriteError> {
+        let bytecodes_cursor =
+            tx.cursor_write::<tables::Bytecodes>().map_err(BundleWriteError::Bytecodes)?;
+        self.write_to_db_with_bytecode_sink(tx, BytecodesTableSink(bytecodes_cursor))
+    }
+
+    /// Write the post state to the database like [Self::write_to_db], passing the bytecodes to
+    /// `sink` instead of writing them to [tables::Bytecodes].
+    ///
+    /// Returns the number of bytecodes that `sink` reported as already present.
+    pub fn write_to_db_with_bytecode_sink<'a, TX: DbTxMut<'a> + DbTx<'a>>(
         mut self,
         tx: &TX,
-    ) -> Result<(), DatabaseError> {
+        mut sink: impl BytecodeSink,
+    ) -> Result<usize, BundleWriteError> {
         // sort all entries so they can be written to database in more performant way.
         // and take smaller memory footprint.
-        self.0.accounts.par_sort_by_key(|a| a.0);
-        self.0.storage.par_sort_by_key(|a| a.address);
-        self.0.contracts.par_sort_by_key(|a| a.0);
+        self.sort();
 
         // Write new account state
         tracing::trace!(target: "provider::post_state", len = self.0.accounts.len(), "Writing new account state");
-        let mut accounts_cursor = tx.cursor_write::<tables::PlainAccountState>()?;
-        // write account to database.
-        for (address, account) in self.0.accounts.into_iter() {
+        write_accounts(tx, &self.0.accounts).map_err(BundleWriteError::PlainAccounts)?;
+
+        // Write bytecode
+        let deduplicated_bytecodes =
+            write_bytecodes(&mut sink, self.0.contracts).map_err(BundleWriteError::Bytecodes)?;
+
+        // Write new storage state and wipe storage if needed.
+        tracing::trace!(target: "provider::post_state", len = self.0.storage.len(), "Writing new storage state");
+        write_storage(tx, &self.0.storage).map_err(BundleWriteError::PlainStorage)?;
+
+        Ok(deduplicated_bytecodes)
+    }
+
+    /// Apply the post state to an in-memory plain state.
+    ///
+    /// Same as [Self::write_to_db], destroyed accounts are removed, wiped storage is cleared
+    /// before the slots are set and zero values remove the slot.
+    pub fn apply_to_memory(self, state: &mut InMemoryState) {
+        for (address, account) in self.0.accounts {
             if let Some(account) = account {
-                tracing::trace!(target: "provider::post_state", ?address, "Updating plain state account");
-                accounts_cursor.upsert(address, into_reth_acc(account))?;
-            } else if accounts_cursor.seek_exact(address)?.is_some() {
-                tracing::trace!(target: "provider::post_state", ?address, "Deleting plain state account");
-                accounts_cursor.delete_current()?;
+                state.accounts.insert(address, into_reth_acc(account));
+            } else {
+                state.accounts.remove(&address);
             }
         }
 
-        // Write bytecode
-        tracing::trace!(target: "provider::post_state", len = self.0.contracts.len(), "Writing bytecodes");
-        let mut bytecodes_cursor = tx.cursor_write::<tables::Bytecodes>()?;
-        for (hash, bytecode) in self.0.contracts.into_iter() {
-            bytecodes_cursor.upsert(hash, Bytecode(bytecode))?;
+        for (hash, bytecode) in self.0.contracts {
+            state.bytecodes.entry(hash).or_insert(Bytecode(bytecode));
         }
 
-        // Write new storage state and wipe storage if needed.
-        tracing::trace!(target: "provider::post_state", len = self.0.storage.len(), "Writing new storage state");
-        let mut storages_cursor = tx.cursor_dup_write::<tables::PlainStorageState>()?;
-        for PlainStorageChangeset { address, wipe_storage, storage } in self.0.storage.into_iter() {
-            // Wiping of storage.
-            if wipe_storage && storages_cursor.seek_exact(address)?.is_some() {
-                storages_cursor.delete_current_duplicates()?;
+        for PlainStorageChangeset { address, wipe_storage, storage } in self.0.storage {
+            let slots = state.storage.entry(address).or_default();
+            if wipe_storage {
+                slots.clear();
             }
-            // cast storages to H256.
-            let mut storage = storage
-                .into_iter()
-                .map(|(k, value)| StorageEntry { key: k.into(), value })
-                .collect::<Vec<_>>();
-            // sort storage slots by key.
-            storage.par_sort_unstable_by_key(|a| a.key);
-
-            for entry in storage.into_iter() {
-                tracing::trace!(target: "provider::post_state", ?address, ?entry.key, "Updating plain state storage");
-                if let Some(db_entry) = storages_cursor.seek_by_key_subkey(address, entry.key)? {
-                    if db_entry.key == entry.key {
-                        storages_cursor.delete_current()?;
-                    }
+            for (key, value) in storage {
+                let key: H256 = key.into();
+                // Zero values are represented by the absence of the slot.
+                if value == U256::ZERO {
+                    slots.remove(&key);
+                } else {
+                    slots.insert(key, value);
                 }
+            }
+            if slots.is_empty() {
+                state.storage.remove(&address);
+            }
+        }
+    }
+
+    /// Write the post state to the database by appending to the plain state tables.
+    ///
+    /// [tables::PlainAccountState] and [tables::PlainStorageState] have to be empty, for example
+    /// during genesis import. Sorted changes are appended without looking up existing entries,
+    /// destroyed accounts and zero storage values are skipped as there is nothing to delete. If
+    /// either table is not empty, this falls back to [Self::write_to_db].
+    ///
+    /// Returns the number of bytecodes that were already present in the database and were
+    /// skipped.
+    pub fn write_to_db_append<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        mut self,
+        tx: &TX,
+    ) -> Result<usize, BundleWriteError> {
+        let accounts =
+            tx.entries::<tables::PlainAccountState>().map_err(BundleWriteError::PlainAccounts)?;
+        let storage =
+            tx.entries::<tables::PlainStorageState>().map_err(BundleWriteError::PlainStorage)?;
+        if accounts != 0 || storage != 0 {
+            tracing::trace!(target: "provider::post_state", "Plain state is not empty, upserting");
+            return self.write_to_db(tx)
+        }
+
+        self.sort();
+
+        tracing::trace!(target: "provider::post_state", len = self.0.accounts.len(), "Appending new account state");
+        append_accounts(tx, self.0.accounts).map_err(BundleWriteError::PlainAccounts)?;
+
+        let bytecodes_cursor =
+            tx.cursor_write::<tables::Bytecodes>().map_err(BundleWriteError::Bytecodes)?;
+        let deduplicated_bytecodes =
+            write_bytecodes(&mut BytecodesTableSink(bytecodes_cursor), self.0.contracts)
+                .map_err(BundleWriteError::Bytecodes)?;
+
+        tracing::trace!(target: "provider::post_state", len = self.0.storage.len(), "Appending new storage state");
+        append_storage(tx, self.0.storage).map_err(BundleWriteError::PlainStorage)?;
+
+        Ok(deduplicated_bytecodes)
+    }
+}
+
+/// Map account changes to [PlainStateOp]s, destroyed accounts are deleted.
+fn account_ops(
+    accounts: &[(Address, Option<AccountInfo>)],
+) -> impl Iterator<Item = PlainStateOp> + '_ {
+    accounts.iter().map(|(address, account)| match account {
+        Some(account) => PlainStateOp::Upsert(*address, into_reth_acc(account.clone())),
+        None => PlainStateOp::Delete(*address),
+    })
+}
+
+/// Map storage changes to [PlainStorageOp]s, zero values delete the slot.
+fn storage_ops(
+    storage_changes: &[PlainStorageChangeset],
+) -> impl Iterator<Item = PlainStorageOp> + '_ {
+    storage_changes.iter().flat_map(|PlainStorageChangeset { address, wipe_storage, storage }| {
+        let address = *address;
+        let wipe = wipe_storage.then_some(PlainStorageOp::Wipe(address));
+        // cast storages to H256.
+        let slots = storage.iter().map(move |(key, value)| {
+            let key = H256::from(*key);
+            // Zero values are represented by the absence of the slot.
+            if *value == U256::ZERO {
+                PlainStorageOp::Delete(address, key)
+            } else {
+                PlainStorageOp::Upsert(address, StorageEntry { key, value: *value })
+            }
+        });
+        wipe.into_iter().chain(slots)
+    })
+}
+
+/// Write sorted account changes to [tables::PlainAccountState], destroyed accounts are deleted.
+fn write_accounts<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    accounts: &[(Address, Option<AccountInfo>)],
+) -> Result<(), DatabaseError> {
+    let rows = accounts.len();
+    let _span =
+        tracing::info_span!(target: "provider::post_state", "write_plain_accounts", rows).entered();
+    let mut accounts_cursor = tx.cursor_write::<tables::PlainAccountState>()?;
+    // write account to database.
+    for op in account_ops(accounts) {
+        match op {
+            PlainStateOp::Upsert(address, account) => {
+                tracing::trace!(target: "provider::post_state", ?address, "Updating plain state account");
+                accounts_cursor.upsert(address, account)?;
+            }
+            PlainStateOp::Delete(address) => {
+                if accounts_cursor.seek_exact(address)?.is_some() {
+                    tracing::trace!(target: "provider::post_state", ?address, "Deleting plain state account");
+                    accounts_cursor.delete_current()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write sorted storage changes to [tables::PlainStorageState] and wipe storage if needed.
+fn write_storage<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    storage_changes: &[PlainStorageChangeset],
+) -> Result<(), DatabaseError> {
+    let rows = storage_changes.iter().map(|changeset| changeset.storage.len()).sum::<usize>();
+    let _span =
+        tracing::info_span!(target: "provider::post_state", "write_plain_storage", rows).entered();
+    let mut storages_cursor = tx.cursor_dup_write::<tables::PlainStorageState>()?;
+    // Account whose storage was wiped, there is nothing left to delete for it.
+    let mut wiped = None;
+    for op in storage_ops(storage_changes) {
+        let (address, entry) = match op {
+            PlainStorageOp::Wipe(address) => {
+                wiped = Some(address);
+                if storages_cursor.seek_exact(address)?.is_some() {
+                    storages_cursor.delete_current_duplicates()?;
+                }
+                continue
+            }
+            PlainStorageOp::Upsert(address, entry) => (address, entry),
+            PlainStorageOp::Delete(address, key) => {
+                (address, StorageEntry { key, value: U256::ZERO })
+            }
+        };
 
-                if entry.value != U256::ZERO {
-                    storages_cursor.upsert(address, entry)?;
+        tracing::trace!(target: "provider::post_state", ?address, ?entry.key, "Updating plain state storage");
+        if wiped != Some(address) {
+            if let Some(db_entry) = storages_cursor
+                .seek_by_key_subkey(address, entry.key)?
+                .filter(|db_entry| db_entry.key == entry.key)
+            {
+                // Value is unchanged, skip the delete and re-insert.
+                if db_entry.value == entry.value {
+                    continue
                 }
+                storages_cursor.delete_current()?;
             }
         }
-        Ok(())
+
+        // Zero values are represented by the absence of the slot.
+        if entry.value != U256::ZERO {
+            storages_cursor.upsert(address, entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Append sorted present accounts to an empty [tables::PlainAccountState].
+fn append_accounts<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    accounts: Vec<(Address, Option<AccountInfo>)>,
+) -> Result<(), DatabaseError> {
+    let rows = accounts.len();
+    let _span = tracing::info_span!(target: "provider::post_state", "append_plain_accounts", rows)
+        .entered();
+    let mut accounts_cursor = tx.cursor_write::<tables::PlainAccountState>()?;
+    for (address, account) in accounts.into_iter() {
+        if let Some(account) = account {
+            accounts_cursor.append(address, into_reth_acc(account))?;
+        }
+    }
+    Ok(())
+}
+
+/// Append sorted non-zero storage to an empty [tables::PlainStorageState].
+fn append_storage<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    storage_changes: Vec<PlainStorageChangeset>,
+) -> Result<(), DatabaseError> {
+    let rows = storage_changes.iter().map(|changeset| changeset.storage.len()).sum::<usize>();
+    let _span =
+        tracing::info_span!(target: "provider::post_state", "append_plain_storage", rows).entered();
+    let mut storages_cursor = tx.cursor_dup_write::<tables::PlainStorageState>()?;
+    for PlainStorageChangeset { address, storage, .. } in storage_changes.into_iter() {
+        for (key, value) in storage.into_iter().filter(|(_, value)| *value != U256::ZERO) {
+            storages_cursor.append_dup(address, StorageEntry { key: key.into(), value })?;
+        }
+    }
+    Ok(())
+}
+
+/// Write bytecodes to the sink.
+///
+/// Returns the number of bytecodes that were already present and were skipped.
+fn write_bytecodes(
+    sink: &mut impl BytecodeSink,
+    contracts: Vec<(H256, RevmBytecode)>,
+) -> Result<usize, DatabaseError> {
+    let contracts_len = contracts.len();
+    let _span =
+        tracing::info_span!(target: "provider::post_state", "write_bytecodes", rows = contracts_len)
+            .entered();
+    tracing::trace!(target: "provider::post_state", len = contracts_len, "Writing bytecodes");
+    let mut deduplicated_bytecodes = 0;
+    for (hash, bytecode) in contracts.into_iter() {
+        if !sink.write_bytecode(hash, Bytecode(bytecode))? {
+            deduplicated_bytecodes += 1;
+        }
     }
+    tracing::trace!(target: "provider::post_state", len = contracts_len, deduplicated_bytecodes, "Bytecodes written");
+    Ok(deduplicated_bytecodes)
 }
@@ -1,3 +1,4 @@
+use crate::bundle_state::BundleWriteError;
 use rayon::slice::ParallelSliceMut;
 use reth_db::{
     cursor::{DbCursorRO, DbDupCursorRO, DbDupCursorRW},
@@ -6,12 +7,20 @@ use reth_db::{
     transaction::{DbTx, DbTxMut},
 };
 use reth_interfaces::db::DatabaseError;
-use reth_primitives::{BlockNumber, StorageEntry, H256, U256};
+use reth_primitives::{Address, BlockNumber, StorageEntry, H256, U256};
 use reth_revm_primitives::{
     db::states::{PlainStateReverts, PlainStorageRevert, RevertToSlot},
     into_reth_acc,
+    primitives::AccountInfo,
 };
-use std::iter::Peekable;
+use std::{
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+};
+
+/// Number of wiped storage slots read from [tables::PlainStorageState] at once by
+/// [StateReverts::write_to_db].
+const WIPED_STORAGE_BATCH_SIZE: usize = 1024;
 
 /// Revert of the state.
 #[derive(Debug, Default)]
@@ -27,70 +36,225 @@ impl StateReverts {
     /// Write reverts to database.
     ///
     /// Note:: Reverts will delete all wiped storage from plain state.
+    ///
+    /// Wiped storage of accounts that did not exist before the first block is known to be empty,
+    /// it is not read from the plain state.
     pub fn write_to_db<'a, TX: DbTxMut<'a> + DbTx<'a>>(
         self,
         tx: &TX,
         first_block: BlockNumber,
-    ) -> Result<(), DatabaseError> {
+    ) -> Result<(), BundleWriteError> {
+        self.write_to_db_with_batch_size(tx, first_block, WIPED_STORAGE_BATCH_SIZE)
+    }
+
+    /// Write reverts to database, reading wiped storage from the plain state in batches of
+    /// `wiped_storage_batch_size` slots.
+    ///
+    /// Same as [Self::write_to_db], which reads 1024 slots at once. Every batch is merged with the
+    /// storage reverts of the account before the next batch is read, so only one batch of the
+    /// wiped storage is held in memory.
+    ///
+    /// # Panics
+    ///
+    /// If `wiped_storage_batch_size` is zero.
+    pub fn write_to_db_with_batch_size<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+        self,
+        tx: &TX,
+        first_block: BlockNumber,
+        wiped_storage_batch_size: usize,
+    ) -> Result<(), BundleWriteError> {
+        assert!(wiped_storage_batch_size > 0, "wiped storage batch size must not be zero");
+        let not_existing = not_existing_accounts(&self.0.accounts);
+
         // Write storage changes
         tracing::trace!(target: "provider::reverts", "Writing storage changes");
-        let mut storages_cursor = tx.cursor_dup_write::<tables::PlainStorageState>()?;
-        let mut storage_changeset_cursor = tx.cursor_dup_write::<tables::StorageChangeSet>()?;
-        for (block_index, mut storage_changes) in self.0.storage.into_iter().enumerate() {
+        write_storage_reverts(
+            tx,
+            self.0.storage,
+            first_block,
+            &not_existing,
+            wiped_storage_batch_size,
+        )
+        .map_err(BundleWriteError::StorageReverts)?;
+
+        // Write account changes
+        tracing::trace!(target: "provider::reverts", "Writing account changes");
+        write_account_reverts(tx, self.0.accounts, first_block)
+            .map_err(BundleWriteError::AccountReverts)?;
+
+        Ok(())
+    }
+
+    /// Count the wiped storage slots per block and address.
+    ///
+    /// For every wiped storage revert, this returns the number of entries in
+    /// [tables::PlainStorageState] that [Self::write_to_db] will read and move into the storage
+    /// changeset. Nothing is written or deleted.
+    pub fn wiped_storage_counts<'a, TX: DbTx<'a>>(
+        &self,
+        tx: &TX,
+        first_block: BlockNumber,
+    ) -> Result<Vec<(BlockNumber, Address, usize)>, DatabaseError> {
+        let not_existing = not_existing_accounts(&self.0.accounts);
+        let mut storages_cursor = tx.cursor_dup_read::<tables::PlainStorageState>()?;
+        let mut counts = Vec::new();
+        for (block_index, storage_changes) in self.0.storage.iter().enumerate() {
             let block_number = first_block + block_index as BlockNumber;
+            for revert in storage_changes.iter().filter(|revert| revert.wiped) {
+                let mut count = 0;
+                if !not_existing.contains(&revert.address) {
+                    walk_wiped_storage(&mut storages_cursor, revert.address, |_| count += 1)?;
+                }
+                counts.push((block_number, revert.address, count));
+            }
+        }
+        counts.sort_unstable_by_key(|(block_number, address, _)| (*block_number, *address));
+        Ok(counts)
+    }
+}
+
+/// Write storage reverts to [tables::StorageChangeSet], together with the plain storage of wiped
+/// accounts that existed before the first block.
+fn write_storage_reverts<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    storage_reverts: Vec<Vec<PlainStorageRevert>>,
+    first_block: BlockNumber,
+    not_existing: &HashSet<Address>,
+    wiped_storage_batch_size: usize,
+) -> Result<(), DatabaseError> {
+    // Slots of wiped storage read from the plain state are not counted.
+    let rows =
+        storage_reverts.iter().flatten().map(|revert| revert.storage_revert.len()).sum::<usize>();
+    let _span =
+        tracing::info_span!(target: "provider::reverts", "write_storage_reverts", rows).entered();
+    let mut storages_cursor = tx.cursor_dup_write::<tables::PlainStorageState>()?;
+    let mut storage_changeset_cursor = tx.cursor_dup_write::<tables::StorageChangeSet>()?;
+    for (block_index, mut storage_changes) in storage_reverts.into_iter().enumerate() {
+        if storage_changes.is_empty() {
+            continue
+        }
+        let block_number = first_block + block_index as BlockNumber;
+
+        tracing::trace!(target: "provider::reverts", block_number, "Writing block change");
+        // sort changes by address.
+        storage_changes.par_sort_unstable_by_key(|a| a.address);
+        for PlainStorageRevert { address, wiped, storage_revert } in storage_changes.into_iter() {
+            let storage_id = BlockNumberAddress((block_number, address));
 
-            tracing::trace!(target: "provider::reverts", block_number, "Writing block change");
-            // sort changes by address.
-            storage_changes.par_sort_unstable_by_key(|a| a.address);
-            for PlainStorageRevert { address, wiped, storage_revert } in storage_changes.into_iter()
-            {
-                let storage_id = BlockNumberAddress((block_number, address));
-
-                let mut storage = storage_revert
-                    .into_iter()
-                    .map(|(k, v)| (H256(k.to_be_bytes()), v))
-                    .collect::<Vec<_>>();
-                // sort storage slots by key.
-                storage.par_sort_unstable_by_key(|a| a.0);
-
-                // If we are writing the primary storage wipe transition, the pre-existing plain
-                // storage state has to be taken from the database and written to storage history.
-                // See [StorageWipe::Primary] for more details.
-                let mut wiped_storage = Vec::new();
+            let mut storage = storage_revert
+                .into_iter()
+                .map(|(k, v)| (H256(k.to_be_bytes()), v))
+                .collect::<Vec<_>>();
+            // sort storage slots by key.
+            storage.par_sort_unstable_by_key(|a| a.0);
+
+            tracing::trace!(target: "provider::reverts", ?address, ?storage, "Writing storage reverts");
+            // If we are writing the primary storage wipe transition, the pre-existing plain
+            // storage state has to be taken from the database and written to storage history.
+            // See [StorageWipe::Primary] for more details.
+            if !wiped || not_existing.contains(&address) {
                 if wiped {
-                    tracing::trace!(target: "provider::reverts", ?address, "Wiping storage");
-                    if let Some((_, entry)) = storages_cursor.seek_exact(address)? {
-                        wiped_storage.push((entry.key, entry.value));
-                        while let Some(entry) = storages_cursor.next_dup_val()? {
-                            wiped_storage.push((entry.key, entry.value))
-                        }
-                    }
+                    tracing::trace!(target: "provider::reverts", ?address, "Skipping wipe of empty storage");
                 }
+                for (key, revert) in storage {
+                    let value = revert.to_previous_value();
+                    storage_changeset_cursor.append_dup(storage_id, StorageEntry { key, value })?;
+                }
+                continue
+            }
 
-                tracing::trace!(target: "provider::reverts", ?address, ?storage, "Writing storage reverts");
-                for (key, value) in StorageRevertsIter::new(storage, wiped_storage) {
+            tracing::trace!(target: "provider::reverts", ?address, "Wiping storage");
+            let mut reverts = storage.into_iter().peekable();
+            // Walker is only created for an existing address, as it would otherwise continue from
+            // the previous position of the cursor.
+            let mut wiped_storage = if storages_cursor.seek_exact(address)?.is_some() {
+                Some(storages_cursor.walk_dup(Some(address), None)?)
+            } else {
+                None
+            };
+            loop {
+                let batch = match wiped_storage.as_mut() {
+                    Some(walker) => walker
+                        .by_ref()
+                        .take(wiped_storage_batch_size)
+                        .map(|entry| entry.map(|(_, entry)| (entry.key, entry.value)))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    None => Vec::new(),
+                };
+                let is_last_batch = batch.len() < wiped_storage_batch_size;
+                let last_key = batch.last().map(|(key, _)| *key);
+                // Both sides are sorted, reverts after the last slot of the batch are merged with
+                // the next batch.
+                let batch_reverts = std::iter::from_fn(|| {
+                    reverts.next_if(|(key, _)| is_last_batch || Some(*key) <= last_key)
+                });
+                for (key, value) in StorageRevertsIter::new(batch_reverts, batch) {
                     storage_changeset_cursor.append_dup(storage_id, StorageEntry { key, value })?;
                 }
+                if is_last_batch {
+                    break
+                }
             }
         }
+    }
+    Ok(())
+}
 
-        // Write account changes
-        tracing::trace!(target: "provider::reverts", "Writing account changes");
-        let mut account_changeset_cursor = tx.cursor_dup_write::<tables::AccountChangeSet>()?;
-        for (block_index, mut account_block_reverts) in self.0.accounts.into_iter().enumerate() {
-            let block_number = first_block + block_index as BlockNumber;
-            // Sort accounts by address.
-            account_block_reverts.par_sort_by_key(|a| a.0);
-            for (address, info) in account_block_reverts {
-                account_changeset_cursor.append_dup(
-                    block_number,
-                    AccountBeforeTx { address, info: info.map(into_reth_acc) },
-                )?;
-            }
+/// Write account reverts to [tables::AccountChangeSet].
+fn write_account_reverts<'a, TX: DbTxMut<'a> + DbTx<'a>>(
+    tx: &TX,
+    account_reverts: Vec<Vec<(Address, Option<AccountInfo>)>>,
+    first_block: BlockNumber,
+) -> Result<(), DatabaseError> {
+    let rows = account_reverts.iter().map(Vec::len).sum::<usize>();
+    let _span =
+        tracing::info_span!(target: "provider::reverts", "write_account_reverts", rows).entered();
+    let mut account_changeset_cursor = tx.cursor_dup_write::<tables::AccountChangeSet>()?;
+    for (block_index, mut account_block_reverts) in account_reverts.into_iter().enumerate() {
+        if account_block_reverts.is_empty() {
+            continue
+        }
+        let block_number = first_block + block_index as BlockNumber;
+        // Sort accounts by address.
+        account_block_reverts.par_sort_by_key(|a| a.0);
+        for (address, info) in account_block_reverts {
+            account_changeset_cursor.append_dup(
+                block_number,
+                AccountBeforeTx { address, info: info.map(into_reth_acc) },
+            )?;
         }
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Return accounts that did not exist before the first block of the account reverts.
+///
+/// The first revert of an account holds its info before the first block, as the account was not
+/// changed in earlier blocks. Plain state has no storage for accounts that do not exist.
+fn not_existing_accounts(accounts: &[Vec<(Address, Option<AccountInfo>)>]) -> HashSet<Address> {
+    let mut existed = HashMap::new();
+    for (address, info) in accounts.iter().flatten() {
+        existed.entry(*address).or_insert(info.is_some());
+    }
+    existed.into_iter().filter(|(_, existed)| !existed).map(|(address, _)| address).collect()
+}
+
+/// Walk all plain storage entries of the given address and pass them to `f`.
+fn walk_wiped_storage<'a, C>(
+    cursor: &mut C,
+    address: Address,
+    mut f: impl FnMut(StorageEntry),
+) -> Result<(), DatabaseError>
+where
+    C: DbCursorRO<'a, tables::PlainStorageState> + DbDupCursorRO<'a, tables::PlainStorageState>,
+{
+    if let Some((_, entry)) = cursor.seek_exact(address)? {
+        f(entry);
+        while let Some(entry) = cursor.next_dup_val()? {
+            f(entry);
+        }
     }
+    Ok(())
 }
 
 /// Iterator over storage reverts.
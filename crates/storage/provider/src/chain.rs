@@ -137,7 +137,13 @@ impl Chain {
     /// Get all receipts for the given block.
     pub fn receipts_by_block_hash(&self, block_hash: BlockHash) -> Option<Vec<&Receipt>> {
         let num = self.block_number(block_hash)?;
-        self.state.receipts_by_block(num).iter().map(Option::as_ref).collect()
+        self.state
+            .receipts_view()
+            .by_block(num)
+            .unwrap_or_default()
+            .iter()
+            .map(Option::as_ref)
+            .collect()
     }
 
     /// Get all receipts with attachment.
@@ -145,7 +151,9 @@ impl Chain {
     /// Attachment includes block number, block hash, transaction hash and transaction index.
     pub fn receipts_with_attachment(&self) -> Vec<BlockReceipts> {
         let mut receipt_attch = Vec::new();
-        for ((block_num, block), receipts) in self.blocks().iter().zip(self.state.receipts().iter())
+        let receipts_view = self.state.receipts_view();
+        for ((block_num, block), (_, receipts)) in
+            self.blocks().iter().zip(receipts_view.iter_blocks())
         {
             let mut tx_receipts = Vec::new();
             for (tx, receipt) in block.body.iter().zip(receipts.iter()) {
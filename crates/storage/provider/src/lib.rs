@@ -3,6 +3,8 @@
 //! ## Feature Flags
 //!
 //! - `test-utils`: Export utilities for testing
+//! - `compressed-receipts`: Store the receipts of a bundle state compressed in memory and
+//!   decompress them on access
 
 #![doc(
     html_logo_url = "https://raw.githubusercontent.com/paradigmxyz/reth/main/assets/reth-docs.png",
@@ -45,4 +47,6 @@ pub mod chain;
 pub use chain::{Chain, DisplayBlocksChain};
 
 pub mod bundle_state;
-pub use bundle_state::{BundleStateWithReceipts, OriginalValuesKnown, StateChanges, StateReverts};
+pub use bundle_state::{
+    BundleStateWithReceipts, OriginalValuesKnown, StateChanges, StateReverts, WriteTables,
+};
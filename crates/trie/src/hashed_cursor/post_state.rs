@@ -6,7 +6,7 @@ use reth_db::{
     transaction::{DbTx, DbTxGAT},
 };
 use reth_primitives::{trie::Nibbles, Account, StorageEntry, H256, U256};
-use std::collections::{HashMap, HashSet};
+use std::collections::{hash_map, HashMap, HashSet};
 
 /// The post state account storage with hashed slots.
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -32,10 +32,13 @@ impl HashedStorage {
         }
     }
 
-    /// Sorts the non zero value storage entries.
+    /// Sorts the non zero value storage entries by hashed slot.
+    ///
+    /// If a slot was inserted more than once only the last inserted value is kept, so the sorted
+    /// entries only depend on the inserted values and not on the order of the distinct slots.
     pub fn sort_storage(&mut self) {
         if !self.sorted {
-            self.non_zero_valued_storage.sort_unstable_by_key(|(slot, _)| *slot);
+            sort_dedup_last(&mut self.non_zero_valued_storage);
             self.sorted = true;
         }
     }
@@ -51,6 +54,28 @@ impl HashedStorage {
     pub fn insert_zero_valued_slot(&mut self, slot: H256) {
         self.zero_valued_slots.insert(slot);
     }
+
+    /// Extend with the entries of the newer storage. Slots of `other` take precedence, if
+    /// `other` was wiped it replaces this storage entirely.
+    pub fn extend(&mut self, other: Self) {
+        if other.wiped {
+            *self = other;
+            return
+        }
+
+        let other_slots = other
+            .non_zero_valued_storage
+            .iter()
+            .map(|(slot, _)| *slot)
+            .chain(other.zero_valued_slots.iter().copied())
+            .collect::<HashSet<_>>();
+        self.non_zero_valued_storage.retain(|(slot, _)| !other_slots.contains(slot));
+        self.zero_valued_slots.retain(|slot| !other_slots.contains(slot));
+
+        self.sorted &= other.non_zero_valued_storage.is_empty();
+        self.non_zero_valued_storage.extend(other.non_zero_valued_storage);
+        self.zero_valued_slots.extend(other.zero_valued_slots);
+    }
 }
 
 /// The post state with hashed addresses as keys.
@@ -84,14 +109,16 @@ impl HashedPostState {
         self
     }
 
-    /// Sort account and storage entries.
+    /// Sort account and storage entries by hashed key, see [HashedStorage::sort_storage].
+    ///
+    /// If an account was inserted more than once only the last inserted account is kept.
     pub fn sort(&mut self) {
         if !self.sorted {
             for (_, storage) in self.storages.iter_mut() {
                 storage.sort_storage();
             }
 
-            self.accounts.sort_unstable_by_key(|(address, _)| *address);
+            sort_dedup_last(&mut self.accounts);
             self.sorted = true;
         }
     }
@@ -113,6 +140,37 @@ impl HashedPostState {
         self.storages.insert(hashed_address, hashed_storage);
     }
 
+    /// Extend with the entries of the newer post state. Accounts and storage slots of `other`
+    /// take precedence.
+    pub fn extend(&mut self, other: Self) {
+        let other_accounts = other
+            .accounts
+            .iter()
+            .map(|(hashed_address, _)| *hashed_address)
+            .chain(other.cleared_accounts.iter().copied())
+            .collect::<HashSet<_>>();
+        self.accounts.retain(|(hashed_address, _)| !other_accounts.contains(hashed_address));
+        self.cleared_accounts.retain(|hashed_address| !other_accounts.contains(hashed_address));
+
+        self.sorted &= other.accounts.is_empty();
+        self.accounts.extend(other.accounts);
+        self.cleared_accounts.extend(other.cleared_accounts);
+
+        for (hashed_address, hashed_storage) in other.storages {
+            match self.storages.entry(hashed_address) {
+                hash_map::Entry::Vacant(entry) => {
+                    self.sorted &= hashed_storage.sorted;
+                    entry.insert(hashed_storage);
+                }
+                hash_map::Entry::Occupied(mut entry) => {
+                    let storage = entry.get_mut();
+                    storage.extend(hashed_storage);
+                    self.sorted &= storage.sorted;
+                }
+            }
+        }
+    }
+
     /// Construct (PrefixSet)[PrefixSet] from hashed post state.
     /// The prefix sets contain the hashed account and storage keys that have been changed in the
     /// post state.
@@ -149,6 +207,22 @@ impl HashedPostState {
     }
 }
 
+/// Sort entries by key, keeping only the last inserted entry of every key.
+///
+/// The sort is stable, so entries with equal keys stay in insertion order until they are
+/// deduplicated.
+fn sort_dedup_last<T>(entries: &mut Vec<(H256, T)>) {
+    entries.sort_by_key(|(key, _)| *key);
+    entries.dedup_by(|later, earlier| {
+        if later.0 == earlier.0 {
+            std::mem::swap(later, earlier);
+            true
+        } else {
+            false
+        }
+    });
+}
+
 /// The hashed cursor factory for the post state.
 #[derive(Debug)]
 pub struct HashedPostStateCursorFactory<'a, 'b, TX> {
@@ -745,6 +819,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn extend_post_state() {
+        let address_a = H256::random();
+        let address_b = H256::random();
+        let slot_a = H256::random();
+        let slot_b = H256::random();
+
+        let mut post_state = HashedPostState::default();
+        post_state.insert_account(address_a, Account { nonce: 1, ..Default::default() });
+        post_state.insert_cleared_account(address_b);
+        let mut storage = HashedStorage::new(false);
+        storage.insert_non_zero_valued_storage(slot_a, U256::from(1));
+        storage.insert_non_zero_valued_storage(slot_b, U256::from(2));
+        post_state.insert_hashed_storage(address_a, storage);
+
+        // Account A is destroyed and slot A is zeroed, account B is recreated.
+        let mut newer = HashedPostState::default();
+        newer.insert_cleared_account(address_a);
+        newer.insert_account(address_b, Account { nonce: 2, ..Default::default() });
+        let mut storage = HashedStorage::new(false);
+        storage.insert_zero_valued_slot(slot_a);
+        newer.insert_hashed_storage(address_a, storage);
+
+        post_state.extend(newer);
+        post_state.sort();
+
+        let mut expected = HashedPostState::default();
+        expected.insert_cleared_account(address_a);
+        expected.insert_account(address_b, Account { nonce: 2, ..Default::default() });
+        let mut storage = HashedStorage::new(false);
+        storage.insert_non_zero_valued_storage(slot_b, U256::from(2));
+        storage.insert_zero_valued_slot(slot_a);
+        expected.insert_hashed_storage(address_a, storage);
+        assert_eq!(post_state, expected.sorted());
+    }
+
     #[test]
     fn storage_is_empty() {
         let address = H256::random();
@@ -1027,4 +1137,63 @@ mod tests {
             assert_storage_cursor_order(&factory, expected.into_iter());
         });
     }
+
+    #[test]
+    fn sort_is_independent_of_insertion_order() {
+        proptest!(ProptestConfig::with_cases(10),
+            |(accounts: BTreeMap<H256, Account>, storages: BTreeMap<H256, BTreeMap<H256, U256>>)|
+        {
+            let hashed_post_state = |reverse: bool| {
+                let mut hashed_post_state = HashedPostState::default();
+                let mut accounts = Vec::from_iter(accounts.iter());
+                let mut storages = Vec::from_iter(storages.iter());
+                if reverse {
+                    accounts.reverse();
+                    storages.reverse();
+                }
+                for (hashed_address, account) in accounts {
+                    hashed_post_state.insert_account(*hashed_address, *account);
+                }
+                for (hashed_address, storage) in storages {
+                    let mut slots = Vec::from_iter(storage.iter());
+                    if reverse {
+                        slots.reverse();
+                    }
+                    let mut hashed_storage = HashedStorage::new(false);
+                    for (slot, value) in slots {
+                        if *value == U256::ZERO {
+                            hashed_storage.insert_zero_valued_slot(*slot);
+                        } else {
+                            hashed_storage.insert_non_zero_valued_storage(*slot, *value);
+                        }
+                    }
+                    hashed_post_state.insert_hashed_storage(*hashed_address, hashed_storage);
+                }
+                hashed_post_state.sorted()
+            };
+
+            assert_eq!(hashed_post_state(false), hashed_post_state(true));
+        });
+    }
+
+    #[test]
+    fn sort_keeps_last_inserted_duplicate() {
+        let slot = H256::from_low_u64_be(1);
+        let mut hashed_storage = HashedStorage::new(false);
+        hashed_storage.insert_non_zero_valued_storage(H256::from_low_u64_be(2), U256::from(1));
+        hashed_storage.insert_non_zero_valued_storage(slot, U256::from(1));
+        hashed_storage.insert_non_zero_valued_storage(slot, U256::from(2));
+        hashed_storage.sort_storage();
+        assert_eq!(
+            hashed_storage.non_zero_valued_storage,
+            vec![(slot, U256::from(2)), (H256::from_low_u64_be(2), U256::from(1))]
+        );
+
+        let account = |nonce| Account { nonce, ..Default::default() };
+        let mut hashed_post_state = HashedPostState::default();
+        hashed_post_state.insert_account(slot, account(1));
+        hashed_post_state.insert_account(slot, account(2));
+        hashed_post_state.sort();
+        assert_eq!(hashed_post_state.accounts, vec![(slot, account(2))]);
+    }
 }
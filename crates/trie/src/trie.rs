@@ -35,6 +35,8 @@ pub struct StateRoot<'a, 'b, TX, H> {
     pub changed_storage_prefixes: HashMap<H256, PrefixSet>,
     /// A map containing keys of accounts that were destroyed.
     pub destroyed_accounts: HashSet<H256>,
+    /// Storage roots used instead of the computed ones, keyed by hashed address.
+    pub storage_root_overrides: HashMap<H256, H256>,
     /// Previous intermediate state.
     previous_state: Option<IntermediateStateRootState>,
     /// The number of updates after which the intermediate progress should be returned.
@@ -60,6 +62,15 @@ impl<'a, 'b, TX, H> StateRoot<'a, 'b, TX, H> {
         self
     }
 
+    /// Set the storage roots used instead of the computed ones.
+    ///
+    /// Storage tries of these accounts are not walked, so no storage trie updates are produced
+    /// for them.
+    pub fn with_storage_root_overrides(mut self, overrides: HashMap<H256, H256>) -> Self {
+        self.storage_root_overrides = overrides;
+        self
+    }
+
     /// Set the threshold.
     pub fn with_threshold(mut self, threshold: u64) -> Self {
         self.threshold = threshold;
@@ -88,6 +99,7 @@ impl<'a, 'b, TX, H> StateRoot<'a, 'b, TX, H> {
             changed_account_prefixes: self.changed_account_prefixes,
             changed_storage_prefixes: self.changed_storage_prefixes,
             destroyed_accounts: self.destroyed_accounts,
+            storage_root_overrides: self.storage_root_overrides,
             threshold: self.threshold,
             previous_state: self.previous_state,
             hashed_cursor_factory,
@@ -106,6 +118,7 @@ where
             changed_account_prefixes: PrefixSetMut::default().freeze(),
             changed_storage_prefixes: HashMap::default(),
             destroyed_accounts: HashSet::default(),
+            storage_root_overrides: HashMap::default(),
             previous_state: None,
             threshold: 100_000,
             hashed_cursor_factory: tx,
@@ -299,23 +312,29 @@ where
                 // progress.
                 // TODO: We can consider introducing the TrieProgress::Progress/Complete
                 // abstraction inside StorageRoot, but let's give it a try as-is for now.
-                let storage_root_calculator = StorageRoot::new_hashed(self.tx, hashed_address)
-                    .with_hashed_cursor_factory(self.hashed_cursor_factory)
-                    .with_changed_prefixes(
-                        self.changed_storage_prefixes
-                            .get(&hashed_address)
-                            .cloned()
-                            .unwrap_or_default(),
-                    );
-
-                let storage_root = if retain_updates {
-                    let (root, storage_slots_walked, updates) =
-                        storage_root_calculator.root_with_updates()?;
-                    hashed_entries_walked += storage_slots_walked;
-                    trie_updates.extend(updates.into_iter());
-                    root
+                let storage_root = if let Some(root) =
+                    self.storage_root_overrides.get(&hashed_address)
+                {
+                    *root
                 } else {
-                    storage_root_calculator.root()?
+                    let storage_root_calculator = StorageRoot::new_hashed(self.tx, hashed_address)
+                        .with_hashed_cursor_factory(self.hashed_cursor_factory)
+                        .with_changed_prefixes(
+                            self.changed_storage_prefixes
+                                .get(&hashed_address)
+                                .cloned()
+                                .unwrap_or_default(),
+                        );
+
+                    if retain_updates {
+                        let (root, storage_slots_walked, updates) =
+                            storage_root_calculator.root_with_updates()?;
+                        hashed_entries_walked += storage_slots_walked;
+                        trie_updates.extend(updates.into_iter());
+                        root
+                    } else {
+                        storage_root_calculator.root()?
+                    }
                 };
 
                 let account = EthAccount::from(account).with_storage_root(storage_root);